@@ -0,0 +1,25 @@
+// Lighting shared by the shaders of the game.  The ambient light comes
+// from the map as u_Ambient.
+
+const vec3 SUN_DIRECTION = normalize(vec3(0.4, 0.3, 1.0));
+const vec4 SUN_COLOR = vec4(1.0, 1.0, 1.0, 0.3);
+const vec4 DARKNESS = vec4(0.1, 0.1, 0.1, 1.0);
+
+void directionalLight(in vec3 normal,
+                      in vec3 lightDir,
+                      in vec3 halfVec,
+                      in float shininess,
+                      in vec4 lightDiffuse,
+                      in vec4 lightSpecular,
+                      inout vec4 diffuse,
+                      inout vec4 specular)
+{
+    float nDotVp; /* normal . light dir */
+    float nDotHv; /* normal . half vec */
+    float pf; /* power factor */
+    nDotVp = max(0.0, dot(normal, normalize(lightDir)));
+    nDotHv = max(0.0, dot(normal, halfVec));
+    pf = (nDotVp == 0.0) ? 0.0 : pow(nDotHv, shininess);
+    diffuse += lightDiffuse * nDotVp;
+    specular += lightSpecular * pf;
+}
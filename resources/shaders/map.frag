@@ -0,0 +1,32 @@
+#version 150 core
+
+#include "lighting.glsl"
+
+in vec2 v_TexCoord;
+in vec4 v_TexRect;
+in vec3 v_Normal;
+in vec3 v_HalfVec;
+in vec3 v_SunDirection;
+out vec4 o_Color;
+
+uniform sampler2D t_Color;
+uniform vec4 u_Ambient;
+
+void main() {
+    vec4 diffuse = vec4(0.0);
+    vec4 specular = vec4(0.0);
+
+    directionalLight(v_Normal, v_SunDirection, v_HalfVec,
+                     30.0, SUN_COLOR, vec4(0.0), diffuse,
+                     specular);
+
+    // merged faces repeat the texture once per tile.  The gradients
+    // are taken before wrapping so there are no seams between tiles.
+    vec2 uv = v_TexRect.xy + fract(v_TexCoord) * v_TexRect.zw;
+    vec4 color = textureGrad(t_Color, uv,
+                             dFdx(v_TexCoord) * v_TexRect.zw,
+                             dFdy(v_TexCoord) * v_TexRect.zw);
+    color = color * clamp(DARKNESS + u_Ambient + diffuse, 0.0, 1.0);
+
+    o_Color = color;
+}
@@ -0,0 +1,24 @@
+#version 150 core
+
+#include "lighting.glsl"
+
+in vec3 a_Pos;
+in vec3 a_Normal;
+in vec2 a_TexCoord;
+in vec4 a_TexRect;
+out vec2 v_TexCoord;
+out vec4 v_TexRect;
+out vec3 v_Normal;
+out vec3 v_SunDirection;
+out vec3 v_HalfVec;
+
+uniform mat4 u_Transform;
+
+void main() {
+    v_TexCoord = a_TexCoord;
+    v_TexRect = a_TexRect;
+    v_Normal = a_Normal;
+    v_SunDirection = SUN_DIRECTION;
+    v_HalfVec = normalize(a_Pos + v_SunDirection);
+    gl_Position = u_Transform * vec4(a_Pos, 1.0);
+}
@@ -0,0 +1,542 @@
+use std::io;
+use std::num::Float;
+use std::f32::consts::PI;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+use serialize::{json, Decodable};
+
+use bullet::{BulletKind, BulletConfig};
+use raycast;
+use raycast::{BankShotQuery, Trajectory};
+use geom::{Vec2, normalize_angle};
+use map::{Map, EnemyType};
+use mine::MineConfig;
+use pathfinding::{Pathfinder, tile_of};
+use world::tile_center;
+use tank::{Tank, TankKind, TankConfig};
+use errors::{Res, GameError};
+
+/// how many angles are tried when looking for a bank shot
+static BANK_SHOT_SAMPLES : uint = 90;
+/// how close to the target heading the turret has to be to fire
+static FIRE_TOLERANCE : f32 = 0.05;
+/// how close a tank has to get to a waypoint to head for the next one
+static ARRIVE_DISTANCE : f32 = 0.3;
+
+
+/// The tuning values of one enemy type.  These are loaded from the
+/// enemy config so that they can be changed without recompiling.
+#[deriving(Decodable, Clone, Show)]
+pub struct EnemyParams {
+    /// top speed in tiles per second, zero for tanks that do not move
+    pub speed: f32,
+    /// hull turn rate in radians per second
+    pub turn_rate: f32,
+    /// turret turn rate in radians per second
+    pub turret_turn_rate: f32,
+    /// seconds between two shots
+    pub fire_cooldown: f32,
+    /// how many of its bullets may be in the air at the same time
+    pub max_bullets: uint,
+    /// the name of the bullet kind that is fired
+    pub bullet: String,
+    /// how many ricochets are considered when looking for a shot
+    pub bank_shots: u8,
+    /// how far players can be away to be noticed, in tiles
+    pub sight_range: f32,
+    /// how far away the next wander target may be, in tiles
+    pub wander_range: f32,
+    /// seconds after which a new wander target is picked
+    pub wander_interval: f32,
+}
+
+impl EnemyParams {
+
+    pub fn get_bullet_kind(&self) -> Res<BulletKind> {
+        match BulletKind::from_name(self.bullet.as_slice()) {
+            Some(kind) => Ok(kind),
+            None => Err(GameError::InvalidConfig("Unknown bullet kind")),
+        }
+    }
+}
+
+/// The tuning values of all enemy types, of tanks in general, of the
+/// bullets tanks fire and of mines.
+#[deriving(Decodable, Clone, Show)]
+pub struct AiConfig {
+    pub stationary: EnemyParams,
+    pub basic: EnemyParams,
+    pub fast: EnemyParams,
+    pub heat_seeker: EnemyParams,
+    pub rocket: EnemyParams,
+    pub tanks: TankConfig,
+    pub bullets: BulletConfig,
+    pub mines: MineConfig,
+}
+
+impl AiConfig {
+
+    pub fn open(path: &Path) -> Res<AiConfig> {
+        let mut file = try!(io::File::open(path));
+        let json = try!(json::from_reader(&mut file));
+        let mut decoder = json::Decoder::new(json);
+        let config : AiConfig = try!(Decodable::decode(&mut decoder));
+        for ty in [EnemyType::Stationary, EnemyType::Basic, EnemyType::Fast,
+                   EnemyType::HeatSeeker, EnemyType::Rocket].iter() {
+            try!(config.get(*ty).get_bullet_kind());
+        }
+        Ok(config)
+    }
+
+    pub fn get(&self, ty: EnemyType) -> &EnemyParams {
+        match ty {
+            EnemyType::Stationary => &self.stationary,
+            EnemyType::Basic => &self.basic,
+            EnemyType::Fast => &self.fast,
+            EnemyType::HeatSeeker => &self.heat_seeker,
+            EnemyType::Rocket => &self.rocket,
+        }
+    }
+}
+
+/// How an enemy type behaves.  The numbers come from `EnemyParams`,
+/// the behaviour decides what is done with them.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum Behaviour {
+    /// only turns the turret and shoots at players in sight
+    Turret,
+    /// wanders around and also uses bank shots
+    Wander,
+    /// wanders around and fires homing missiles without needing sight
+    Seeker,
+    /// wanders around and only fires when there is a direct line
+    Sniper,
+}
+
+impl Behaviour {
+
+    pub fn for_enemy(ty: EnemyType) -> Behaviour {
+        match ty {
+            EnemyType::Stationary => Behaviour::Turret,
+            EnemyType::Basic | EnemyType::Fast => Behaviour::Wander,
+            EnemyType::HeatSeeker => Behaviour::Seeker,
+            EnemyType::Rocket => Behaviour::Sniper,
+        }
+    }
+
+    pub fn can_move(&self) -> bool {
+        *self != Behaviour::Turret
+    }
+}
+
+/// What an enemy tank wants to do in this step.  Mirrors the player
+/// input so both can be applied the same way.
+#[deriving(Copy, Clone, Show)]
+pub struct Command {
+    pub forward: f32,
+    pub turn: f32,
+    pub turret: f32,
+    pub fire: bool,
+}
+
+/// The per-tank state of the enemy AI.
+pub struct Brain {
+    pub tank_id: uint,
+    pub enemy_type: EnemyType,
+    pub behaviour: Behaviour,
+    path: Vec<Vec2>,
+    wander_timer: f32,
+    rng: XorShiftRng,
+}
+
+/// the center of the next tile on the way to a player tank, taken from
+/// the distance field towards that player
+fn chase_step(nav: &Pathfinder, me: &Tank, target: &Tank) -> Option<Vec2> {
+    let player = match target.kind {
+        TankKind::Player(idx) => idx,
+        TankKind::Enemy(_) => return None,
+    };
+    let field = unwrap_or!(nav.get_player_field(player), return None);
+    let (x, y) = tile_of(me.pos);
+    field.next_step(x, y).map(|(nx, ny)| tile_center(nx, ny))
+}
+
+/// true if any tank other than `shooter` and `target` is on the path
+fn path_blocked_by_tank(shot: &Trajectory, tanks: &[Tank], shooter: uint,
+                        target: uint) -> bool {
+    tanks.iter().any(|t| {
+        t.alive && t.id != shooter && t.id != target &&
+            shot.passes(t.pos, t.radius)
+    })
+}
+
+impl Brain {
+
+    pub fn new(tank_id: uint, enemy_type: EnemyType) -> Brain {
+        Brain {
+            tank_id: tank_id,
+            enemy_type: enemy_type,
+            behaviour: Behaviour::for_enemy(enemy_type),
+            path: vec![],
+            wander_timer: 0.0,
+            rng: SeedableRng::from_seed([tank_id as u32 + 1, 0x193a6754,
+                                         0xa8a7d469, 0x97830e05]),
+        }
+    }
+
+    /// the closest living player tank within sight range
+    fn find_target<'a>(&self, me: &Tank, tanks: &'a [Tank],
+                       params: &EnemyParams) -> Option<&'a Tank> {
+        let mut best : Option<&'a Tank> = None;
+        for tank in tanks.iter() {
+            if !tank.is_player() || !tank.alive {
+                continue;
+            }
+            let dist = me.pos.distance(tank.pos);
+            if dist > params.sight_range {
+                continue;
+            }
+            match best {
+                Some(other) if me.pos.distance(other.pos) <= dist => {}
+                _ => { best = Some(tank); }
+            }
+        }
+        best
+    }
+
+    /// Finds the angle the turret has to face to hit `target`.  The
+    /// direct line is preferred, after that bank shots with up to
+    /// `bounces` ricochets closest to the current turret heading.  Shots
+    /// that would come back to the shooter or hit another tank first
+    /// are skipped.
+    fn find_firing_angle(&self, map: &Map, me: &Tank, target: &Tank,
+                         tanks: &[Tank], bounces: u8,
+                         range: f32) -> Option<f32> {
+        let mut query = BankShotQuery::new(bounces);
+        query.samples = BANK_SHOT_SAMPLES;
+        query.max_distance = range;
+        query.target_radius = target.radius;
+        query.preferred_angle = Some(me.turret_heading);
+        raycast::find_bank_shot(map, me.pos, target.pos, &query, |shot| {
+            !shot.passes_after(1, me.pos, me.radius) &&
+                !path_blocked_by_tank(shot, tanks, me.id, target.id)
+        }).map(|shot| shot.angle)
+    }
+
+    /// Picks a random reachable tile near the tank and returns the
+    /// waypoints to get there.
+    fn pick_wander_path(&mut self, nav: &Pathfinder, me: &Tank,
+                        params: &EnemyParams) -> Vec<Vec2> {
+        let (x, y) = tile_of(me.pos);
+        let range = params.wander_range.max(1.0) as i32;
+        for _ in range(0, 8u) {
+            let tx = x as i32 + self.rng.gen_range(-range, range + 1);
+            let ty = y as i32 + self.rng.gen_range(-range, range + 1);
+            if (tx == x as i32 && ty == y as i32) || !nav.grid().is_passable(tx, ty) {
+                continue;
+            }
+            if let Some(path) = nav.find_path((x, y), (tx as u16, ty as u16)) {
+                return path.iter().skip(1)
+                    .map(|&(px, py)| tile_center(px, py)).collect();
+            }
+        }
+        vec![]
+    }
+
+    fn steer_to(&self, me: &Tank, target: Vec2, dt: f32) -> (f32, f32) {
+        let diff = normalize_angle(target.sub(me.pos).angle() - me.heading);
+        let max_turn = me.turn_rate * dt;
+        let turn = if max_turn > 0.0 { (diff / max_turn).max(-1.0).min(1.0) } else { 0.0 };
+        let forward = if diff.abs() < PI / 4.0 { 1.0 } else { 0.0 };
+        (forward, turn)
+    }
+
+    fn wander(&mut self, nav: &Pathfinder, me: &Tank, params: &EnemyParams,
+              dt: f32) -> (f32, f32) {
+        self.wander_timer -= dt;
+        if self.path.len() > 0 && me.pos.distance(self.path[0]) < ARRIVE_DISTANCE {
+            self.path.remove(0);
+        }
+        if self.path.is_empty() || self.wander_timer <= 0.0 {
+            self.path = self.pick_wander_path(nav, me, params);
+            self.wander_timer = params.wander_interval;
+        }
+        if self.path.is_empty() {
+            (0.0, 0.0)
+        } else {
+            self.steer_to(me, self.path[0], dt)
+        }
+    }
+
+    /// Decides what the tank does in this step.  `bullets_in_air` is the
+    /// number of bullets this tank currently has flying.
+    pub fn think(&mut self, map: &Map, nav: &Pathfinder, tanks: &[Tank],
+                 params: &EnemyParams, bullets_in_air: uint,
+                 dt: f32) -> Command {
+        let me = unwrap_or!(tanks.iter().find(|t| t.id == self.tank_id),
+                            return Command { forward: 0.0, turn: 0.0,
+                                             turret: 0.0, fire: false });
+        let mut cmd = Command { forward: 0.0, turn: 0.0, turret: 0.0, fire: false };
+
+        let target = self.find_target(me, tanks, params);
+        let aim = match target {
+            Some(target) => match self.behaviour {
+                Behaviour::Turret | Behaviour::Sniper => self.find_firing_angle(
+                    map, me, target, tanks, 0, params.sight_range),
+                Behaviour::Wander => self.find_firing_angle(
+                    map, me, target, tanks, params.bank_shots, params.sight_range),
+                // heat seekers steer on their own, pointing roughly into
+                // the direction of the target is good enough.
+                Behaviour::Seeker => Some(target.pos.sub(me.pos).angle()),
+            },
+            None => None,
+        };
+
+        if self.behaviour.can_move() {
+            // a target that cannot be shot at is hunted down, otherwise
+            // the tank wanders around
+            let chase = match (target, aim) {
+                (Some(target), None) => chase_step(nav, me, target),
+                _ => None,
+            };
+            let (forward, turn) = match chase {
+                Some(step) => {
+                    self.path.clear();
+                    self.steer_to(me, step, dt)
+                }
+                None => self.wander(nav, me, params, dt),
+            };
+            cmd.forward = forward;
+            cmd.turn = turn;
+        }
+
+        if let Some(angle) = aim {
+            let diff = normalize_angle(angle - me.turret_heading);
+            let max_turn = me.turret_turn_rate * dt;
+            if max_turn > 0.0 {
+                cmd.turret = (diff / max_turn).max(-1.0).min(1.0);
+            }
+            cmd.fire = diff.abs() < FIRE_TOLERANCE &&
+                bullets_in_air < params.max_bullets;
+        }
+
+        cmd
+    }
+}
+
+/// applies the movement values of an enemy type to a freshly spawned tank
+pub fn apply_params(tank: &mut Tank, params: &EnemyParams) {
+    tank.speed = params.speed;
+    tank.turn_rate = params.turn_rate;
+    tank.turret_turn_rate = params.turret_turn_rate;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use bullet::{Bullet, BulletKind};
+    use geom::{Vec2, normalize_angle};
+    use map::{Map, EnemyType};
+    use pathfinding::tile_of;
+    use raycast;
+    use tank::{Tank, TankKind};
+    use world::{World, Inputs};
+    use super::{AiConfig, Brain};
+
+    static DT : f32 = 1.0 / 60.0;
+
+    fn config() -> AiConfig {
+        AiConfig::open(&Path::new("resources/config/enemies.json")).unwrap()
+    }
+
+    fn enemy(world: &World, ty: EnemyType) -> Tank {
+        world.tanks().iter().find(|t| t.kind == TankKind::Enemy(ty)).unwrap().clone()
+    }
+
+    /// how far `vel` points away from the direction from `from` to `to`
+    fn off_target(vel: Vec2, from: Vec2, to: Vec2) -> f32 {
+        normalize_angle(to.sub(from).angle() - vel.angle()).abs()
+    }
+
+    #[test]
+    fn stationary_only_turns_its_turret() {
+        let map = Map::from_glyphs(&[
+            "1111111",
+            "1P...T1",
+            "1111111",
+        ]).unwrap();
+        let mut world = World::new(&map, config());
+        let start = enemy(&world, EnemyType::Stationary);
+        let inputs = Inputs::new();
+        for _ in range(0, 60u) {
+            world.step(DT, &inputs);
+        }
+        let tank = enemy(&world, EnemyType::Stationary);
+        assert!(tank.pos.distance(start.pos) < 0.000001);
+        assert_eq!(tank.heading, start.heading);
+        // the player is behind it, one second is not enough to turn
+        // around all the way but the turret is on its way
+        assert!(tank.turret_heading.abs() > 1.0);
+        assert_eq!(world.bullets().len(), 0);
+    }
+
+    #[test]
+    fn basic_finds_a_bank_shot() {
+        let map = Map::from_glyphs(&[
+            "1111111",
+            "1P.1.B1",
+            "1.....1",
+            "1111111",
+        ]).unwrap();
+        let config = config();
+        let world = World::new(&map, config.clone());
+        let me = enemy(&world, EnemyType::Basic);
+        let player = world.get_player_tank(0).unwrap();
+        let brain = Brain::new(me.id, EnemyType::Basic);
+
+        // the wall is in the way, the shot has to go off the bottom wall
+        let params = config.get(EnemyType::Basic);
+        let angle = brain.find_firing_angle(world.map(), &me, player, world.tanks(),
+                                            params.bank_shots,
+                                            params.sight_range).unwrap();
+        assert!(angle.sin() > 0.0);
+        let shot = raycast::trace(world.map(), me.pos, angle, params.bank_shots,
+                                  params.sight_range)
+            .truncate_at(player.pos, player.radius);
+        assert_eq!(shot.bounces(), 1);
+        assert!(shot.passes(player.pos, player.radius));
+
+        // without bank shots there is nothing to fire at
+        let params = config.get(EnemyType::Stationary);
+        assert!(brain.find_firing_angle(world.map(), &me, player, world.tanks(),
+                                        params.bank_shots,
+                                        params.sight_range).is_none());
+    }
+
+    /// how far the enemy of a type has to go to reach the first player
+    fn cost_to_player(world: &World, ty: EnemyType) -> u32 {
+        let (x, y) = tile_of(enemy(world, ty).pos);
+        world.pathfinder().get_player_field(0).unwrap()
+            .get(x as i32, y as i32).unwrap()
+    }
+
+    #[test]
+    fn enemies_chase_players_they_cannot_shoot() {
+        let map = Map::from_glyphs(&[
+            "1111111",
+            "1P....1",
+            "11111.1",
+            "1B....1",
+            "1111111",
+        ]).unwrap();
+        let mut world = World::new(&map, config());
+        let idle = Inputs::new();
+        world.step(DT, &idle);
+        assert_eq!(cost_to_player(&world, EnemyType::Basic), 100);
+        for _ in range(0, 300u) {
+            if cost_to_player(&world, EnemyType::Basic) <= 60 {
+                break;
+            }
+            world.step(DT, &idle);
+        }
+        // made it to the bend of the corridor
+        assert!(cost_to_player(&world, EnemyType::Basic) <= 60);
+    }
+
+    #[test]
+    fn fast_drives_faster_than_basic() {
+        let map = Map::from_glyphs(&[
+            "1111111111",
+            "1F.......1",
+            "1B.......1",
+            "1P.......1",
+            "1111111111",
+        ]).unwrap();
+        let config = config();
+        let world = World::new(&map, config.clone());
+        let mut fast = enemy(&world, EnemyType::Fast);
+        let mut basic = enemy(&world, EnemyType::Basic);
+        assert_eq!(fast.speed, config.fast.speed);
+        assert_eq!(basic.speed, config.basic.speed);
+        assert!(fast.speed > basic.speed);
+
+        let (fast_start, basic_start) = (fast.pos, basic.pos);
+        fast.drive(world.map(), 1.0, 0.0, 0.5);
+        basic.drive(world.map(), 1.0, 0.0, 0.5);
+        assert!((fast.pos.distance(fast_start) - config.fast.speed * 0.5).abs() < 0.001);
+        assert!((basic.pos.distance(basic_start) - config.basic.speed * 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn heat_seeker_bullets_follow_the_target() {
+        let map = Map::from_glyphs(&[
+            "11111111111",
+            "1.H.......1",
+            "1.........1",
+            "1.........1",
+            "1.........1",
+            "1.........1",
+            "1.........1",
+            "1.........1",
+            "1.........1",
+            "1.P.......1",
+            "1.........1",
+            "11111111111",
+        ]).unwrap();
+        let mut world = World::new(&map, config());
+        let idle = Inputs::new();
+        for _ in range(0, 600u) {
+            if world.bullets().len() > 0 {
+                break;
+            }
+            world.step(DT, &idle);
+        }
+        let fired = world.bullets()[0].clone();
+        assert_eq!(fired.kind, BulletKind::HeatSeeker);
+
+        // the player drives off to the side, the missile has to turn
+        let mut inputs = Inputs::new();
+        inputs.players[0].forward = 1.0;
+        for _ in range(0, 15u) {
+            world.step(DT, &inputs);
+        }
+        let player = world.get_player_tank(0).unwrap().pos;
+        let bullet = world.bullets()[0].clone();
+        assert!(bullet.alive);
+        assert!(off_target(bullet.vel, bullet.pos, player) <
+                off_target(fired.vel, bullet.pos, player));
+        assert!((bullet.vel.length() - fired.vel.length()).abs() < 0.001);
+    }
+
+    #[test]
+    fn rockets_die_on_the_first_wall() {
+        let map = Map::from_glyphs(&[
+            "11111111",
+            "1K....P1",
+            "11111111",
+        ]).unwrap();
+        let config = config();
+        let mut world = World::new(&map, config.clone());
+        let idle = Inputs::new();
+        for _ in range(0, 120u) {
+            if world.bullets().len() > 0 {
+                break;
+            }
+            world.step(DT, &idle);
+        }
+        let mut rocket = world.bullets()[0].clone();
+        assert_eq!(rocket.kind, BulletKind::Rocket);
+        assert_eq!(rocket.ricochets_left, config.bullets.rocket.ricochets);
+
+        // a standard shell on the same course bounces off the wall
+        // behind the player, the rocket stays there
+        let mut shell = Bullet::new(0, BulletKind::Standard, &config.bullets.standard,
+                                    0, rocket.pos, rocket.vel.angle());
+        rocket.advance(world.map(), 1.0);
+        shell.advance(world.map(), 1.0);
+        assert!(!rocket.alive);
+        assert!((rocket.pos.x - 7.0).abs() < 0.01);
+        assert!(shell.alive);
+        assert!(shell.vel.x < 0.0);
+    }
+}
@@ -0,0 +1,199 @@
+use std::io;
+use std::cmp;
+use std::collections::HashMap;
+use serialize::{json, Decodable};
+
+use atlas::TextureAtlas;
+use errors::{Res, GameError};
+use map::Tile;
+use texture::{TextureOptions, Filtering};
+
+/// the maximum anisotropy of anisotropic filtering unless configured
+static DEFAULT_ANISOTROPY : u8 = 8;
+
+
+/// Which atlas images a tile is drawn with.  Images are referred to by
+/// their index in the appearance table so they can be compared cheaply.
+#[deriving(Clone, PartialEq, Show)]
+pub struct TileLook {
+    /// the image of the top surface
+    pub top: u16,
+    /// the images of the sides, one per height level starting at the
+    /// bottom.  Levels above the last entry repeat it.
+    pub sides: Vec<u16>,
+}
+
+#[deriving(Decodable)]
+struct TileLookData {
+    top: String,
+    sides: Vec<String>,
+}
+
+#[deriving(Decodable)]
+struct AppearanceData {
+    images: String,
+    filtering: Option<String>,
+    anisotropy: Option<u8>,
+    tiles: HashMap<String, TileLookData>,
+}
+
+/// The table of how tiles look in the map mesh.  Tiles are drawn with
+/// images from a texture atlas that is packed from a folder of images.
+/// The table also says how that atlas is filtered, trilinear unless
+/// configured otherwise.
+#[deriving(Clone, Show)]
+pub struct TileAppearance {
+    images: String,
+    options: TextureOptions,
+    names: Vec<String>,
+    /// the looks in the order of `Tile::all`
+    looks: Vec<TileLook>,
+}
+
+impl TileAppearance {
+
+    pub fn open(path: &Path) -> Res<TileAppearance> {
+        let mut file = try!(io::File::open(path));
+        let json = try!(json::from_reader(&mut file));
+        let mut decoder = json::Decoder::new(json);
+        let data : AppearanceData = try!(Decodable::decode(&mut decoder));
+
+        for name in data.tiles.keys() {
+            if Tile::from_name(name.as_slice()).is_none() {
+                return Err(GameError::InvalidConfig("Unknown tile in tile appearance"));
+            }
+        }
+
+        let mut options = TextureOptions::new();
+        options.filtering = match data.filtering {
+            Some(ref name) => unwrap_or!(
+                Filtering::from_name(name.as_slice(),
+                                     data.anisotropy.unwrap_or(DEFAULT_ANISOTROPY)),
+                return Err(GameError::InvalidConfig("Unknown texture filtering"))),
+            None => Filtering::Trilinear,
+        };
+
+        let mut names = vec![];
+        let mut looks = vec![];
+        for tile in Tile::all().iter() {
+            let look = unwrap_or!(data.tiles.get(&tile.name().to_string()),
+                return Err(GameError::InvalidConfig("Tile appearance misses a tile")));
+            if look.sides.is_empty() {
+                return Err(GameError::InvalidConfig("Tile appearance without sides"));
+            }
+            let top = intern(&mut names, look.top.as_slice());
+            let sides = look.sides.iter().map(|x| intern(&mut names, x.as_slice())).collect();
+            looks.push(TileLook { top: top, sides: sides });
+        }
+
+        Ok(TileAppearance {
+            images: data.images,
+            options: options,
+            names: names,
+            looks: looks,
+        })
+    }
+
+    /// the name of the folder in the images the atlas is packed from
+    pub fn images(&self) -> &str {
+        self.images.as_slice()
+    }
+
+    /// how the atlas is sampled
+    pub fn texture_options(&self) -> TextureOptions {
+        self.options
+    }
+
+    /// Fails if the atlas lacks an image the table refers to.
+    pub fn check(&self, atlas: &TextureAtlas) -> Res<()> {
+        if self.names.iter().all(|x| atlas.contains(x.as_slice())) {
+            Ok(())
+        } else {
+            Err(GameError::InvalidConfig("Tile image missing from texture atlas"))
+        }
+    }
+
+    pub fn get_look(&self, tile: Tile) -> &TileLook {
+        let idx = Tile::all().iter().position(|x| *x == tile).unwrap();
+        &self.looks[idx]
+    }
+
+    /// the image of the top surface of a tile
+    pub fn top_cell(&self, tile: Tile) -> u16 {
+        self.get_look(tile).top
+    }
+
+    /// The image of the side of a tile at a height level.  Level 1 is
+    /// the lowest, the walls of holes and fluids use it too.
+    pub fn side_cell(&self, tile: Tile, level: uint) -> u16 {
+        let sides = &self.get_look(tile).sides;
+        let idx = if level > 0 { level - 1 } else { 0 };
+        sides[cmp::min(idx, sides.len() - 1)]
+    }
+
+    /// The texture measurements of every image of the table, indexed by
+    /// cell.  `measure` looks an image up by name, `None` if it has none.
+    pub fn measure_cells(&self, measure: |&str| -> Option<(f32, f32, f32, f32)>)
+                         -> Vec<Option<(f32, f32, f32, f32)>> {
+        let mut rv = Vec::with_capacity(self.names.len());
+        for name in self.names.iter() {
+            rv.push(measure(name.as_slice()));
+        }
+        rv
+    }
+}
+
+/// the index of a name in a list, the name is added if it is new
+fn intern(names: &mut Vec<String>, name: &str) -> u16 {
+    match names.iter().position(|x| x.as_slice() == name) {
+        Some(idx) => idx as u16,
+        None => {
+            names.push(name.to_string());
+            (names.len() - 1) as u16
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atlas::{PackedAtlas, DEFAULT_PADDING};
+    use map::Tile;
+    use super::TileAppearance;
+
+    fn open() -> TileAppearance {
+        TileAppearance::open(&Path::new("resources/config/tiles.json")).unwrap()
+    }
+
+    #[test]
+    fn walls_have_a_base() {
+        let appearance = open();
+        let base = appearance.side_cell(Tile::Wall3, 1);
+        let wall = appearance.side_cell(Tile::Wall3, 2);
+        assert!(base != wall);
+        assert_eq!(appearance.side_cell(Tile::Wall3, 0), base);
+        assert_eq!(appearance.side_cell(Tile::Wall3, 3), wall);
+        assert_eq!(appearance.side_cell(Tile::Wall5, 5), wall);
+    }
+
+    #[test]
+    fn obstacles_and_recesses_look_different() {
+        let appearance = open();
+        let tiles = [Tile::Ground, Tile::Wall1, Tile::Box1, Tile::Water,
+                     Tile::Swamp, Tile::Hole];
+        for (idx, a) in tiles.iter().enumerate() {
+            for b in tiles.slice_from(idx + 1).iter() {
+                assert!(appearance.top_cell(*a) != appearance.top_cell(*b));
+            }
+        }
+    }
+
+    #[test]
+    fn every_image_is_packed_into_the_atlas() {
+        let appearance = open();
+        let root = Path::new("resources/images").join(appearance.images());
+        let packed = PackedAtlas::from_directory(&root, DEFAULT_PADDING).unwrap();
+        let measurements = appearance.measure_cells(
+            |name| packed.get(name).map(|_| (1.0, 1.0, 0.0, 0.0)));
+        assert!(measurements.iter().all(|x| x.is_some()));
+    }
+}
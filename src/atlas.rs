@@ -0,0 +1,355 @@
+use std::cmp;
+use std::io::fs;
+
+use gfx;
+use image;
+use image::{DynamicImage, GenericImage, ImageBuf};
+
+use errors::{Res, GameError};
+use mipmap::{generate_atlas_mips, mip_count};
+use texture::{Texture, BasicTexture, TextureSlice, TextureOptions, RgbaImage};
+
+/// The free space around every packed image in pixels.  Each mip level
+/// halves it, so this is enough for four levels without bleeding.
+pub static DEFAULT_PADDING : u32 = 8;
+
+/// the largest atlas that is made, bigger ones fail to pack
+pub static MAX_ATLAS_SIZE : u32 = 4096;
+
+/// the smallest atlas that is tried first
+static MIN_ATLAS_SIZE : u32 = 64;
+
+
+/// Where an image ended up in an atlas.  This is the image itself, the
+/// padding around it is not included.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub struct AtlasRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Places rects of the given sizes row by row into a square of `size`.
+/// Returns `None` if they do not fit.  The sizes already include the
+/// padding.
+fn place_rows(sizes: &[(u32, u32)], size: u32) -> Option<Vec<(u32, u32)>> {
+    let mut rv = Vec::with_capacity(sizes.len());
+    let (mut x, mut y, mut row_height) = (0, 0, 0);
+    for &(width, height) in sizes.iter() {
+        if width > size {
+            return None;
+        }
+        if x + width > size {
+            x = 0;
+            y += row_height;
+            row_height = 0;
+        }
+        if y + height > size {
+            return None;
+        }
+        rv.push((x, y));
+        x += width;
+        row_height = cmp::max(row_height, height);
+    }
+    Some(rv)
+}
+
+/// Rounds a size up to a multiple of the padding.  Cells of that size
+/// keep the images at the same place relative to the pixels of smaller
+/// mip levels.
+fn align(size: u32, padding: u32) -> u32 {
+    if padding == 0 {
+        size
+    } else {
+        (size + padding - 1) / padding * padding
+    }
+}
+
+/// Copies an image into its cell of the atlas and repeats its outermost
+/// pixels into the rest of the cell, so that filtering at the border of
+/// a slice does not pick up its neighbours.
+fn blit_extruded(atlas: &mut RgbaImage, image: &RgbaImage, x: u32, y: u32,
+                 cell: (u32, u32), padding: u32) {
+    let (width, height) = image.dimensions();
+    let (cell_width, cell_height) = cell;
+    for ty in range(0, cell_height) {
+        for tx in range(0, cell_width) {
+            let sx = cmp::min(cmp::max(tx, padding) - padding, width - 1);
+            let sy = cmp::min(cmp::max(ty, padding) - padding, height - 1);
+            atlas.put_pixel(x + tx, y + ty, image.get_pixel(sx, sy));
+        }
+    }
+}
+
+/// An atlas packed on the CPU.  This is everything a `TextureAtlas` is
+/// made of without touching the GPU.
+pub struct PackedAtlas {
+    image: RgbaImage,
+    padding: u32,
+    rects: Vec<(String, AtlasRect)>,
+}
+
+impl PackedAtlas {
+
+    /// Packs named images into one square image with `padding` pixels
+    /// around each of them.  Images are sorted by height so that rows
+    /// waste little space, equal heights keep the order they came in.
+    /// The atlas is the smallest power of two they fit into.  For mip
+    /// levels to line up the padding should be a power of two.
+    pub fn pack(images: Vec<(String, RgbaImage)>, padding: u32) -> Res<PackedAtlas> {
+        let mut images = images;
+        for &(_, ref image) in images.iter() {
+            let (width, height) = image.dimensions();
+            if width == 0 || height == 0 {
+                return Err(GameError::InvalidConfig("Empty image in texture atlas"));
+            }
+        }
+        images.sort_by(|&(_, ref a), &(_, ref b)| {
+            let (_, ah) = a.dimensions();
+            let (_, bh) = b.dimensions();
+            bh.cmp(&ah)
+        });
+
+        let sizes : Vec<(u32, u32)> = images.iter().map(|&(_, ref image)| {
+            let (width, height) = image.dimensions();
+            (align(width + padding * 2, padding), align(height + padding * 2, padding))
+        }).collect();
+        let mut size = MIN_ATLAS_SIZE;
+        let positions;
+        loop {
+            match place_rows(sizes.as_slice(), size) {
+                Some(rv) => { positions = rv; break; }
+                None if size < MAX_ATLAS_SIZE => { size *= 2; }
+                None => {
+                    return Err(GameError::InvalidConfig("Images do not fit into the texture atlas"));
+                }
+            }
+        }
+
+        let mut atlas : RgbaImage = ImageBuf::new(size, size);
+        let mut rects = Vec::with_capacity(images.len());
+        for ((&(ref name, ref image), &(x, y)), &cell) in images.iter()
+                .zip(positions.iter()).zip(sizes.iter()) {
+            blit_extruded(&mut atlas, image, x, y, cell, padding);
+            let (width, height) = image.dimensions();
+            rects.push((name.clone(), AtlasRect {
+                x: x + padding,
+                y: y + padding,
+                width: width,
+                height: height,
+            }));
+        }
+
+        Ok(PackedAtlas {
+            image: atlas,
+            padding: padding,
+            rects: rects,
+        })
+    }
+
+    /// Packs every PNG in a directory.  Images are named after their
+    /// file name without the extension.
+    pub fn from_directory(path: &Path, padding: u32) -> Res<PackedAtlas> {
+        let mut paths = try!(fs::readdir(path));
+        paths.sort();
+        let mut images = vec![];
+        for path in paths.iter() {
+            if path.extension_str() != Some("png") {
+                continue;
+            }
+            let name = unwrap_or!(path.filestem_str(), continue).to_string();
+            images.push((name, try!(image::open(path)).to_rgba()));
+        }
+        if images.is_empty() {
+            return Err(GameError::InvalidConfig("No images for the texture atlas"));
+        }
+        PackedAtlas::pack(images, padding)
+    }
+
+    /// the packed image
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    /// where the image of a name is within the packed image
+    pub fn get(&self, name: &str) -> Option<AtlasRect> {
+        self.rects.iter()
+            .find(|&&(ref other, _)| other.as_slice() == name)
+            .map(|&(_, rect)| rect)
+    }
+
+    pub fn rects(&self) -> &[(String, AtlasRect)] {
+        self.rects.as_slice()
+    }
+
+    /// How many mip levels the padding keeps free of bleeding.  There is
+    /// at least one pixel of padding left on the last of them.
+    pub fn mip_levels(&self) -> u8 {
+        let (width, height) = self.image.dimensions();
+        let mut levels = 1;
+        while self.padding >> levels as uint > 0 {
+            levels += 1;
+        }
+        cmp::min(levels, mip_count(width, height))
+    }
+
+    /// Generates the mip chain of the packed image, at most as many
+    /// levels as the padding supports.
+    pub fn generate_mips(&self) -> Vec<RgbaImage> {
+        let rects : Vec<AtlasRect> = self.rects.iter().map(|&(_, rect)| rect).collect();
+        generate_atlas_mips(&DynamicImage::ImageRgba8(self.image.clone()),
+                            rects.as_slice(), self.padding, self.mip_levels())
+    }
+}
+
+/// Many images packed into one texture.  Images are looked up by name
+/// and come back as slices of the texture.
+pub struct TextureAtlas {
+    texture: BasicTexture,
+    rects: Vec<(String, AtlasRect)>,
+}
+
+impl TextureAtlas {
+
+    /// Uploads a packed atlas.  Mip levels are generated if the
+    /// filtering needs them.
+    pub fn from_packed(device: &mut gfx::GlDevice, packed: PackedAtlas,
+                       options: TextureOptions) -> Res<TextureAtlas> {
+        let mips = if options.filtering.needs_mips() {
+            packed.generate_mips()
+        } else {
+            vec![packed.image.clone()]
+        };
+        let texture = try!(BasicTexture::from_mips(device, mips.as_slice(), options));
+        Ok(TextureAtlas {
+            texture: texture,
+            rects: packed.rects,
+        })
+    }
+
+    pub fn from_directory(device: &mut gfx::GlDevice, path: &Path, padding: u32,
+                          options: TextureOptions) -> Res<TextureAtlas> {
+        let packed = try!(PackedAtlas::from_directory(path, padding));
+        TextureAtlas::from_packed(device, packed, options)
+    }
+
+    /// the texture all images are packed into
+    pub fn texture(&self) -> &BasicTexture {
+        &self.texture
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.rects.iter().any(|&(ref other, _)| other.as_slice() == name)
+    }
+
+    /// the slice of the texture an image was packed into
+    pub fn get_slice(&self, name: &str) -> Option<TextureSlice> {
+        self.rects.iter()
+            .find(|&&(ref other, _)| other.as_slice() == name)
+            .map(|&(_, rect)| self.texture.slice(rect.x as u16, rect.y as u16,
+                                                 rect.width as u16, rect.height as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImage, ImageBuf, Pixel};
+
+    use errors::GameError;
+    use testutils::{solid, pixel};
+    use texture::RgbaImage;
+    use super::{PackedAtlas, AtlasRect, MAX_ATLAS_SIZE};
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> AtlasRect {
+        AtlasRect { x: x, y: y, width: width, height: height }
+    }
+
+    fn pack_three() -> PackedAtlas {
+        PackedAtlas::pack(vec![
+            ("red".to_string(), solid(10, 10, (255, 0, 0))),
+            ("green".to_string(), solid(20, 8, (0, 255, 0))),
+            ("blue".to_string(), solid(6, 12, (0, 0, 255))),
+        ], 4).unwrap()
+    }
+
+    #[test]
+    fn images_are_packed_by_height() {
+        let atlas = pack_three();
+        // cells are the images plus the padding rounded up to multiples
+        // of it: 16x20, 20x20 and 28x16, which fill one row of 64.
+        assert_eq!(atlas.rects(), [
+            ("blue".to_string(), rect(4, 4, 6, 12)),
+            ("red".to_string(), rect(20, 4, 10, 10)),
+            ("green".to_string(), rect(40, 4, 20, 8)),
+        ].as_slice());
+        assert_eq!(atlas.get("red"), Some(rect(20, 4, 10, 10)));
+        assert_eq!(atlas.get("yellow"), None);
+        assert_eq!(atlas.image().dimensions(), (64, 64));
+    }
+
+    #[test]
+    fn atlas_grows_in_powers_of_two() {
+        let atlas = PackedAtlas::pack(vec![
+            ("wide".to_string(), solid(70, 10, (255, 255, 255))),
+        ], 4).unwrap();
+        assert_eq!(atlas.image().dimensions(), (128, 128));
+        assert_eq!(atlas.get("wide"), Some(rect(4, 4, 70, 10)));
+
+        let atlas = PackedAtlas::pack(vec![
+            ("large".to_string(), solid(200, 10, (255, 255, 255))),
+        ], 8).unwrap();
+        assert_eq!(atlas.image().dimensions(), (256, 256));
+    }
+
+    #[test]
+    fn padding_repeats_the_border() {
+        let atlas = pack_three();
+        let image = atlas.image();
+        for &(ref name, rect) in atlas.rects().iter() {
+            let color = pixel(image, rect.x, rect.y);
+            for y in range(rect.y - 4, rect.y + rect.height + 4) {
+                for x in range(rect.x - 4, rect.x + rect.width + 4) {
+                    assert!(pixel(image, x, y) == color, "{} at {}, {}", name, x, y);
+                }
+            }
+        }
+
+        // every pixel of the border has its own color
+        let mut gradient : RgbaImage = ImageBuf::new(4, 4);
+        for y in range(0, 4) {
+            for x in range(0, 4) {
+                gradient.put_pixel(x, y, Pixel::from_channels(
+                    (x * 60) as u8, (y * 60) as u8, 0, 255));
+            }
+        }
+        let atlas = PackedAtlas::pack(vec![("gradient".to_string(), gradient.clone())], 4)
+            .unwrap();
+        assert_eq!(atlas.get("gradient"), Some(rect(4, 4, 4, 4)));
+        for y in range(0, 12) {
+            for x in range(0, 12) {
+                let (sx, sy) = (if x < 4 { 0 } else if x > 7 { 3 } else { x - 4 },
+                                if y < 4 { 0 } else if y > 7 { 3 } else { y - 4 });
+                assert_eq!(pixel(atlas.image(), x, y), pixel(&gradient, sx, sy));
+            }
+        }
+    }
+
+    #[test]
+    fn too_large_images_fail() {
+        let err = PackedAtlas::pack(vec![
+            ("huge".to_string(), solid(MAX_ATLAS_SIZE, 1, (255, 255, 255))),
+        ], 8).err().unwrap();
+        assert!(match err {
+            GameError::InvalidConfig(msg) => msg == "Images do not fit into the texture atlas",
+            _ => false,
+        });
+
+        let err = PackedAtlas::pack(vec![("empty".to_string(), solid(0, 4, (0, 0, 0)))], 8)
+            .err().unwrap();
+        assert!(match err {
+            GameError::InvalidConfig(msg) => msg == "Empty image in texture atlas",
+            _ => false,
+        });
+    }
+}
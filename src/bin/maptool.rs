@@ -0,0 +1,146 @@
+extern crate tanks;
+
+use std::os;
+use std::error::Error;
+
+use tanks::errors::{Res, GameError};
+use tanks::map::{Map, MapFormat, Tile};
+
+static USAGE : &'static str = "usage: tanks-maptool <command> [args]
+
+commands:
+  validate <map>...     checks maps for problems
+  ascii <map>           prints the terrain with one character per tile
+  convert <in> <out>    converts between TMX and JSON by extension
+  stats <map>           prints tile counts and spawns";
+
+
+fn print_error(path: &Path, err: &GameError) {
+    match err.detail() {
+        Some(detail) => println!("{}: {} ({})", path.display(), err.description(), detail),
+        None => println!("{}: {}", path.display(), err.description()),
+    }
+}
+
+/// Prints the problems of every map.  Returns false if any map could not
+/// be loaded or has problems.
+fn validate(paths: &[String]) -> bool {
+    let mut ok = true;
+    for path in paths.iter() {
+        let path = Path::new(path.as_slice());
+        let map = match Map::open(&path) {
+            Ok(map) => map,
+            Err(err) => {
+                print_error(&path, &err);
+                ok = false;
+                continue;
+            }
+        };
+        for problem in map.validate().iter() {
+            println!("{}: {}", path.display(), problem);
+            ok = false;
+        }
+    }
+    ok
+}
+
+fn ascii(map: &Map) {
+    let mut used = vec![];
+    for y in range(0, map.height()) {
+        let mut line = String::new();
+        for x in range(0, map.width()) {
+            let tile = map.get_tile(x, y);
+            line.push(tile.get_glyph());
+            if !used.contains(&tile) {
+                used.push(tile);
+            }
+        }
+        println!("{}", line.as_slice().trim_right());
+    }
+    println!("");
+    for tile in used.iter() {
+        println!("'{}' {}", tile.get_glyph(), tile);
+    }
+}
+
+fn convert(input: &Path, output: &Path) -> Res<()> {
+    let map = try!(Map::open(input));
+    map.save(output, MapFormat::from_path(output))
+}
+
+fn stats(map: &Map) {
+    println!("size: {}x{}", map.width(), map.height());
+    println!("layers: {}", map.layers().len());
+    println!("");
+
+    let mut counts : Vec<(Tile, uint)> = vec![];
+    for y in range(0, map.height()) {
+        for x in range(0, map.width()) {
+            let tile = map.get_tile(x, y);
+            match counts.iter().position(|&(other, _)| other == tile) {
+                Some(idx) => {
+                    let (_, count) = counts[idx];
+                    counts[idx] = (tile, count + 1);
+                }
+                None => counts.push((tile, 1)),
+            }
+        }
+    }
+    counts.sort_by(|&(_, a), &(_, b)| b.cmp(&a));
+    println!("tiles:");
+    for &(tile, count) in counts.iter() {
+        println!("  {:5} {}", count, tile.debug());
+    }
+    println!("");
+
+    println!("spawns:");
+    for point in map.spawn_points().iter() {
+        println!("  {:12} {:6.2} {:6.2}", point.spawn.name(), point.x, point.y);
+    }
+}
+
+/// Opens a map for a command that works on a single map.
+fn with_map(args: &[String], f: |&Map|) -> bool {
+    if args.len() != 1 {
+        println!("{}", USAGE);
+        return false;
+    }
+    let path = Path::new(args[0].as_slice());
+    match Map::open(&path) {
+        Ok(map) => { f(&map); true }
+        Err(err) => { print_error(&path, &err); false }
+    }
+}
+
+fn main() {
+    let args = os::args();
+    if args.len() < 2 {
+        println!("{}", USAGE);
+        os::set_exit_status(2);
+        return;
+    }
+    let rest = args.slice_from(2);
+
+    let ok = match args[1].as_slice() {
+        "validate" => validate(rest),
+        "ascii" => with_map(rest, |map| ascii(map)),
+        "stats" => with_map(rest, |map| stats(map)),
+        "convert" if rest.len() == 2 => {
+            let input = Path::new(rest[0].as_slice());
+            let output = Path::new(rest[1].as_slice());
+            match convert(&input, &output) {
+                Ok(()) => true,
+                Err(err) => { print_error(&input, &err); false }
+            }
+        }
+        _ => {
+            println!("{}", USAGE);
+            os::set_exit_status(2);
+            return;
+        }
+    };
+
+    if !ok {
+        os::set_exit_status(1);
+    }
+}
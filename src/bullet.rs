@@ -0,0 +1,254 @@
+use std::num::Float;
+
+use collision;
+use geom::{Vec2, approach_angle, closest_point_on_segment, segment_distance};
+use map::{Map, Tile};
+
+/// how far a bullet is moved away from a wall after a ricochet
+static BOUNCE_OFFSET : f32 = 0.001;
+
+
+/// The different kinds of projectiles.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum BulletKind {
+    /// the regular shell fired by players and most enemies
+    Standard,
+    /// a fast rocket that explodes on the first wall
+    Rocket,
+    /// a slow missile that steers towards its target
+    HeatSeeker,
+}
+
+/// The tuning values of a bullet kind.
+#[deriving(Decodable, Copy, Clone, Show)]
+pub struct BulletParams {
+    /// travel speed in tiles per second
+    pub speed: f32,
+    /// how often the bullet bounces off walls before it is destroyed
+    pub ricochets: u8,
+    /// the radius used for hitting tanks and other bullets
+    pub radius: f32,
+    /// seconds until the bullet disappears on its own
+    pub lifetime: f32,
+    /// how fast the bullet turns towards its target in radians per
+    /// second.  Zero for bullets that fly straight.
+    pub homing: f32,
+}
+
+/// The tuning values of all bullet kinds.  These are part of the enemy
+/// config, the bullets of players are configured there as well.
+#[deriving(Decodable, Clone, Show)]
+pub struct BulletConfig {
+    pub standard: BulletParams,
+    pub rocket: BulletParams,
+    pub heat_seeker: BulletParams,
+}
+
+impl BulletConfig {
+
+    pub fn get(&self, kind: BulletKind) -> &BulletParams {
+        match kind {
+            BulletKind::Standard => &self.standard,
+            BulletKind::Rocket => &self.rocket,
+            BulletKind::HeatSeeker => &self.heat_seeker,
+        }
+    }
+}
+
+impl BulletKind {
+
+    /// looks up a bullet kind by the name used in config files
+    pub fn from_name(name: &str) -> Option<BulletKind> {
+        match name {
+            "standard" => Some(BulletKind::Standard),
+            "rocket" => Some(BulletKind::Rocket),
+            "heat_seeker" => Some(BulletKind::HeatSeeker),
+            _ => None,
+        }
+    }
+}
+
+#[deriving(Clone, Show)]
+pub struct Bullet {
+    pub id: uint,
+    pub kind: BulletKind,
+    /// the id of the tank that fired the bullet
+    pub owner: uint,
+    pub pos: Vec2,
+    /// where the bullet was before the last step
+    pub prev_pos: Vec2,
+    /// The way the bullet took during the last step from `prev_pos` to
+    /// `pos`, with a point for every ricochet in between.
+    pub path: Vec<Vec2>,
+    pub vel: Vec2,
+    pub radius: f32,
+    pub ricochets_left: u8,
+    pub age: f32,
+    pub lifetime: f32,
+    pub homing: f32,
+    /// bullets cannot hit the tank that fired them until they left it
+    pub armed: bool,
+    pub alive: bool,
+}
+
+impl Bullet {
+
+    pub fn new(id: uint, kind: BulletKind, params: &BulletParams, owner: uint,
+               pos: Vec2, angle: f32) -> Bullet {
+        Bullet {
+            id: id,
+            kind: kind,
+            owner: owner,
+            pos: pos,
+            prev_pos: pos,
+            path: vec![pos],
+            vel: Vec2::from_angle(angle).scale(params.speed),
+            radius: params.radius,
+            ricochets_left: params.ricochets,
+            age: 0.0,
+            lifetime: params.lifetime,
+            homing: params.homing,
+            armed: false,
+            alive: true,
+        }
+    }
+
+    /// Turns a homing bullet towards `target` for `dt` seconds.  Bullets
+    /// without homing ignore this.
+    pub fn steer_towards(&mut self, target: Vec2, dt: f32) {
+        if self.homing <= 0.0 {
+            return;
+        }
+        let speed = self.vel.length();
+        let angle = approach_angle(self.vel.angle(),
+                                   target.sub(self.pos).angle(),
+                                   self.homing * dt);
+        self.vel = Vec2::from_angle(angle).scale(speed);
+    }
+
+    /// Moves the bullet for `dt` seconds.  The path is swept against the
+    /// tile grid and reflected on every tile that blocks bullets until
+    /// the bullet runs out of ricochets.
+    pub fn advance(&mut self, map: &Map, dt: f32) {
+        self.prev_pos = self.pos;
+        self.path.clear();
+        self.path.push(self.pos);
+        self.age += dt;
+        if self.age > self.lifetime {
+            self.alive = false;
+            return;
+        }
+
+        let mut delta = self.vel.scale(dt);
+        loop {
+            let target = self.pos.add(delta);
+            let hit = match collision::sweep_ray(map, self.pos, target,
+                                                 Tile::blocks_bullet) {
+                Some(hit) => hit,
+                None => {
+                    self.pos = target;
+                    self.path.push(target);
+                    break;
+                }
+            };
+
+            self.pos = hit.pos.add(hit.normal.scale(BOUNCE_OFFSET));
+            self.path.push(hit.pos);
+            if self.ricochets_left == 0 {
+                self.alive = false;
+                return;
+            }
+            self.ricochets_left -= 1;
+            self.vel = self.vel.reflect(hit.normal);
+            delta = delta.scale(1.0 - hit.fraction).reflect(hit.normal);
+        }
+
+        if self.pos.x < 0.0 || self.pos.y < 0.0 ||
+           self.pos.x >= map.width() as f32 || self.pos.y >= map.height() as f32 {
+            self.alive = false;
+        }
+    }
+
+    /// the shortest distance between the path of the last step and `pos`
+    pub fn distance_to(&self, pos: Vec2) -> f32 {
+        if self.path.len() < 2 {
+            return self.pos.distance(pos);
+        }
+        self.path.windows(2)
+            .map(|seg| closest_point_on_segment(seg[0], seg[1], pos).distance(pos))
+            .fold(Float::infinity(), |a, b| a.min(b))
+    }
+
+    /// the shortest distance between the paths two bullets took during
+    /// the last step
+    pub fn distance_to_bullet(&self, other: &Bullet) -> f32 {
+        if self.path.len() < 2 {
+            return other.distance_to(self.pos);
+        }
+        if other.path.len() < 2 {
+            return self.distance_to(other.pos);
+        }
+        let mut rv = Float::infinity();
+        for a in self.path.windows(2) {
+            for b in other.path.windows(2) {
+                rv = rv.min(segment_distance(a[0], a[1], b[0], b[1]));
+            }
+        }
+        rv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use std::f32::consts::PI;
+
+    use geom::Vec2;
+    use map::Map;
+    use super::{Bullet, BulletKind, BulletParams};
+
+    static STANDARD : BulletParams = BulletParams {
+        speed: 6.0,
+        ricochets: 1,
+        radius: 0.08,
+        lifetime: 8.0,
+        homing: 0.0,
+    };
+
+    fn corridor() -> Map {
+        Map::from_glyphs(&[
+            "11111111",
+            "1......1",
+            "11111111",
+        ]).unwrap()
+    }
+
+    #[test]
+    fn ricochets_are_part_of_the_path() {
+        let map = corridor();
+        let mut bullet = Bullet::new(1, BulletKind::Standard, &STANDARD, 1,
+                                     Vec2::new(6.5, 1.5), 0.0);
+        let speed = bullet.vel.length();
+        bullet.advance(&map, 1.0 / speed);
+        assert!(bullet.alive);
+        assert_eq!(bullet.path.len(), 3);
+        assert!(bullet.path[1].distance(Vec2::new(7.0, 1.5)) < 0.0001);
+        assert!((bullet.pos.x - 6.5).abs() < 0.01);
+        // the wall was touched even though the step ended where it began
+        assert!(bullet.distance_to(Vec2::new(7.0, 1.5)) < 0.0001);
+    }
+
+    #[test]
+    fn bullets_crossing_between_steps_meet() {
+        let map = corridor();
+        let mut a = Bullet::new(1, BulletKind::Standard, &STANDARD, 1,
+                                Vec2::new(2.0, 1.5), 0.0);
+        let mut b = Bullet::new(2, BulletKind::Standard, &STANDARD, 2,
+                                Vec2::new(3.0, 1.5), PI);
+        let dt = 0.8 / a.vel.length();
+        a.advance(&map, dt);
+        b.advance(&map, dt);
+        assert!(a.pos.distance(b.pos) > a.radius + b.radius);
+        assert!(a.distance_to_bullet(&b) < 0.0001);
+    }
+}
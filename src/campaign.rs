@@ -0,0 +1,202 @@
+use std::io;
+use serialize::{json, Decodable};
+
+use errors::{Res, GameError};
+use map::Map;
+use resources::ResourceLoader;
+
+
+/// How a level is going.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum Outcome {
+    /// players and enemies are left
+    Playing,
+    /// every enemy was destroyed
+    Won,
+    /// every player was destroyed
+    Lost,
+}
+
+/// What happens after a level ended.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum Progress {
+    /// the level is not over yet
+    Continue,
+    /// the level was won and the next one is up
+    NextLevel,
+    /// the level was lost and is played again
+    RetryLevel,
+    /// the level was lost and there are no lives left
+    GameOver,
+    /// the last level was won
+    Completed,
+}
+
+#[deriving(Decodable)]
+struct CampaignData {
+    lives: u32,
+    maps: Vec<String>,
+}
+
+/// An ordered list of maps that are played one after another.  Losing
+/// a level costs a life and restarts it, winning moves on to the next.
+pub struct Campaign {
+    maps: Vec<String>,
+    starting_lives: u32,
+    current: uint,
+    lives: u32,
+}
+
+impl Campaign {
+
+    pub fn new(maps: Vec<String>, lives: u32) -> Res<Campaign> {
+        if maps.is_empty() {
+            return Err(GameError::InvalidConfig("Campaign has no maps"));
+        }
+        if lives == 0 {
+            return Err(GameError::InvalidConfig("Campaign needs at least one life"));
+        }
+        Ok(Campaign {
+            maps: maps,
+            starting_lives: lives,
+            current: 0,
+            lives: lives,
+        })
+    }
+
+    pub fn open(path: &Path) -> Res<Campaign> {
+        let mut file = try!(io::File::open(path));
+        let json = try!(json::from_reader(&mut file));
+        let mut decoder = json::Decoder::new(json);
+        let cd : CampaignData = try!(Decodable::decode(&mut decoder));
+        Campaign::new(cd.maps, cd.lives)
+    }
+
+    /// the index of the level that is being played
+    #[inline(always)]
+    pub fn level(&self) -> uint {
+        self.current
+    }
+
+    #[inline(always)]
+    pub fn level_count(&self) -> uint {
+        self.maps.len()
+    }
+
+    #[inline(always)]
+    pub fn lives(&self) -> u32 {
+        self.lives
+    }
+
+    /// the file name of the map of the current level
+    pub fn current_map_name(&self) -> &str {
+        self.maps[self.current].as_slice()
+    }
+
+    pub fn load_current_map(&self, rl: &ResourceLoader) -> Res<Map> {
+        rl.load_map(self.current_map_name())
+    }
+
+    /// Feeds the outcome of the current level into the campaign and
+    /// moves it along accordingly.
+    pub fn report(&mut self, outcome: Outcome) -> Progress {
+        match outcome {
+            Outcome::Playing => Progress::Continue,
+            Outcome::Won => {
+                if self.current + 1 >= self.maps.len() {
+                    Progress::Completed
+                } else {
+                    self.current += 1;
+                    Progress::NextLevel
+                }
+            }
+            Outcome::Lost => {
+                if self.lives == 0 {
+                    return Progress::GameOver;
+                }
+                self.lives -= 1;
+                if self.lives == 0 {
+                    Progress::GameOver
+                } else {
+                    Progress::RetryLevel
+                }
+            }
+        }
+    }
+
+    /// starts over from the first level with all lives
+    pub fn restart(&mut self) {
+        self.current = 0;
+        self.lives = self.starting_lives;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ai::AiConfig;
+    use map::Map;
+    use world::{World, Inputs};
+    use super::{Campaign, Outcome, Progress};
+
+    fn campaign(lives: u32) -> Campaign {
+        Campaign::new(vec!["one.json".to_string(), "two.json".to_string()], lives).unwrap()
+    }
+
+    #[test]
+    fn playing_a_level_to_the_end_advances() {
+        let map = Map::from_glyphs(&[
+            "1111111",
+            "1P...T1",
+            "1111111",
+        ]).unwrap();
+        let config = AiConfig::open(&Path::new("resources/config/enemies.json")).unwrap();
+        let mut world = World::new(&map, config);
+        let mut inputs = Inputs::new();
+        // the player faces the enemy from the start
+        inputs.players[0].fire = true;
+        for _ in range(0, 300u) {
+            if world.outcome() != Outcome::Playing {
+                break;
+            }
+            world.step(1.0 / 60.0, &inputs);
+        }
+        assert_eq!(world.outcome(), Outcome::Won);
+
+        let mut campaign = campaign(3);
+        assert_eq!(campaign.report(world.outcome()), Progress::NextLevel);
+        assert_eq!(campaign.level(), 1);
+        assert_eq!(campaign.current_map_name(), "two.json");
+        assert_eq!(campaign.report(Outcome::Won), Progress::Completed);
+        assert_eq!(campaign.lives(), 3);
+    }
+
+    #[test]
+    fn playing_does_not_change_anything() {
+        let mut campaign = campaign(1);
+        assert_eq!(campaign.report(Outcome::Playing), Progress::Continue);
+        assert_eq!(campaign.level(), 0);
+        assert_eq!(campaign.lives(), 1);
+    }
+
+    #[test]
+    fn losing_costs_lives_until_game_over() {
+        let mut campaign = campaign(2);
+        assert_eq!(campaign.report(Outcome::Lost), Progress::RetryLevel);
+        assert_eq!(campaign.lives(), 1);
+        assert_eq!(campaign.report(Outcome::Lost), Progress::GameOver);
+        assert_eq!(campaign.lives(), 0);
+        // reporting after the game is over must not wrap the lives
+        assert_eq!(campaign.report(Outcome::Lost), Progress::GameOver);
+        assert_eq!(campaign.lives(), 0);
+
+        campaign.restart();
+        assert_eq!(campaign.lives(), 2);
+        assert_eq!(campaign.level(), 0);
+    }
+
+    #[test]
+    fn campaigns_need_maps_and_lives() {
+        assert!(Campaign::new(vec![], 3).is_err());
+        assert!(Campaign::new(vec!["one.json".to_string()], 0).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::num::Float;
+use std::f32::INFINITY;
+
+use geom::Vec2;
+use map::{Map, Tile};
+
+/// how often a circle is pushed out of tiles before giving up
+static MAX_ITERATIONS : uint = 4;
+/// distances below this are considered to be zero
+static EPSILON : f32 = 0.0001;
+
+
+/// Where a ray hit a tile.
+#[deriving(Copy, Clone, Show)]
+pub struct RayHit {
+    /// the point on the tile edge that was hit
+    pub pos: Vec2,
+    /// the unit normal of the tile edge that was hit
+    pub normal: Vec2,
+    /// how far along the ray the hit happened, from 0.0 to 1.0
+    pub fraction: f32,
+    /// the column of the tile that was hit
+    pub x: i32,
+    /// the row of the tile that was hit
+    pub y: i32,
+}
+
+fn clamp(value: f32, min: f32, max: f32) -> f32 {
+    value.max(min).min(max)
+}
+
+/// Returns the vector that moves a circle out of the given tile or
+/// `None` if the two do not overlap.
+pub fn circle_tile_push(pos: Vec2, radius: f32, x: i32, y: i32) -> Option<Vec2> {
+    let (left, top) = (x as f32, y as f32);
+    let (right, bottom) = (left + 1.0, top + 1.0);
+    let closest = Vec2::new(clamp(pos.x, left, right), clamp(pos.y, top, bottom));
+    let diff = pos.sub(closest);
+    let dist = diff.length();
+
+    if dist >= radius {
+        return None;
+    }
+
+    if dist > EPSILON {
+        return Some(diff.scale((radius - dist) / dist));
+    }
+
+    // the center is inside the tile, leave through the nearest edge.
+    let exits = [
+        (pos.x - left, Vec2::new(-1.0, 0.0)),
+        (right - pos.x, Vec2::new(1.0, 0.0)),
+        (pos.y - top, Vec2::new(0.0, -1.0)),
+        (bottom - pos.y, Vec2::new(0.0, 1.0)),
+    ];
+    let (mut best_dist, mut best_normal) = exits[0];
+    for &(dist, normal) in exits.iter() {
+        if dist < best_dist {
+            best_dist = dist;
+            best_normal = normal;
+        }
+    }
+    Some(best_normal.scale(best_dist + radius))
+}
+
+/// true if a circle overlaps any tile that `blocks` rejects
+pub fn circle_overlaps(map: &Map, pos: Vec2, radius: f32,
+                       blocks: fn(&Tile) -> bool) -> bool {
+    for y in range((pos.y - radius).floor() as i32, (pos.y + radius).floor() as i32 + 1) {
+        for x in range((pos.x - radius).floor() as i32, (pos.x + radius).floor() as i32 + 1) {
+            if blocks(&map.get_tile_checked(x, y)) &&
+               circle_tile_push(pos, radius, x, y).is_some() {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Pushes a circle out of all tiles that `blocks` rejects.  The deepest
+/// overlap is resolved first which makes circles slide along walls and
+/// into corners instead of getting stuck on tile seams.
+pub fn resolve_circle(map: &Map, pos: Vec2, radius: f32,
+                      blocks: fn(&Tile) -> bool) -> Vec2 {
+    let mut pos = pos;
+
+    for _ in range(0, MAX_ITERATIONS) {
+        let mut best : Option<Vec2> = None;
+        let mut best_depth = 0.0;
+
+        for y in range((pos.y - radius).floor() as i32, (pos.y + radius).floor() as i32 + 1) {
+            for x in range((pos.x - radius).floor() as i32, (pos.x + radius).floor() as i32 + 1) {
+                if !blocks(&map.get_tile_checked(x, y)) {
+                    continue;
+                }
+                let push = unwrap_or!(circle_tile_push(pos, radius, x, y), continue);
+                let depth = push.length_sq();
+                if depth > best_depth {
+                    best_depth = depth;
+                    best = Some(push);
+                }
+            }
+        }
+
+        match best {
+            Some(push) => { pos = pos.add(push); }
+            None => { break; }
+        }
+    }
+
+    pos
+}
+
+/// Moves a circle by `delta` and resolves collisions on the way.  Long
+/// moves are split into steps no longer than half the radius so that
+/// the circle cannot skip over thin walls.
+pub fn move_circle(map: &Map, pos: Vec2, delta: Vec2, radius: f32,
+                   blocks: fn(&Tile) -> bool) -> Vec2 {
+    let steps = (delta.length() / (radius * 0.5)).ceil().max(1.0) as uint;
+    let step = delta.scale(1.0 / steps as f32);
+    let mut pos = pos;
+    for _ in range(0, steps) {
+        pos = resolve_circle(map, pos.add(step), radius, blocks);
+    }
+    pos
+}
+
+/// Walks the tile grid along the segment from `from` to `to` and returns
+/// the first tile edge that `blocks` rejects.  Every tile the segment
+/// touches is visited so even very long segments cannot pass through
+/// a wall that is just one tile thick.  The tile `from` is in is not
+/// checked.
+pub fn sweep_ray(map: &Map, from: Vec2, to: Vec2,
+                 blocks: fn(&Tile) -> bool) -> Option<RayHit> {
+    let delta = to.sub(from);
+    let mut x = from.x.floor() as i32;
+    let mut y = from.y.floor() as i32;
+
+    let (step_x, mut t_max_x, t_delta_x) = if delta.x > 0.0 {
+        (1, (x as f32 + 1.0 - from.x) / delta.x, 1.0 / delta.x)
+    } else if delta.x < 0.0 {
+        (-1, (from.x - x as f32) / -delta.x, 1.0 / -delta.x)
+    } else {
+        (0, INFINITY, INFINITY)
+    };
+    let (step_y, mut t_max_y, t_delta_y) = if delta.y > 0.0 {
+        (1, (y as f32 + 1.0 - from.y) / delta.y, 1.0 / delta.y)
+    } else if delta.y < 0.0 {
+        (-1, (from.y - y as f32) / -delta.y, 1.0 / -delta.y)
+    } else {
+        (0, INFINITY, INFINITY)
+    };
+
+    loop {
+        let (t, normal) = if t_max_x < t_max_y {
+            let t = t_max_x;
+            x += step_x;
+            t_max_x += t_delta_x;
+            (t, Vec2::new(-step_x as f32, 0.0))
+        } else {
+            let t = t_max_y;
+            y += step_y;
+            t_max_y += t_delta_y;
+            (t, Vec2::new(0.0, -step_y as f32))
+        };
+
+        if t > 1.0 {
+            return None;
+        }
+
+        if blocks(&map.get_tile_checked(x, y)) {
+            return Some(RayHit {
+                pos: from.add(delta.scale(t)),
+                normal: normal,
+                fraction: t,
+                x: x,
+                y: y,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use geom::Vec2;
+    use map::{Map, Tile};
+    use super::{resolve_circle, move_circle, sweep_ray, circle_overlaps};
+
+    static RADIUS : f32 = 0.35;
+
+    fn room() -> Map {
+        Map::from_glyphs(&[
+            "111111",
+            "1....1",
+            "1....1",
+            "1....1",
+            "111111",
+        ]).unwrap()
+    }
+
+    fn assert_near(pos: Vec2, x: f32, y: f32) {
+        assert!((pos.x - x).abs() < 0.001 && (pos.y - y).abs() < 0.001,
+                "expected {}, {} but got {}", x, y, pos);
+    }
+
+    #[test]
+    fn free_circles_stay_put() {
+        let map = room();
+        let pos = Vec2::new(2.5, 2.5);
+        assert_eq!(resolve_circle(&map, pos, RADIUS, Tile::blocks_tank), pos);
+        assert!(!circle_overlaps(&map, pos, RADIUS, Tile::blocks_tank));
+    }
+
+    #[test]
+    fn circle_slides_along_wall() {
+        let map = room();
+        let pos = move_circle(&map, Vec2::new(2.0, 1.5), Vec2::new(1.0, -1.0),
+                              RADIUS, Tile::blocks_tank);
+        assert_near(pos, 3.0, 1.0 + RADIUS);
+
+        let pos = move_circle(&map, Vec2::new(1.5, 2.0), Vec2::new(-1.0, 0.5),
+                              RADIUS, Tile::blocks_tank);
+        assert_near(pos, 1.0 + RADIUS, 2.5);
+    }
+
+    #[test]
+    fn corner_pushes_out_on_both_axes() {
+        let map = room();
+        let pos = resolve_circle(&map, Vec2::new(1.2, 1.1), RADIUS, Tile::blocks_tank);
+        assert_near(pos, 1.0 + RADIUS, 1.0 + RADIUS);
+
+        let pos = move_circle(&map, Vec2::new(3.5, 2.5), Vec2::new(2.0, 2.0),
+                              RADIUS, Tile::blocks_tank);
+        assert_near(pos, 5.0 - RADIUS, 4.0 - RADIUS);
+    }
+
+    #[test]
+    fn circle_inside_a_wall_leaves_through_the_nearest_edge() {
+        let map = room();
+        let pos = resolve_circle(&map, Vec2::new(2.5, 0.9), RADIUS, Tile::blocks_tank);
+        assert_near(pos, 2.5, 1.0 + RADIUS);
+    }
+
+    #[test]
+    fn holes_and_water_block_tanks_but_not_bullets() {
+        let map = Map::from_glyphs(&[
+            "1111111",
+            "1..O..1",
+            "1..~..1",
+            "1..\"..1",
+            "1111111",
+        ]).unwrap();
+        for &y in [1.5f32, 2.5].iter() {
+            let pos = move_circle(&map, Vec2::new(1.5, y), Vec2::new(3.0, 0.0),
+                                  RADIUS, Tile::blocks_tank);
+            assert_near(pos, 3.0 - RADIUS, y);
+            assert!(sweep_ray(&map, Vec2::new(1.5, y), Vec2::new(5.5, y),
+                              Tile::blocks_bullet).is_none());
+        }
+
+        // swamps only slow tanks down
+        let pos = move_circle(&map, Vec2::new(1.5, 3.5), Vec2::new(3.0, 0.0),
+                              RADIUS, Tile::blocks_tank);
+        assert_near(pos, 4.5, 3.5);
+    }
+
+    #[test]
+    fn long_moves_do_not_tunnel_through_walls() {
+        let map = Map::from_glyphs(&[
+            "1111111",
+            "1..1..1",
+            "1111111",
+        ]).unwrap();
+        let pos = move_circle(&map, Vec2::new(1.5, 1.5), Vec2::new(4.0, 0.0),
+                              RADIUS, Tile::blocks_tank);
+        assert_near(pos, 3.0 - RADIUS, 1.5);
+
+        let hit = sweep_ray(&map, Vec2::new(1.5, 1.5), Vec2::new(5.5, 1.5),
+                            Tile::blocks_bullet).unwrap();
+        assert_eq!((hit.x, hit.y), (3, 1));
+        assert_near(hit.pos, 3.0, 1.5);
+        assert_eq!(hit.normal, Vec2::new(-1.0, 0.0));
+    }
+}
@@ -30,6 +30,7 @@ impl Engine {
             return Err(GameError::WindowInitError));
 
         window.make_current();
+        glfw.set_swap_interval(1);
         glfw.set_error_callback(glfw::FAIL_ON_ERRORS);
         window.set_key_polling(true);
 
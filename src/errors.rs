@@ -9,11 +9,16 @@ use image;
 pub enum GameError {
     EngineInitError,
     WindowInitError,
-    InvalidMap(&'static str),
+    /// a broken map and the tile the problem is at if it is known
+    InvalidMap(&'static str, Option<(u16, u16)>),
+    InvalidConfig(&'static str),
+    MeshError(&'static str),
 
     TextureError(device::tex::TextureError),
     ImageError(image::ImageError),
-    ProgramError(render::ProgramError),
+    /// a shader that failed to build and the compiler log with its line
+    /// references pointing to the shader files if there is one
+    ProgramError(render::ProgramError, Option<String>),
     BatchError(render::batch::BatchError),
     IoError(io::IoError),
     JsonParseError(json::ParserError),
@@ -38,11 +43,13 @@ impl Error for GameError {
         match *self {
             GameError::EngineInitError => "failed to initialize engine",
             GameError::WindowInitError => "failed to create window",
-            GameError::InvalidMap(desc) => desc,
+            GameError::InvalidMap(desc, _) => desc,
+            GameError::InvalidConfig(desc) => desc,
+            GameError::MeshError(desc) => desc,
 
             GameError::TextureError(_) => "texture error",
             GameError::ImageError(_) => "image error",
-            GameError::ProgramError(_) => "program error",
+            GameError::ProgramError(_, _) => "program error",
             GameError::BatchError(_) => "batch error",
             GameError::IoError(ref err) => err.description(),
             GameError::JsonParseError(_) => "Could not parse JSON",
@@ -54,11 +61,13 @@ impl Error for GameError {
         match *self {
             GameError::TextureError(ref err) => Some(err.to_string()),
             GameError::ImageError(ref err) => Some(get_image_error_detail(err)),
-            GameError::ProgramError(ref err) => Some(err.to_string()),
+            GameError::ProgramError(_, Some(ref log)) => Some(log.clone()),
+            GameError::ProgramError(ref err, None) => Some(err.to_string()),
             GameError::BatchError(ref err) => Some(err.to_string()),
             GameError::IoError(ref err) => err.detail(),
             GameError::JsonParseError(_) => None,
             GameError::JsonDecodeError(ref err) => err.detail(),
+            GameError::InvalidMap(_, Some((x, y))) => Some(format!("at tile {}, {}", x, y)),
             _ => None,
         }
     }
@@ -80,7 +89,7 @@ impl FromError<device::tex::TextureError> for GameError {
 
 impl FromError<render::ProgramError> for GameError {
     fn from_error(err: render::ProgramError) -> GameError {
-        GameError::ProgramError(err)
+        GameError::ProgramError(err, None)
     }
 }
 
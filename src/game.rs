@@ -1,20 +1,27 @@
 use time;
 use cgmath;
 use cgmath::FixedArray;
-use cgmath::Matrix;
-use gfx::{Device, DeviceHelper};
+use cgmath::{Matrix, Matrix4};
 use glfw::{Context, WindowEvent};
 use gfx;
 use glfw;
 
-use std::io::timer::sleep;
-use std::time::duration::Duration;
 use std::error::Error;
+use std::num::Float;
 
-use errors::Res;
+use errors::{Res, GameError};
 use engine::Engine;
 use resources::ResourceLoader;
-use texture::{Texture, BasicTexture};
+use shaders::ShaderProgram;
+use texture::Texture;
+use atlas::TextureAtlas;
+use world::{World, Inputs, PlayerInput, EntityBox};
+use timestep::{FixedTimestep, DEFAULT_TICK_RATE};
+use campaign::{Outcome, Progress};
+use ai::AiConfig;
+use appearance::TileAppearance;
+use map::{Map, MapMesh, MapChunk};
+use meshutils::{CubeMaker, upload_mesh};
 
 #[shader_param(CubeBatch)]
 struct Params {
@@ -24,117 +31,127 @@ struct Params {
     #[name = "u_Time"]
     time: f32,
 
+    #[name = "u_Ambient"]
+    ambient: [f32, ..4],
+
     #[name = "t_Color"]
     color: gfx::shade::TextureParam,
 }
 
-static VERTEX_SRC: gfx::ShaderSource<'static> = shaders! {
-GLSL_150: b"
-    #version 150 core
-
-    in vec3 a_Pos;
-    in vec3 a_Normal;
-    in vec2 a_TexCoord;
-    out vec2 v_TexCoord;
-    out vec3 v_Normal;
-    out vec3 v_SunDirection;
-    out vec3 v_HalfVec;
-
-    uniform mat4 u_Transform;
-
-    void main() {
-        v_TexCoord = a_TexCoord;
-        v_Normal = a_Normal;
-        v_SunDirection = normalize(vec3(0.4, 0.3, 1.0));
-        v_HalfVec = normalize(a_Pos + v_SunDirection);
-        gl_Position = u_Transform * vec4(a_Pos, 1.0);
+fn key_axis(engine: &Engine, negative: glfw::Key, positive: glfw::Key) -> f32 {
+    let mut rv = 0.0;
+    if engine.window.get_key(negative) == glfw::Action::Press {
+        rv -= 1.0;
     }
-"
-};
-
-static FRAGMENT_SRC: gfx::ShaderSource<'static> = shaders! {
-GLSL_150: b"
-    #version 150 core
-
-    in vec2 v_TexCoord;
-    in vec3 v_Normal;
-    in vec3 v_HalfVec;
-    in vec3 v_SunDirection;
-    out vec4 o_Color;
-
-    uniform sampler2D t_Color;
-
-    void directionalLight(in vec3 normal,
-                          in vec3 lightDir,
-                          in vec3 halfVec,
-                          in float shininess,
-                          in vec4 lightDiffuse,
-                          in vec4 lightSpecular,
-                          inout vec4 diffuse,
-                          inout vec4 specular)
-    {
-        float nDotVp; /* normal . light dir */
-        float nDotHv; /* normal . half vec */
-        float pf; /* power factor */
-        nDotVp = max(0.0, dot(normal, normalize(lightDir)));
-        nDotHv = max(0.0, dot(normal, halfVec));
-        pf = (nDotVp == 0.0) ? 0.0 : pow(nDotHv, shininess);
-        diffuse += lightDiffuse * nDotVp;
-        specular += lightSpecular * pf;
+    if engine.window.get_key(positive) == glfw::Action::Press {
+        rv += 1.0;
     }
+    rv
+}
 
-    void main() {
-        vec4 darkness = vec4(0.1, 0.1, 0.1, 1.0);
-        vec4 ambient = vec4(0.4, 0.4, 0.4, 1.0);
-        vec4 sunColor = vec4(1.0, 1.0, 1.0, 0.3);
-        vec4 diffuse = vec4(0.0);
-        vec4 specular = vec4(0.0);
-
-        directionalLight(v_Normal, v_SunDirection, v_HalfVec,
-                         30.0, sunColor, vec4(0.0), diffuse,
-                         specular);
-                         
-        vec4 color = texture(t_Color, v_TexCoord);
-        color = color * clamp(darkness + ambient + diffuse, 0.0, 1.0);
-
-        o_Color = color;
-    }
-"
-};
+fn read_inputs(engine: &Engine) -> Inputs {
+    let mut inputs = Inputs::new();
+    inputs.players[0] = PlayerInput {
+        forward: key_axis(engine, glfw::Key::S, glfw::Key::W),
+        turn: key_axis(engine, glfw::Key::A, glfw::Key::D),
+        turret: key_axis(engine, glfw::Key::Left, glfw::Key::Right),
+        fire: engine.window.get_key(glfw::Key::Space) == glfw::Action::Press,
+        lay_mine: engine.window.get_key(glfw::Key::E) == glfw::Action::Press,
+    };
+    inputs
+}
 
+/// how long the game keeps running after a level was won or lost
+static LEVEL_END_DELAY : f64 = 2.0;
 
-fn run_everything() -> Res<()> {
-    let engine = try!(Engine::new());
-    let rl = ResourceLoader::new();
+/// the ambient light of maps that do not set their own
+static DEFAULT_AMBIENT : [f32, ..4] = [0.4, 0.4, 0.4, 1.0];
 
-    let frame = engine.new_frame();
-    let mut device = engine.new_device();
+/// how often shader files are checked for changes in debug builds
+static SHADER_POLL_INTERVAL : f64 = 0.5;
 
-    let image = try!(rl.load_image("board.png"));
-    let texture_map = try!(BasicTexture::from_image(&mut device, &image));
-    let sampler = device.create_sampler(
-        gfx::tex::SamplerInfo::new(gfx::tex::FilterMethod::Bilinear,
-                                   gfx::tex::WrapMode::Clamp)
-    );
+/// the atlas image tanks, bullets and mines are drawn with
+static ENTITY_IMAGE : &'static str = "box";
 
-    let map = try!(rl.load_map("map001.json"));
-    let map_mesh = map.create_mesh(&mut device, &texture_map);
 
-    let program = try!(device.link_program(VERTEX_SRC.clone(), FRAGMENT_SRC.clone()));
-    let state = gfx::DrawState::new().depth(gfx::state::Comparison::LessEqual, true);
+type Graphics = gfx::Graphics<gfx::GlDevice, gfx::GlCommandBuffer>;
 
-    let mut graphics = gfx::Graphics::new(device);
-    let batch: CubeBatch = try!(graphics.make_batch(
-        &program, map_mesh.get_mesh(), map_mesh.get_slice(), &state));
+/// Makes the batch drawing a chunk of the map, `None` for empty chunks.
+fn make_chunk_batch(graphics: &mut Graphics, program: &gfx::ProgramHandle,
+                    state: &gfx::DrawState, chunk: &MapChunk) -> Res<Option<CubeBatch>> {
+    match (chunk.get_mesh(), chunk.get_slice()) {
+        (Some(mesh), Some(slice)) =>
+            Ok(Some(try!(graphics.make_batch(program, mesh, slice, state)))),
+        _ => Ok(None),
+    }
+}
+
+/// Makes the unit cube entities are drawn with.  It is centered on the
+/// origin and moved into place for every entity.
+fn make_entity_cube(device: &mut gfx::GlDevice,
+                    atlas: &TextureAtlas) -> Res<(gfx::Mesh, gfx::Slice)> {
+    let tex = unwrap_or!(atlas.get_slice(ENTITY_IMAGE),
+        return Err(GameError::InvalidConfig("Entity image missing from texture atlas")));
+    let mut cube_maker = CubeMaker::new(1.0);
+    cube_maker.add_all_sides((0.0, 0.0, 0.0), 1.0, &tex);
+    let (vertices, indexes) = try!(cube_maker.finish());
+    Ok(upload_mesh(device, vertices.as_slice(), indexes))
+}
+
+/// Makes the batch drawing the entity cube.
+fn make_cube_batch(graphics: &mut Graphics, program: &gfx::ProgramHandle,
+                   state: &gfx::DrawState,
+                   cube: &(gfx::Mesh, gfx::Slice)) -> Res<CubeBatch> {
+    let (ref mesh, slice) = *cube;
+    Ok(try!(graphics.make_batch(program, mesh, slice, state)))
+}
+
+/// Where the unit cube goes to draw an entity box.  In the space of the
+/// map mesh tile centers are at whole numbers, the ground is half a tile
+/// up and the rows of the map run along -z.
+fn box_transform(map: &Map, entity: &EntityBox) -> Matrix4<f32> {
+    let (cos, sin) = (entity.heading.cos(), entity.heading.sin());
+    Matrix4::new(
+        entity.length * cos, 0.0, -entity.length * sin, 0.0,
+        0.0, entity.height, 0.0, 0.0,
+        entity.width * sin, 0.0, entity.width * cos, 0.0,
+        entity.pos.x - 0.5, 0.5 + entity.lift + entity.height / 2.0,
+        map.height() as f32 - entity.pos.y - 0.5, 1.0)
+}
+
+/// Plays a single level until it is over or the window is closed.  The
+/// returned outcome is `Outcome::Playing` if the window was closed.
+fn play_level(engine: &Engine,
+              graphics: &mut Graphics,
+              program: &mut ShaderProgram, state: &gfx::DrawState,
+              atlas: &TextureAtlas, appearance: &TileAppearance,
+              cube: &(gfx::Mesh, gfx::Slice),
+              map: &Map, ai_config: &AiConfig) -> Res<Outcome> {
+    let frame = engine.new_frame();
+    if let Some(ref name) = map.properties().name {
+        engine.window.set_title(name.as_slice());
+    }
+    let mut world = World::new(map, ai_config.clone());
+
+    // the world's copy of the map is meshed as crates on it get destroyed
+    let mut map_mesh = try!(MapMesh::new(&mut graphics.device, atlas,
+                                         appearance, world.map()));
+    let mut batches = vec![];
+    for chunk in map_mesh.chunks().iter() {
+        batches.push(try!(make_chunk_batch(graphics, program.handle(), state, chunk)));
+    }
+    let mut cube_batch = try!(make_cube_batch(graphics, program.handle(), state, cube));
 
     let view = map.get_camera_view();
     let proj = cgmath::perspective(cgmath::deg(30.0f32),
         engine.get_framebuffer_aspect(), 0.1, 100.0);
+    let view_proj = proj.mul_m(&view.mat);
 
     let mut data = Params {
-        transform: proj.mul_m(&view.mat).into_fixed(),
+        transform: view_proj.into_fixed(),
         time: 0.0,
-        color: (texture_map.handle(), Some(sampler)),
+        ambient: map.properties().ambient_color.unwrap_or(DEFAULT_AMBIENT),
+        color: (atlas.texture().handle(), Some(atlas.texture().sampler())),
     };
 
     let clear_data = gfx::ClearData {
@@ -143,7 +160,12 @@ fn run_everything() -> Res<()> {
         stencil: 0,
     };
 
-    let started = time::precise_time_s();
+    let mut timestep = FixedTimestep::new(DEFAULT_TICK_RATE);
+    let mut previous = world.snapshot();
+    let mut current = previous.clone();
+    let mut last_frame = time::precise_time_s();
+    let mut ended_at = None;
+    let mut last_shader_poll = last_frame;
 
     while !engine.window.should_close() {
         engine.glfw.poll_events();
@@ -155,26 +177,122 @@ fn run_everything() -> Res<()> {
             }
         }
 
-        data.time = (time::precise_time_s() - started) as f32;
+        let now = time::precise_time_s();
+        let ticks = timestep.advance(now - last_frame);
+        last_frame = now;
+
+        let inputs = read_inputs(engine);
+        for _ in range(0, ticks) {
+            world.step(timestep.dt(), &inputs);
+            previous = current;
+            current = world.snapshot();
+        }
+
+        for &idx in try!(map_mesh.update(&mut graphics.device, atlas,
+                                         appearance, world.map())).iter() {
+            batches[idx] = try!(make_chunk_batch(graphics, program.handle(), state,
+                                                 &map_mesh.chunks()[idx]));
+        }
+
+        // shaders are reloaded while developing.  A broken shader is
+        // reported and the previous version stays in use.
+        if cfg!(not(ndebug)) && now - last_shader_poll > SHADER_POLL_INTERVAL {
+            last_shader_poll = now;
+            match program.reload_if_stale(&mut graphics.device) {
+                Ok(true) => {
+                    for (idx, chunk) in map_mesh.chunks().iter().enumerate() {
+                        batches[idx] = try!(make_chunk_batch(
+                            graphics, program.handle(), state, chunk));
+                    }
+                    cube_batch = try!(make_cube_batch(
+                        graphics, program.handle(), state, cube));
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    println!("Error: Could not reload shaders");
+                    print_error_details(&err);
+                }
+            }
+        }
+
+        let outcome = world.outcome();
+        match (outcome, ended_at) {
+            (Outcome::Playing, _) => {}
+            (_, None) => { ended_at = Some(world.time()); }
+            (_, Some(at)) => {
+                if world.time() - at > LEVEL_END_DELAY {
+                    return Ok(outcome);
+                }
+            }
+        }
+
+        let snapshot = previous.interpolate(&current, timestep.alpha());
+        data.time = snapshot.time as f32;
 
         graphics.clear(clear_data, gfx::COLOR | gfx::DEPTH, &frame);
-        graphics.draw(&batch, &data, &frame);
+        data.transform = view_proj.into_fixed();
+        for batch in batches.iter() {
+            if let Some(ref batch) = *batch {
+                graphics.draw(batch, &data, &frame);
+            }
+        }
+        for entity in snapshot.boxes().iter() {
+            data.transform = view_proj.mul_m(&box_transform(world.map(), entity))
+                .into_fixed();
+            graphics.draw(&cube_batch, &data, &frame);
+        }
         graphics.end_frame();
 
         engine.window.swap_buffers();
-        sleep(Duration::milliseconds(13));
+    }
+
+    Ok(Outcome::Playing)
+}
+
+fn run_everything() -> Res<()> {
+    let engine = try!(Engine::new());
+    let rl = ResourceLoader::new();
+
+    let mut device = engine.new_device();
+
+    let appearance = try!(rl.load_tile_appearance("tiles.json"));
+    let atlas = try!(rl.load_atlas(&mut device, appearance.images(),
+                                   appearance.texture_options()));
+    try!(appearance.check(&atlas));
+    let cube = try!(make_entity_cube(&mut device, &atlas));
+
+    let mut program = try!(rl.load_program(&mut device, "map.vert", "map.frag"));
+    let state = gfx::DrawState::new().depth(gfx::state::Comparison::LessEqual, true);
+
+    let mut graphics = gfx::Graphics::new(device);
+    let ai_config = try!(rl.load_ai_config("enemies.json"));
+    let mut campaign = try!(rl.load_campaign("campaign.json"));
+
+    while !engine.window.should_close() {
+        let map = try!(campaign.load_current_map(&rl));
+        let outcome = try!(play_level(&engine, &mut graphics, &mut program, &state,
+                                      &atlas, &appearance, &cube, &map,
+                                      &ai_config));
+        match campaign.report(outcome) {
+            Progress::GameOver | Progress::Completed => campaign.restart(),
+            _ => {}
+        }
     }
 
     Ok(())
 }
 
+fn print_error_details(err: &GameError) {
+    println!("  {}", err.description());
+    match err.detail() {
+        Some(detail) => { println!("  detail: {}", detail); }
+        None => {}
+    }
+}
+
 pub fn run() {
     if let Err(err) = run_everything() {
         println!("Error: Something went wrong!");
-        println!("  {}", err.description());
-        match err.detail() {
-            Some(detail) => { println!("  detail: {}", detail); }
-            None => {}
-        }
+        print_error_details(&err);
     }
 }
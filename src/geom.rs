@@ -0,0 +1,181 @@
+use std::num::Float;
+use std::f32::consts::PI;
+
+
+/// A two dimensional vector in map space.  The x axis runs along the
+/// columns of the map, the y axis along the rows.  One unit is one tile.
+#[deriving(PartialEq, Copy, Clone, Show)]
+pub struct Vec2 {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Vec2 {
+
+    #[inline(always)]
+    pub fn new(x: f32, y: f32) -> Vec2 {
+        Vec2 { x: x, y: y }
+    }
+
+    #[inline(always)]
+    pub fn zero() -> Vec2 {
+        Vec2::new(0.0, 0.0)
+    }
+
+    /// unit vector pointing into the direction of the given angle
+    pub fn from_angle(angle: f32) -> Vec2 {
+        Vec2::new(angle.cos(), angle.sin())
+    }
+
+    #[inline(always)]
+    pub fn add(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x + other.x, self.y + other.y)
+    }
+
+    #[inline(always)]
+    pub fn sub(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x - other.x, self.y - other.y)
+    }
+
+    #[inline(always)]
+    pub fn scale(self, factor: f32) -> Vec2 {
+        Vec2::new(self.x * factor, self.y * factor)
+    }
+
+    #[inline(always)]
+    pub fn dot(self, other: Vec2) -> f32 {
+        self.x * other.x + self.y * other.y
+    }
+
+    /// the z component of the cross product of the two vectors extended
+    /// to 3D
+    #[inline(always)]
+    pub fn cross(self, other: Vec2) -> f32 {
+        self.x * other.y - self.y * other.x
+    }
+
+    #[inline(always)]
+    pub fn length_sq(self) -> f32 {
+        self.dot(self)
+    }
+
+    pub fn length(self) -> f32 {
+        self.length_sq().sqrt()
+    }
+
+    pub fn distance(self, other: Vec2) -> f32 {
+        other.sub(self).length()
+    }
+
+    /// returns the vector with a length of one.  The zero vector stays
+    /// the zero vector.
+    pub fn normalize(self) -> Vec2 {
+        let len = self.length();
+        if len > 0.0 { self.scale(1.0 / len) } else { self }
+    }
+
+    /// the angle of the vector in radians
+    pub fn angle(self) -> f32 {
+        self.y.atan2(self.x)
+    }
+
+    /// reflects the vector on a surface with the given unit normal
+    pub fn reflect(self, normal: Vec2) -> Vec2 {
+        self.sub(normal.scale(2.0 * self.dot(normal)))
+    }
+
+    /// linear interpolation between two vectors
+    pub fn lerp(self, other: Vec2, alpha: f32) -> Vec2 {
+        self.add(other.sub(self).scale(alpha))
+    }
+}
+
+/// returns the point on the segment from `a` to `b` closest to `p`
+pub fn closest_point_on_segment(a: Vec2, b: Vec2, p: Vec2) -> Vec2 {
+    let ab = b.sub(a);
+    let len_sq = ab.length_sq();
+    if len_sq <= 0.0 {
+        return a;
+    }
+    let t = (p.sub(a).dot(ab) / len_sq).max(0.0).min(1.0);
+    a.add(ab.scale(t))
+}
+
+/// The shortest distance between the segment from `a0` to `a1` and the
+/// segment from `b0` to `b1`.  Zero if they cross.
+pub fn segment_distance(a0: Vec2, a1: Vec2, b0: Vec2, b1: Vec2) -> f32 {
+    let (da, db) = (a1.sub(a0), b1.sub(b0));
+    let denom = da.cross(db);
+    if denom != 0.0 {
+        let diff = b0.sub(a0);
+        let t = diff.cross(db) / denom;
+        let u = diff.cross(da) / denom;
+        if t >= 0.0 && t <= 1.0 && u >= 0.0 && u <= 1.0 {
+            return 0.0;
+        }
+    }
+    // parallel or apart, then one of the end points is closest
+    closest_point_on_segment(a0, a1, b0).distance(b0)
+        .min(closest_point_on_segment(a0, a1, b1).distance(b1))
+        .min(closest_point_on_segment(b0, b1, a0).distance(a0))
+        .min(closest_point_on_segment(b0, b1, a1).distance(a1))
+}
+
+/// wraps an angle into the range -PI to PI
+pub fn normalize_angle(angle: f32) -> f32 {
+    let mut rv = angle % (PI * 2.0);
+    if rv > PI {
+        rv -= PI * 2.0;
+    } else if rv < -PI {
+        rv += PI * 2.0;
+    }
+    rv
+}
+
+/// rotates `current` towards `target` by at most `max_delta` radians
+/// taking the shorter way around.
+pub fn approach_angle(current: f32, target: f32, max_delta: f32) -> f32 {
+    let diff = normalize_angle(target - current);
+    if diff.abs() <= max_delta {
+        target
+    } else {
+        normalize_angle(current + diff.signum() * max_delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use super::{Vec2, segment_distance};
+
+    #[test]
+    fn crossing_segments_touch() {
+        let d = segment_distance(Vec2::new(0.0, 0.0), Vec2::new(2.0, 2.0),
+                                 Vec2::new(0.0, 2.0), Vec2::new(2.0, 0.0));
+        assert_eq!(d, 0.0);
+    }
+
+    #[test]
+    fn apart_segments_measure_end_points() {
+        // parallel
+        let d = segment_distance(Vec2::new(0.0, 0.0), Vec2::new(2.0, 0.0),
+                                 Vec2::new(1.0, 0.5), Vec2::new(3.0, 0.5));
+        assert!((d - 0.5).abs() < 0.0001);
+        // the end of one segment points at the middle of the other
+        let d = segment_distance(Vec2::new(0.0, 0.0), Vec2::new(2.0, 0.0),
+                                 Vec2::new(1.0, 3.0), Vec2::new(1.0, 1.0));
+        assert!((d - 1.0).abs() < 0.0001);
+        // collinear but apart
+        let d = segment_distance(Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0),
+                                 Vec2::new(3.0, 0.0), Vec2::new(4.0, 0.0));
+        assert!((d - 2.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn points_are_segments_too() {
+        let p = Vec2::new(1.0, 1.0);
+        let d = segment_distance(p, p, Vec2::new(0.0, 0.0), Vec2::new(2.0, 0.0));
+        assert!((d - 1.0).abs() < 0.0001);
+    }
+}
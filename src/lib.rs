@@ -0,0 +1,47 @@
+#![feature(phase, if_let, macro_rules)]
+
+extern crate serialize;
+extern crate gl;
+extern crate gfx;
+extern crate time;
+extern crate device;
+extern crate render;
+extern crate cgmath;
+#[phase(plugin)]
+extern crate gfx_macros;
+extern crate glfw;
+extern crate image;
+extern crate flate;
+extern crate xml;
+
+mod macros;
+
+pub mod errors;
+pub mod map;
+pub mod properties;
+pub mod tmx;
+pub mod tiledjson;
+pub mod mapgen;
+pub mod appearance;
+pub mod engine;
+pub mod game;
+pub mod texture;
+pub mod atlas;
+pub mod mipmap;
+pub mod shaders;
+pub mod resources;
+pub mod meshutils;
+pub mod geom;
+pub mod world;
+pub mod tank;
+pub mod collision;
+pub mod bullet;
+pub mod ai;
+pub mod pathfinding;
+pub mod raycast;
+pub mod mine;
+pub mod campaign;
+pub mod timestep;
+
+#[cfg(test)]
+mod testutils;
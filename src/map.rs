@@ -1,20 +1,28 @@
-use std::io;
+use std::cmp;
+use std::fmt;
+use std::default::Default;
 use std::num::FromPrimitive;
-use serialize::{json, Decodable};
 
 use cgmath::{Transform, AffineMatrix3};
 use cgmath::{Point3, Vector3};
 use gfx;
-use gfx::{Device, DeviceHelper, ToSlice};
 
+use appearance::TileAppearance;
+use atlas::TextureAtlas;
 use errors::{Res, GameError};
-use meshutils::CubeMaker;
-use texture::{Texture, TextureSlice};
+use geom::Vec2;
+use meshutils::{CubeMaker, Face, Indexes, Vertex, merge_cells, upload_mesh};
+use pathfinding::{NavGrid, DistanceField, tile_of};
+use properties::{Properties, MapProperties};
+use texture::Texture;
+use tiledjson;
+use tmx;
+use world::MAX_PLAYERS;
 
 static TILE_SIZE : f32 = 1.0;
 
 
-#[deriving(PartialEq, Eq, FromPrimitive, Copy, Show)]
+#[deriving(PartialEq, Eq, FromPrimitive, Copy, Clone, Show)]
 pub enum Tile {
     /* environment */
         /// out of bounds ground
@@ -75,7 +83,17 @@ pub enum Tile {
         Crate = 4,
 }
 
-#[deriving(Copy, Show)]
+static ALL_TILES : [Tile, ..26] = [
+    Tile::Oob, Tile::Ground, Tile::Hole, Tile::Water, Tile::Swamp,
+    Tile::Wall1, Tile::Wall2, Tile::Wall3, Tile::Wall4, Tile::Wall5,
+    Tile::Box1, Tile::Box2, Tile::Box3, Tile::Box4, Tile::Box5,
+    Tile::Player1Spawn, Tile::Player2Spawn, Tile::Player3Spawn, Tile::Player4Spawn,
+    Tile::StationaryEnemy, Tile::BasicEnemy, Tile::FastEnemy,
+    Tile::HeatSeekerEnemy, Tile::RocketEnemy,
+    Tile::Mine, Tile::Crate,
+];
+
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
 pub enum EnemyType {
     Stationary,
     Basic,
@@ -84,24 +102,61 @@ pub enum EnemyType {
     Rocket,
 }
 
-#[deriving(Copy, Show)]
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
 pub enum ObjectType {
     Mine,
     Crate,
 }
 
-#[deriving(Copy, Show)]
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
 pub enum Spawn {
     Player(u8),
     Enemy(EnemyType),
     Object(ObjectType),
 }
 
+impl Spawn {
+
+    /// Looks up a spawn by the name used for spawn objects in maps.
+    pub fn from_name(name: &str) -> Option<Spawn> {
+        match name {
+            "player1" => Some(Spawn::Player(0)),
+            "player2" => Some(Spawn::Player(1)),
+            "player3" => Some(Spawn::Player(2)),
+            "player4" => Some(Spawn::Player(3)),
+            "stationary" => Some(Spawn::Enemy(EnemyType::Stationary)),
+            "basic" => Some(Spawn::Enemy(EnemyType::Basic)),
+            "fast" => Some(Spawn::Enemy(EnemyType::Fast)),
+            "heat_seeker" => Some(Spawn::Enemy(EnemyType::HeatSeeker)),
+            "rocket" => Some(Spawn::Enemy(EnemyType::Rocket)),
+            "mine" => Some(Spawn::Object(ObjectType::Mine)),
+            "crate" => Some(Spawn::Object(ObjectType::Crate)),
+            _ => None,
+        }
+    }
+
+    /// the name used for spawn objects in maps
+    pub fn name(&self) -> String {
+        match *self {
+            Spawn::Player(idx) => format!("player{}", idx + 1),
+            Spawn::Enemy(EnemyType::Stationary) => "stationary".to_string(),
+            Spawn::Enemy(EnemyType::Basic) => "basic".to_string(),
+            Spawn::Enemy(EnemyType::Fast) => "fast".to_string(),
+            Spawn::Enemy(EnemyType::HeatSeeker) => "heat_seeker".to_string(),
+            Spawn::Enemy(EnemyType::Rocket) => "rocket".to_string(),
+            Spawn::Object(ObjectType::Mine) => "mine".to_string(),
+            Spawn::Object(ObjectType::Crate) => "crate".to_string(),
+        }
+    }
+}
+
 impl Tile {
 
-    /// construct a tile type from a gid
-    pub fn from_gid(gid: u8) -> Option<Tile> {
-        FromPrimitive::from_u8(gid)
+    /// Construct a tile type from a gid.  This assumes the meta tileset
+    /// starts at gid 1, maps with other tilesets resolve their gids with
+    /// `Map::resolve_gid` instead.
+    pub fn from_gid(gid: u32) -> Option<Tile> {
+        FromPrimitive::from_u32(gid)
     }
 
     /// true if the tiel is out of bounds
@@ -116,9 +171,10 @@ impl Tile {
     ///
     /// Tanks can stand on these tiles.
     pub fn is_ground(&self) -> bool {
-        // if things spawn on it, they are on a ground tile.
+        // if things spawn on it, they are on a ground tile unless the
+        // spawned thing is in the way itself (crates).
         if self.is_spawn() {
-            return true;
+            return !self.is_obstacle();
         }
 
         match *self {
@@ -144,6 +200,34 @@ impl Tile {
         self.height() > 0
     }
 
+    /// true if tanks cannot enter the tile.  Holes and water stop tanks
+    /// but not bullets, swamps only slow tanks down.
+    pub fn blocks_tank(&self) -> bool {
+        match *self {
+            Tile::Hole | Tile::Water => true,
+            _ => self.is_obstacle() || self.is_oob(),
+        }
+    }
+
+    /// true if tanks drive slower on this tile
+    pub fn slows_tank(&self) -> bool {
+        match *self {
+            Tile::Swamp => true,
+            _ => false,
+        }
+    }
+
+    /// How far the surface of the tile is below the ground in tiles.
+    /// Zero for everything that is not a hole or a fluid.
+    pub fn recess_depth(&self) -> f32 {
+        match *self {
+            Tile::Hole => 0.9,
+            Tile::Water => 0.3,
+            Tile::Swamp => 0.08,
+            _ => 0.0,
+        }
+    }
+
     /// true if the tile is a spawn
     pub fn is_spawn(&self) -> bool {
         self.get_spawn().is_some()
@@ -152,7 +236,7 @@ impl Tile {
     /// returns the height of the tile
     pub fn height(&self) -> u8 {
         match *self {
-            Tile::Wall1 | Tile::Box1 => 1,
+            Tile::Wall1 | Tile::Box1 | Tile::Crate => 1,
             Tile::Wall2 | Tile::Box2 => 2,
             Tile::Wall3 | Tile::Box3 => 3,
             Tile::Wall4 | Tile::Box4 => 4,
@@ -184,25 +268,107 @@ impl Tile {
         }
     }
 
-    /// get the gid
-    pub fn get_gid(&self) -> u8 {
-        *self as u8
+    /// the spawn tile for something that spawns
+    pub fn from_spawn(spawn: Spawn) -> Option<Tile> {
+        match spawn {
+            Spawn::Player(0) => Some(Tile::Player1Spawn),
+            Spawn::Player(1) => Some(Tile::Player2Spawn),
+            Spawn::Player(2) => Some(Tile::Player3Spawn),
+            Spawn::Player(3) => Some(Tile::Player4Spawn),
+            Spawn::Player(_) => None,
+            Spawn::Enemy(EnemyType::Stationary) => Some(Tile::StationaryEnemy),
+            Spawn::Enemy(EnemyType::Basic) => Some(Tile::BasicEnemy),
+            Spawn::Enemy(EnemyType::Fast) => Some(Tile::FastEnemy),
+            Spawn::Enemy(EnemyType::HeatSeeker) => Some(Tile::HeatSeekerEnemy),
+            Spawn::Enemy(EnemyType::Rocket) => Some(Tile::RocketEnemy),
+            Spawn::Object(ObjectType::Mine) => Some(Tile::Mine),
+            Spawn::Object(ObjectType::Crate) => Some(Tile::Crate),
+        }
     }
 
-    /// Return a texture slice for this tile from an atlas.  The atlas
-    /// needs to be 8x8.
-    pub fn get_texture_slice<'a>(&self, tex: &'a Texture) -> TextureSlice<'a> {
-        //let idx = (self.get_gid() - 1) as u16;
-        //let w = tex.width() / 8;
-        //let h = tex.height() / 8;
-        //let x = (idx % 8) * w;
-        //let y = (idx / 8) * h;
-        let idx = if self.height() == 0 { 0 } else { 1 };
-        let w = tex.width() / 4;
-        let h = tex.height() / 4;
-        let x = (idx % 4) * w;
-        let y = (idx / 4) * h;
-        tex.safe_slice(x, y, w, h)
+    /// get the gid, assuming the meta tileset starts at gid 1
+    pub fn get_gid(&self) -> u32 {
+        *self as u32
+    }
+
+    /// A character that stands for the tile in text dumps of maps.
+    /// Walls are their height, boxes go from `v` (1) to `z` (5).
+    pub fn get_glyph(&self) -> char {
+        match *self {
+            Tile::Oob => ' ',
+            Tile::Ground => '.',
+            Tile::Hole => 'O',
+            Tile::Water => '~',
+            Tile::Swamp => '"',
+            Tile::Wall1 => '1',
+            Tile::Wall2 => '2',
+            Tile::Wall3 => '3',
+            Tile::Wall4 => '4',
+            Tile::Wall5 => '5',
+            Tile::Box1 => 'v',
+            Tile::Box2 => 'w',
+            Tile::Box3 => 'x',
+            Tile::Box4 => 'y',
+            Tile::Box5 => 'z',
+            Tile::Player1Spawn => 'P',
+            Tile::Player2Spawn => 'Q',
+            Tile::Player3Spawn => 'R',
+            Tile::Player4Spawn => 'S',
+            Tile::StationaryEnemy => 'T',
+            Tile::BasicEnemy => 'B',
+            Tile::FastEnemy => 'F',
+            Tile::HeatSeekerEnemy => 'H',
+            Tile::RocketEnemy => 'K',
+            Tile::Mine => '*',
+            Tile::Crate => '=',
+        }
+    }
+
+    /// every tile there is
+    pub fn all() -> &'static [Tile] {
+        ALL_TILES.as_slice()
+    }
+
+    /// the name of the tile in config files
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Tile::Oob => "oob",
+            Tile::Ground => "ground",
+            Tile::Hole => "hole",
+            Tile::Water => "water",
+            Tile::Swamp => "swamp",
+            Tile::Wall1 => "wall1",
+            Tile::Wall2 => "wall2",
+            Tile::Wall3 => "wall3",
+            Tile::Wall4 => "wall4",
+            Tile::Wall5 => "wall5",
+            Tile::Box1 => "box1",
+            Tile::Box2 => "box2",
+            Tile::Box3 => "box3",
+            Tile::Box4 => "box4",
+            Tile::Box5 => "box5",
+            Tile::Player1Spawn => "player1_spawn",
+            Tile::Player2Spawn => "player2_spawn",
+            Tile::Player3Spawn => "player3_spawn",
+            Tile::Player4Spawn => "player4_spawn",
+            Tile::StationaryEnemy => "stationary_enemy",
+            Tile::BasicEnemy => "basic_enemy",
+            Tile::FastEnemy => "fast_enemy",
+            Tile::HeatSeekerEnemy => "heat_seeker_enemy",
+            Tile::RocketEnemy => "rocket_enemy",
+            Tile::Mine => "mine",
+            Tile::Crate => "crate",
+        }
+    }
+
+    /// looks up a tile by the name used in config files
+    pub fn from_name(name: &str) -> Option<Tile> {
+        Tile::all().iter().map(|x| *x).find(|tile| tile.name() == name)
+    }
+
+    /// looks up a tile by its glyph in text dumps of maps
+    pub fn from_glyph(glyph: char) -> Option<Tile> {
+        Tile::all().iter().map(|x| *x).find(|tile| tile.get_glyph() == glyph)
     }
 
     /// get detail debug info
@@ -217,43 +383,645 @@ impl Tile {
     }
 }
 
+/// The file formats maps can be stored in.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum MapFormat {
+    /// Tiled's JSON export
+    Json,
+    /// Tiled's native XML format
+    Tmx,
+}
+
+impl MapFormat {
+
+    /// guesses the format from the extension of a file
+    pub fn from_path(path: &Path) -> MapFormat {
+        match path.extension_str() {
+            Some("tmx") => MapFormat::Tmx,
+            _ => MapFormat::Json,
+        }
+    }
+}
+
+/// The name of the layer the terrain is read from.  Maps without a
+/// layer of that name use their first tile layer.
+pub static TERRAIN_LAYER : &'static str = "environment";
+
+/// The name of the tileset whose tiles are the game's `Tile`s.  Maps
+/// without a tileset of that name use their first tileset.
+pub static META_TILESET : &'static str = "mapmeta";
+
+/// the size of a tile in pixels for maps that do not say otherwise
+pub static DEFAULT_TILE_PIXELS : u32 = 32;
+
+/// the width and height of the parts the map mesh is split into, in tiles
+pub static CHUNK_SIZE : u16 = 8;
+
+/// how many chunks a map of a size is split into along x and y
+fn chunk_counts(width: u16, height: u16) -> (u16, u16) {
+    ((width + CHUNK_SIZE - 1) / CHUNK_SIZE, (height + CHUNK_SIZE - 1) / CHUNK_SIZE)
+}
+
+/// Tiled stores how a tile is flipped in the upper bits of the gid.
+pub static FLIPPED_HORIZONTALLY : u32 = 0x80000000;
+pub static FLIPPED_VERTICALLY : u32 = 0x40000000;
+pub static FLIPPED_DIAGONALLY : u32 = 0x20000000;
+/// only used by hexagonal maps, but it still has to be stripped
+static ROTATED_HEXAGONAL_120 : u32 = 0x10000000;
+static GID_MASK : u32 = !(FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY |
+                          FLIPPED_DIAGONALLY | ROTATED_HEXAGONAL_120);
+
+
+/// How a tile is flipped.  Rotations are expressed by Tiled as a
+/// combination of a diagonal flip with a horizontal or vertical one.
+#[deriving(PartialEq, Eq, Copy, Clone, Default, Show)]
+pub struct Orientation {
+    pub flip_horizontally: bool,
+    pub flip_vertically: bool,
+    pub flip_diagonally: bool,
+}
+
+impl Orientation {
+
+    /// Splits a raw gid from a map file into the actual gid and the
+    /// orientation of the tile.
+    pub fn split_gid(raw: u32) -> (u32, Orientation) {
+        (raw & GID_MASK, Orientation {
+            flip_horizontally: raw & FLIPPED_HORIZONTALLY != 0,
+            flip_vertically: raw & FLIPPED_VERTICALLY != 0,
+            flip_diagonally: raw & FLIPPED_DIAGONALLY != 0,
+        })
+    }
+
+    /// the flags to merge into a gid when storing it
+    pub fn bits(&self) -> u32 {
+        (if self.flip_horizontally { FLIPPED_HORIZONTALLY } else { 0 }) |
+        (if self.flip_vertically { FLIPPED_VERTICALLY } else { 0 }) |
+        (if self.flip_diagonally { FLIPPED_DIAGONALLY } else { 0 })
+    }
+
+    #[inline(always)]
+    pub fn is_identity(&self) -> bool {
+        self.bits() == 0
+    }
+
+    /// Applies the orientation to a texture coordinate within a tile.
+    /// Like in Tiled the diagonal flip happens first.
+    pub fn apply(&self, u: f32, v: f32) -> (f32, f32) {
+        let (u, v) = if self.flip_diagonally { (v, u) } else { (u, v) };
+        (if self.flip_horizontally { 1.0 - u } else { u },
+         if self.flip_vertically { 1.0 - v } else { v })
+    }
+}
+
+/// A tileset referenced by a map.  Gids from `first_gid` on belong to
+/// the tileset.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Tileset {
+    pub name: String,
+    pub first_gid: u32,
+    /// the file of an external tileset, such tilesets carry nothing but
+    /// their name and first gid
+    pub source: Option<String>,
+    /// the number of tiles if the map file says so
+    pub tile_count: Option<u32>,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// the image the tiles are cut from, relative to the map
+    pub image: Option<String>,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub margin: u32,
+    pub spacing: u32,
+    pub properties: Properties,
+}
+
+impl Tileset {
+
+    /// the tileset the game's tiles come from in maps that were created
+    /// without any tilesets.  The image is where the map editor finds it
+    /// from the maps folder.
+    pub fn meta() -> Tileset {
+        Tileset {
+            name: META_TILESET.to_string(),
+            first_gid: 1,
+            source: None,
+            tile_count: None,
+            tile_width: DEFAULT_TILE_PIXELS,
+            tile_height: DEFAULT_TILE_PIXELS,
+            image: Some("../../artwork/meta-tiles.png".to_string()),
+            image_width: DEFAULT_TILE_PIXELS * 8,
+            image_height: DEFAULT_TILE_PIXELS * 8,
+            margin: 0,
+            spacing: 0,
+            properties: Properties::new(),
+        }
+    }
+
+    pub fn contains(&self, gid: u32) -> bool {
+        gid >= self.first_gid && match self.tile_count {
+            Some(count) => gid - self.first_gid < count,
+            None => true,
+        }
+    }
+}
+
+/// A grid of raw gids, one per tile of the map.  The gids still carry
+/// Tiled's flip flags.
+#[deriving(Clone, PartialEq, Show)]
+pub struct TileLayer {
+    pub name: String,
+    pub visible: bool,
+    pub data: Vec<u32>,
+}
+
+impl TileLayer {
+
+    /// the gid and the orientation of the tile at `idx`
+    pub fn get(&self, idx: uint) -> (u32, Orientation) {
+        Orientation::split_gid(self.data[idx])
+    }
+}
+
+/// What an object on an object layer is for.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ObjectKind {
+    /// something spawns here
+    Spawn(Spawn),
+    /// an area that reacts to tanks entering it
+    Trigger,
+    /// a point for enemies to navigate along
+    Waypoint,
+    /// anything the game does not know about
+    Unknown,
+}
+
+/// A freely positioned object.  Coordinates are in tiles.
+#[deriving(Clone, PartialEq, Show)]
+pub struct MapObject {
+    pub id: u32,
+    pub name: String,
+    /// the type as written in the map file
+    pub type_name: String,
+    pub kind: ObjectKind,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub visible: bool,
+}
+
+/// A list of free objects.
+#[deriving(Clone, PartialEq, Show)]
+pub struct ObjectLayer {
+    pub name: String,
+    pub visible: bool,
+    pub objects: Vec<MapObject>,
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Layer {
+    Tiles(TileLayer),
+    Objects(ObjectLayer),
+}
+
+impl Layer {
+
+    pub fn name(&self) -> &str {
+        match *self {
+            Layer::Tiles(ref layer) => layer.name.as_slice(),
+            Layer::Objects(ref layer) => layer.name.as_slice(),
+        }
+    }
+}
+
+/// A place where something spawns, in tile coordinates.
+#[deriving(Copy, Clone, PartialEq, Show)]
+pub struct SpawnPoint {
+    pub spawn: Spawn,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[deriving(Clone, PartialEq)]
 pub struct Map {
     width: u16,
     height: u16,
+    tile_width: u32,
+    tile_height: u32,
+    tilesets: Vec<Tileset>,
+    layers: Vec<Layer>,
+    properties: MapProperties,
+    terrain_layer: uint,
     tiles: Vec<Tile>,
+    revision: u32,
+    /// the revision every chunk of the map mesh was last changed at,
+    /// row by row
+    chunk_revisions: Vec<u32>,
 }
 
-#[deriving(Decodable)]
-struct MapLayerData {
-    data: Vec<u8>,
+/// A problem with a map found by `Map::validate`.
+#[deriving(Clone, PartialEq)]
+pub struct MapProblem {
+    /// the tile the problem is at, `None` if it concerns the whole map
+    pub pos: Option<(u16, u16)>,
+    pub message: String,
 }
 
-#[deriving(Decodable)]
-struct MapData {
-    width: u16,
-    height: u16,
-    layers: Vec<MapLayerData>,
+impl MapProblem {
+
+    pub fn at(pos: (u16, u16), message: String) -> MapProblem {
+        MapProblem { pos: Some(pos), message: message }
+    }
+
+    pub fn global(message: String) -> MapProblem {
+        MapProblem { pos: None, message: message }
+    }
+}
+
+impl fmt::Show for MapProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pos {
+            Some((x, y)) => write!(f, "{}, {}: {}", x, y, self.message),
+            None => write!(f, "map: {}", self.message),
+        }
+    }
+}
+
+/// The raw data of a map as stored by Tiled.  Both the JSON and the TMX
+/// loader produce this, `Map::from_map_data` turns it into a map.
+pub struct MapData {
+    pub width: u16,
+    pub height: u16,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tilesets: Vec<Tileset>,
+    pub layers: Vec<Layer>,
+    pub properties: Properties,
+}
+
+impl ObjectKind {
+
+    /// Works out the kind of an object from its type and name.  Spawn
+    /// objects have the type `spawn` and name what they spawn.
+    pub fn from_object(type_name: &str, name: &str) -> ObjectKind {
+        match type_name {
+            "spawn" => match Spawn::from_name(name) {
+                Some(spawn) => ObjectKind::Spawn(spawn),
+                None => ObjectKind::Unknown,
+            },
+            "trigger" => ObjectKind::Trigger,
+            "waypoint" => ObjectKind::Waypoint,
+            _ => ObjectKind::Unknown,
+        }
+    }
+}
+
+/// the tileset the game's tiles come from
+fn meta_tileset(tilesets: &[Tileset]) -> &Tileset {
+    tilesets.iter().find(|t| t.name.as_slice() == META_TILESET)
+        .unwrap_or(&tilesets[0])
+}
+
+/// the tileset a gid belongs to
+fn find_tileset(tilesets: &[Tileset], gid: u32) -> Option<&Tileset> {
+    // tilesets are sorted, the last one starting at or before the gid
+    // is the one it belongs to.
+    match tilesets.iter().rev().find(|t| t.first_gid <= gid) {
+        Some(tileset) if tileset.contains(gid) => Some(tileset),
+        _ => None,
+    }
+}
+
+/// Finds the tileset a gid belongs to and turns the gid into a tile if
+/// that is the meta tileset.
+fn resolve_gid(tilesets: &[Tileset], gid: u32) -> Option<Tile> {
+    let tileset = unwrap_or!(find_tileset(tilesets, gid), return None);
+    if tileset != meta_tileset(tilesets) {
+        return None;
+    }
+    Tile::from_gid(gid - tileset.first_gid + 1)
+}
+
+/// True if a gid means something.  Zero is an empty tile, gids of the
+/// meta tileset have to be a tile the game knows and the terrain layer
+/// may not use any other tileset.
+fn is_known_gid(tilesets: &[Tileset], gid: u32, terrain: bool) -> bool {
+    if gid == 0 {
+        return true;
+    }
+    let tileset = unwrap_or!(find_tileset(tilesets, gid), return false);
+    if tileset == meta_tileset(tilesets) {
+        resolve_gid(tilesets, gid).is_some()
+    } else {
+        !terrain
+    }
+}
+
+/// the first tile of any tile layer with a gid that is not known
+fn find_unknown_gid(width: u16, tilesets: &[Tileset], layers: &[Layer],
+                    terrain_layer: uint) -> Option<(u16, u16)> {
+    for (layer_idx, layer) in layers.iter().enumerate() {
+        let layer = match *layer {
+            Layer::Tiles(ref layer) => layer,
+            Layer::Objects(_) => { continue; }
+        };
+        for idx in range(0, layer.data.len()) {
+            let (gid, _) = layer.get(idx);
+            if !is_known_gid(tilesets, gid, layer_idx == terrain_layer) {
+                return Some(((idx % width as uint) as u16,
+                             (idx / width as uint) as u16));
+            }
+        }
+    }
+    None
 }
 
 impl Map {
 
+    /// Creates a map with a single terrain layer from a row-major list
+    /// of tiles.
+    pub fn new(width: u16, height: u16, tiles: Vec<Tile>) -> Res<Map> {
+        let layer = TileLayer {
+            name: TERRAIN_LAYER.to_string(),
+            visible: true,
+            data: tiles.iter().map(|t| t.get_gid()).collect(),
+        };
+        Map::from_map_data(MapData {
+            width: width,
+            height: height,
+            tile_width: DEFAULT_TILE_PIXELS,
+            tile_height: DEFAULT_TILE_PIXELS,
+            tilesets: vec![Tileset::meta()],
+            layers: vec![Layer::Tiles(layer)],
+            properties: Properties::new(),
+        })
+    }
+
+    /// Creates a map from rows of tile glyphs as `Tile::get_glyph` gives
+    /// them.  All rows need to be equally long.
+    pub fn from_glyphs(rows: &[&str]) -> Res<Map> {
+        let width = rows.get(0).map_or(0, |row| row.chars().count());
+        let mut tiles = vec![];
+        for row in rows.iter() {
+            if row.chars().count() != width {
+                return Err(GameError::InvalidMap("Rows of different length", None));
+            }
+            for glyph in row.chars() {
+                tiles.push(unwrap_or!(Tile::from_glyph(glyph),
+                    return Err(GameError::InvalidMap("Unknown tile glyph", None))));
+            }
+        }
+        Map::new(width as u16, rows.len() as u16, tiles)
+    }
+
+    /// Opens a map.  Files ending in `.tmx` are read as Tiled XML maps,
+    /// everything else as Tiled JSON export.
     pub fn open(path: &Path) -> Res<Map> {
-        let mut file = try!(io::File::open(path));
-        let json = try!(json::from_reader(&mut file));
-        let mut decoder = json::Decoder::new(json);
-        let md : MapData = try!(Decodable::decode(&mut decoder));
-        let tiles : Vec<Tile> = md.layers[0].data.iter().map(
-                |&x| Tile::from_gid(x).unwrap_or(Tile::Oob)).collect();
-
-        if (md.width * md.height) as uint != tiles.len() {
-            Err(GameError::InvalidMap("Invalid dimensions"))
-        } else {
-            Ok(Map {
-                width: md.width,
-                height: md.height,
-                tiles: tiles,
-            })
+        match MapFormat::from_path(path) {
+            MapFormat::Tmx => tmx::open(path),
+            MapFormat::Json => tiledjson::open(path),
+        }
+    }
+
+    /// Writes the map in a format Tiled can open.  Everything the map
+    /// was loaded from is kept, so loading the file again gives the
+    /// same map.
+    pub fn save(&self, path: &Path, format: MapFormat) -> Res<()> {
+        match format {
+            MapFormat::Tmx => tmx::save(self, path),
+            MapFormat::Json => tiledjson::save(self, path),
+        }
+    }
+
+    pub fn from_map_data(mut md: MapData) -> Res<Map> {
+        // maps without tilesets are from before the game knew about
+        // them and always used the meta tiles from gid 1 on.
+        if md.tilesets.is_empty() {
+            md.tilesets.push(Tileset::meta());
+        }
+        md.tilesets.sort_by(|a, b| a.first_gid.cmp(&b.first_gid));
+
+        let mut terrain_layer = None;
+        for (idx, layer) in md.layers.iter().enumerate() {
+            if let Layer::Tiles(ref tiles) = *layer {
+                if (md.width as uint) * (md.height as uint) != tiles.data.len() {
+                    return Err(GameError::InvalidMap("Invalid dimensions", None));
+                }
+                if terrain_layer.is_none() || layer.name() == TERRAIN_LAYER {
+                    terrain_layer = Some(idx);
+                }
+            }
+        }
+        let terrain_layer = unwrap_or!(terrain_layer,
+            return Err(GameError::InvalidMap("Map has no tile layers", None)));
+        if let Some(pos) = find_unknown_gid(md.width, md.tilesets.as_slice(),
+                                            md.layers.as_slice(), terrain_layer) {
+            return Err(GameError::InvalidMap("Unknown tile gid", Some(pos)));
+        }
+
+        let tiles : Vec<Tile> = match md.layers[terrain_layer] {
+            // all gids are known at this point, only empty tiles are left
+            // over which are out of bounds.
+            Layer::Tiles(ref layer) => layer.data.iter().map(|&raw| {
+                let (gid, _) = Orientation::split_gid(raw);
+                resolve_gid(md.tilesets.as_slice(), gid).unwrap_or(Tile::Oob)
+            }).collect(),
+            Layer::Objects(_) => unreachable!(),
+        };
+
+        let (chunks_x, chunks_y) = chunk_counts(md.width, md.height);
+        Ok(Map {
+            width: md.width,
+            height: md.height,
+            tile_width: md.tile_width,
+            tile_height: md.tile_height,
+            tilesets: md.tilesets,
+            layers: md.layers,
+            properties: try!(MapProperties::from_properties(md.properties)),
+            terrain_layer: terrain_layer,
+            tiles: tiles,
+            revision: 0,
+            chunk_revisions: Vec::from_elem(chunks_x as uint * chunks_y as uint, 0),
+        })
+    }
+
+    /// the metadata of the level
+    pub fn properties(&self) -> &MapProperties {
+        &self.properties
+    }
+
+    /// the tilesets of the map ordered by their first gid
+    pub fn tilesets(&self) -> &[Tileset] {
+        self.tilesets.as_slice()
+    }
+
+    /// Resolves a gid without flip flags into a tile.  Gids that are not
+    /// from the meta tileset have no meaning to the game.
+    pub fn resolve_gid(&self, gid: u32) -> Option<Tile> {
+        resolve_gid(self.tilesets.as_slice(), gid)
+    }
+
+    /// all layers of the map in the order they are stored
+    pub fn layers(&self) -> &[Layer] {
+        self.layers.as_slice()
+    }
+
+    pub fn get_layer(&self, name: &str) -> Option<&Layer> {
+        self.layers.iter().find(|l| l.name() == name)
+    }
+
+    /// the name of the layer the terrain comes from
+    pub fn terrain_layer_name(&self) -> &str {
+        self.layers[self.terrain_layer].name()
+    }
+
+    /// every object of every object layer
+    pub fn objects(&self) -> Vec<&MapObject> {
+        let mut rv = vec![];
+        for layer in self.layers.iter() {
+            if let Layer::Objects(ref layer) = *layer {
+                rv.extend(layer.objects.iter());
+            }
+        }
+        rv
+    }
+
+    /// Collects everything that spawns on the map: spawn tiles on any
+    /// tile layer (at the center of the tile) and spawn objects (at the
+    /// center of the object).
+    pub fn spawn_points(&self) -> Vec<SpawnPoint> {
+        let mut rv = vec![];
+        for layer in self.layers.iter() {
+            match *layer {
+                Layer::Tiles(ref layer) => {
+                    for idx in range(0, layer.data.len()) {
+                        let (gid, _) = layer.get(idx);
+                        let tile = unwrap_or!(self.resolve_gid(gid), continue);
+                        let spawn = unwrap_or!(tile.get_spawn(), continue);
+                        rv.push(SpawnPoint {
+                            spawn: spawn,
+                            x: (idx % self.width as uint) as f32 + 0.5,
+                            y: (idx / self.width as uint) as f32 + 0.5,
+                        });
+                    }
+                }
+                Layer::Objects(ref layer) => {
+                    for obj in layer.objects.iter() {
+                        if let ObjectKind::Spawn(spawn) = obj.kind {
+                            rv.push(SpawnPoint {
+                                spawn: spawn,
+                                x: obj.x + obj.width / 2.0,
+                                y: obj.y + obj.height / 2.0,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        rv
+    }
+
+    /// Checks the map for problems that make it unplayable.  Unlike load
+    /// errors these do not stop the map from being used, they are meant
+    /// for map authors.
+    pub fn validate(&self) -> Vec<MapProblem> {
+        let mut problems = vec![];
+        self.check_border(&mut problems);
+
+        let spawns = self.spawn_points();
+        let players = self.check_player_spawns(spawns.as_slice(), &mut problems);
+        self.check_tank_spawns(spawns.as_slice(), players.as_slice(), &mut problems);
+        problems
+    }
+
+    /// tanks and bullets must not be able to leave the map
+    fn check_border(&self, problems: &mut Vec<MapProblem>) {
+        let (w, h) = (self.width, self.height);
+        for y in range(0, h) {
+            for x in range(0, w) {
+                if x != 0 && y != 0 && x != w - 1 && y != h - 1 {
+                    continue;
+                }
+                let tile = self.get_tile(x, y);
+                if !tile.is_oob() && !(tile.is_obstacle() && tile != Tile::Crate) {
+                    problems.push(MapProblem::at((x, y), format!(
+                        "map border is open ({})", tile)));
+                }
+            }
+        }
+    }
+
+    /// Every player needs exactly one spawn and player spawns may not
+    /// skip a player.  Returns the spawn tile of each player.
+    fn check_player_spawns(&self, spawns: &[SpawnPoint],
+                           problems: &mut Vec<MapProblem>) -> Vec<Option<(u16, u16)>> {
+        let mut players = Vec::from_elem(MAX_PLAYERS, None);
+        for point in spawns.iter() {
+            if let Spawn::Player(idx) = point.spawn {
+                let pos = tile_of(Vec2::new(point.x, point.y));
+                if players[idx as uint].is_some() {
+                    problems.push(MapProblem::at(pos, format!(
+                        "duplicate spawn for player {}", idx + 1)));
+                } else {
+                    players[idx as uint] = Some(pos);
+                }
+            }
+        }
+        if players[0].is_none() {
+            problems.push(MapProblem::global("no spawn for player 1".to_string()));
+        }
+        for idx in range(1, MAX_PLAYERS) {
+            if players[idx].is_some() && players[idx - 1].is_none() {
+                problems.push(MapProblem::global(format!(
+                    "spawn for player {} but none for player {}", idx + 1, idx)));
+            }
         }
+        players
+    }
+
+    /// Tanks need room to move and every enemy has to be reachable by
+    /// at least one player.
+    fn check_tank_spawns(&self, spawns: &[SpawnPoint], players: &[Option<(u16, u16)>],
+                         problems: &mut Vec<MapProblem>) {
+        let grid = NavGrid::new(self);
+        let fields : Vec<DistanceField> = players.iter()
+            .filter_map(|pos| pos.map(|pos| grid.distance_field(pos)))
+            .collect();
+
+        for point in spawns.iter() {
+            let is_enemy = match point.spawn {
+                Spawn::Player(_) => false,
+                Spawn::Enemy(_) => true,
+                Spawn::Object(_) => { continue; }
+            };
+            if point.x < 0.0 || point.y < 0.0 ||
+               point.x >= self.width as f32 || point.y >= self.height as f32 {
+                problems.push(MapProblem::global(format!(
+                    "{} spawn outside of the map at {}, {}",
+                    point.spawn.name(), point.x, point.y)));
+                continue;
+            }
+            let pos = tile_of(Vec2::new(point.x, point.y));
+            let (x, y) = pos;
+            let (x, y) = (x as i32, y as i32);
+            let enclosed = !grid.is_passable(x, y) || (
+                !grid.is_passable(x - 1, y) && !grid.is_passable(x + 1, y) &&
+                !grid.is_passable(x, y - 1) && !grid.is_passable(x, y + 1));
+            if enclosed {
+                problems.push(MapProblem::at(pos, format!(
+                    "{} spawn is enclosed by walls", point.spawn.name())));
+            } else if is_enemy && !fields.is_empty() &&
+                      fields.iter().all(|f| f.get(x, y).is_none()) {
+                problems.push(MapProblem::at(pos, format!(
+                    "{} enemy cannot be reached by any player", point.spawn.name())));
+            }
+        }
+    }
+
+    /// the size of a tile in pixels as stored in the map file
+    pub fn tile_pixels(&self) -> (u32, u32) {
+        (self.tile_width, self.tile_height)
     }
 
     #[inline(always)]
@@ -271,64 +1039,259 @@ impl Map {
         self.tiles[(y * self.width + x) as uint]
     }
 
+    /// How the tile at a position is flipped on the terrain layer.
+    pub fn get_orientation(&self, x: u16, y: u16) -> Orientation {
+        match self.layers[self.terrain_layer] {
+            Layer::Tiles(ref layer) => {
+                let (_, orientation) = layer.get((y * self.width + x) as uint);
+                orientation
+            }
+            Layer::Objects(_) => Default::default(),
+        }
+    }
+
+    /// Replaces a tile.  This bumps the revision of the map so that
+    /// derived data like navigation grids know they are stale.  The new
+    /// tile is not flipped.
+    pub fn set_tile(&mut self, x: u16, y: u16, tile: Tile) {
+        let idx = (y * self.width + x) as uint;
+        if self.tiles[idx] != tile {
+            self.tiles[idx] = tile;
+            let gid = meta_tileset(self.tilesets.as_slice()).first_gid +
+                tile.get_gid() - 1;
+            if let Layer::Tiles(ref mut layer) = self.layers[self.terrain_layer] {
+                layer.data[idx] = gid;
+            }
+            self.revision += 1;
+            self.touch_chunks(x, y);
+        }
+    }
+
+    /// Stamps the chunks that show a tile with the current revision.
+    /// The sides of the neighbours depend on the tile too, so their
+    /// chunks are included.
+    fn touch_chunks(&mut self, x: u16, y: u16) {
+        let (chunks_x, _) = chunk_counts(self.width, self.height);
+        let around = [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1)];
+        for &(dx, dy) in around.iter() {
+            let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+            if nx < 0 || ny < 0 || nx >= self.width as i32 || ny >= self.height as i32 {
+                continue;
+            }
+            let idx = (ny / CHUNK_SIZE as i32) * chunks_x as i32 + nx / CHUNK_SIZE as i32;
+            self.chunk_revisions[idx as uint] = self.revision;
+        }
+    }
+
+    /// A counter that goes up with every change to the map.
+    #[inline(always)]
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+
+    /// The revision a chunk of `CHUNK_SIZE` tiles was last changed at.
+    /// Chunks are counted row by row, zero if it never changed.
+    pub fn chunk_revision(&self, idx: uint) -> u32 {
+        self.chunk_revisions[idx]
+    }
+
+    /// Like `get_tile` but accepts coordinates outside of the map in
+    /// which case `Tile::Oob` is returned.
+    pub fn get_tile_checked(&self, x: i32, y: i32) -> Tile {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            Tile::Oob
+        } else {
+            self.get_tile(x as u16, y as u16)
+        }
+    }
+
+    /// The camera looks at the center of the map from above unless the
+    /// map's properties say otherwise.
     pub fn get_camera_view(&self) -> AffineMatrix3<f32> {
-        let left = (self.width() as f32 / 2.0) * TILE_SIZE - TILE_SIZE / 2.0;
-        let top = (self.height() as f32 / 2.0) * TILE_SIZE - TILE_SIZE / 2.0;
+        let camera = &self.properties.camera;
+        let x = camera.x.unwrap_or(self.width() as f32 / 2.0);
+        let y = camera.y.unwrap_or(self.height() as f32 / 2.0);
+        let left = x * TILE_SIZE - TILE_SIZE / 2.0;
+        let top = (self.height() as f32 - y) * TILE_SIZE - TILE_SIZE / 2.0;
+        let height = camera.height.unwrap_or(
+            self.width() as f32 * TILE_SIZE - TILE_SIZE);
         Transform::look_at(
-            &Point3::new(left, left * 2.0, top - 2.0),
+            &Point3::new(left, height, top - 2.0),
             &Point3::new(left, 0.0, top),
             &Vector3::unit_z(),
         )
     }
 
-    pub fn create_mesh(&self, device: &mut gfx::GlDevice,
-                       texture_map: &Texture) -> MapMesh {
+    pub fn create_mesh(&self, device: &mut gfx::GlDevice, atlas: &TextureAtlas,
+                       appearance: &TileAppearance) -> Res<MapMesh> {
+        MapMesh::new(device, atlas, appearance, self)
+    }
+}
+
+/// A square part of the map mesh with its own buffers.
+pub struct MapChunk {
+    /// the first tile of the chunk
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    /// `None` if nothing in the chunk is visible
+    mesh: Option<(gfx::Mesh, gfx::Slice)>,
+    dirty: bool,
+}
+
+impl MapChunk {
+
+    pub fn get_mesh(&self) -> Option<&gfx::Mesh> {
+        self.mesh.as_ref().map(|&(ref mesh, _)| mesh)
+    }
+
+    pub fn get_slice(&self) -> Option<gfx::Slice> {
+        self.mesh.as_ref().map(|&(_, slice)| slice)
+    }
+
+    /// true if the chunk is rebuilt on the next update
+    #[inline(always)]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Builds the mesh data of the chunk without uploading it.  The
+    /// measurements are those of the appearance's images, by cell.
+    /// Returns `None` if there is nothing to draw.
+    fn build(&self, appearance: &TileAppearance, measurements: &[Option<(f32, f32, f32, f32)>],
+             map: &Map) -> Res<Option<(Vec<Vertex>, Indexes)>> {
         let mut builder = MapMeshBuilder::new(
-            device, texture_map, self, TILE_SIZE);
+            measurements, appearance, map, TILE_SIZE,
+            self.x, self.y, self.width, self.height);
         builder.build_mesh();
         builder.finish()
     }
 }
 
-pub struct MapMesh<'a> {
-    map: &'a Map,
-    mesh: gfx::Mesh,
-    slice: gfx::Slice,
+/// The mesh of a map split into chunks of `CHUNK_SIZE` tiles.  When
+/// tiles change only the chunks around them are rebuilt.
+pub struct MapMesh {
+    chunks: Vec<MapChunk>,
+    /// the revision of the map the chunks were last updated for
+    revision: u32,
 }
 
-impl<'a> MapMesh<'a> {
+impl MapMesh {
 
-    pub fn get_mesh(&self) -> &gfx::Mesh {
-        &self.mesh
+    /// A mesh whose chunks are all dirty and have nothing uploaded yet.
+    fn empty(map: &Map) -> MapMesh {
+        let (chunks_x, chunks_y) = chunk_counts(map.width(), map.height());
+        let mut chunks = vec![];
+        for cy in range(0, chunks_y) {
+            for cx in range(0, chunks_x) {
+                let (x, y) = (cx * CHUNK_SIZE, cy * CHUNK_SIZE);
+                chunks.push(MapChunk {
+                    x: x,
+                    y: y,
+                    width: cmp::min(CHUNK_SIZE, map.width() - x),
+                    height: cmp::min(CHUNK_SIZE, map.height() - y),
+                    mesh: None,
+                    dirty: true,
+                });
+            }
+        }
+        MapMesh {
+            chunks: chunks,
+            revision: map.revision(),
+        }
     }
 
-    pub fn get_slice(&self) -> gfx::Slice {
-        self.slice
+    pub fn new(device: &mut gfx::GlDevice, atlas: &TextureAtlas,
+               appearance: &TileAppearance, map: &Map) -> Res<MapMesh> {
+        let mut rv = MapMesh::empty(map);
+        try!(rv.update(device, atlas, appearance, map));
+        Ok(rv)
     }
 
-    pub fn get_map(&self) -> &Map {
-        self.map
+    pub fn chunks(&self) -> &[MapChunk] {
+        self.chunks.as_slice()
+    }
+
+    /// Marks the chunks the map changed in since the last update as
+    /// dirty.
+    fn mark_changes(&mut self, map: &Map) {
+        for (idx, chunk) in self.chunks.iter_mut().enumerate() {
+            if map.chunk_revision(idx) > self.revision {
+                chunk.dirty = true;
+            }
+        }
+        self.revision = map.revision();
+    }
+
+    /// Marks the chunks the map changed in since the last update as
+    /// dirty and rebuilds all dirty chunks.  Returns the indexes of the
+    /// rebuilt chunks so that batches referring to them can be replaced.
+    pub fn update(&mut self, device: &mut gfx::GlDevice, atlas: &TextureAtlas,
+                  appearance: &TileAppearance, map: &Map) -> Res<Vec<uint>> {
+        self.mark_changes(map);
+
+        let measurements = appearance.measure_cells(
+            |name| atlas.get_slice(name).map(|x| x.get_measurements()));
+        let mut rebuilt = vec![];
+        for (idx, chunk) in self.chunks.iter_mut().enumerate() {
+            if !chunk.dirty {
+                continue;
+            }
+            chunk.mesh = match try!(chunk.build(appearance, measurements.as_slice(), map)) {
+                Some((vertices, indexes)) => {
+                    Some(upload_mesh(device, vertices.as_slice(), indexes))
+                }
+                None => None,
+            };
+            chunk.dirty = false;
+            rebuilt.push(idx);
+        }
+        Ok(rebuilt)
     }
 }
 
+/// The top surface of a tile.  Neighbouring surfaces with equal keys
+/// are merged into one quad.
+#[deriving(PartialEq, Copy, Clone)]
+struct TopKey {
+    /// the atlas image the surface is drawn with
+    cell: u16,
+    /// the height of the surface in tiles
+    level: f32,
+    orientation: Orientation,
+}
+
 struct MapMeshBuilder<'a> {
-    device: &'a mut gfx::GlDevice,
     map: &'a Map,
-    texture_map: &'a (Texture + 'a),
+    /// the texture measurements of the appearance's images by cell
+    measurements: &'a [Option<(f32, f32, f32, f32)>],
+    appearance: &'a TileAppearance,
     tile_size: f32,
+    /// the tiles the mesh is built for.  Neighbours outside of them are
+    /// still looked at to hide faces.
+    left: u16,
+    top: u16,
+    width: u16,
+    height: u16,
     cube_maker: CubeMaker,
 }
 
 impl<'a> MapMeshBuilder<'a> {
 
-    pub fn new(device: &'a mut gfx::GlDevice, texture_map: &'a Texture,
-               map: &'a Map, tile_size: f32) -> MapMeshBuilder<'a> {
+    pub fn new(measurements: &'a [Option<(f32, f32, f32, f32)>],
+               appearance: &'a TileAppearance, map: &'a Map, tile_size: f32,
+               left: u16, top: u16, width: u16, height: u16) -> MapMeshBuilder<'a> {
         MapMeshBuilder {
-            device: device,
             map: map,
-            texture_map: texture_map,
+            measurements: measurements,
+            appearance: appearance,
             tile_size: tile_size,
-            cube_maker: CubeMaker::new(),
+            left: left,
+            top: top,
+            width: width,
+            height: height,
+            cube_maker: CubeMaker::new(tile_size),
         }
     }
 
@@ -338,49 +1301,441 @@ impl<'a> MapMeshBuilder<'a> {
          ((self.map.height() - y - 1) as f32) * self.tile_size)
     }
 
-    pub fn add_ground_tile(&mut self, x: u16, y: u16) {
-        let pos = self.get_pos(x, y, 0);
-        let tex = Tile::Ground.get_texture_slice(self.texture_map);
-        self.cube_maker.add_top_side(pos, self.tile_size, &tex);
+    /// the center of a block of tiles at a level
+    fn get_block_pos(&self, x: uint, y: uint, width: uint, height: uint,
+                     z: f32) -> (f32, f32, f32) {
+        let ts = self.tile_size;
+        (((x as f32) + (width as f32 - 1.0) / 2.0) * ts,
+         z * ts,
+         ((self.map.height() as f32) - (y as f32) - (height as f32 + 1.0) / 2.0) * ts)
+    }
+
+    fn get_top_key(&self, x: u16, y: u16) -> TopKey {
+        let tile = self.map.get_tile(x, y);
+        let (tile, level) = if tile.is_ground() {
+            (Tile::Ground, 0.5)
+        } else if tile.height() > 0 {
+            (tile, tile.height() as f32 + 0.5)
+        } else {
+            (tile, 0.5 - tile.recess_depth())
+        };
+        TopKey {
+            cell: self.appearance.top_cell(tile),
+            level: level,
+            orientation: self.map.get_orientation(x, y),
+        }
+    }
+
+    /// Adds the top surfaces of the tiles.  Neighbouring surfaces that
+    /// look the same and are at the same height become one quad.
+    pub fn add_tops(&mut self) {
+        let (w, h) = (self.width as uint, self.height as uint);
+        let mut cells = Vec::with_capacity(w * h);
+        for y in range(self.top, self.top + self.height) {
+            for x in range(self.left, self.left + self.width) {
+                cells.push(Some(self.get_top_key(x, y)));
+            }
+        }
+
+        let ts = self.tile_size;
+        let (left, top) = (self.left as uint, self.top as uint);
+        for rect in merge_cells(w, h, cells.as_slice()).iter() {
+            let key = rect.key;
+            let pos = self.get_block_pos(left + rect.x, top + rect.y,
+                                         rect.width, rect.height, key.level);
+            let tex = unwrap_or!(self.measurements[key.cell as uint], continue);
+            self.cube_maker.add_measured_face(
+                Face::Top, pos, (rect.width as f32 * ts, 0.0, rect.height as f32 * ts),
+                tex, key.orientation);
+        }
+    }
+
+    /// Adds the sides of walls and boxes facing `(dx, dy)`.  Sides that
+    /// touch a neighbour of at least the same height are hidden and are
+    /// left out, the rest is merged along the rows or columns of the map
+    /// and across height levels that are drawn with the same atlas image.
+    pub fn add_sides(&mut self, face: Face, dx: i32, dy: i32) {
+        let (left, top) = (self.left as i32, self.top as i32);
+        let (w, h) = (self.width as i32, self.height as i32);
+        let mut max_height = 0;
+        for y in range(self.top, self.top + self.height) {
+            for x in range(self.left, self.left + self.width) {
+                max_height = cmp::max(max_height, self.map.get_tile(x, y).height() as uint);
+            }
+        }
+        if max_height == 0 {
+            return;
+        }
+
+        // faces facing along x are merged along the columns of the map,
+        // faces facing along y along the rows.
+        let (lines, length) = if dx != 0 { (w, h) } else { (h, w) };
+        let ts = self.tile_size;
+        for line in range(0, lines) {
+            let mut cells = Vec::with_capacity(length as uint * max_height);
+            for level in range(1, max_height + 1) {
+                for along in range(0, length) {
+                    let (x, y) = if dx != 0 {
+                        (left + line, top + along)
+                    } else {
+                        (left + along, top + line)
+                    };
+                    let tile = self.map.get_tile(x as u16, y as u16);
+                    let neighbour = self.map.get_tile_checked(x + dx, y + dy);
+                    cells.push(if tile.height() as uint >= level &&
+                                  (neighbour.height() as uint) < level {
+                        Some(self.appearance.side_cell(tile, level))
+                    } else {
+                        None
+                    });
+                }
+            }
+
+            for rect in merge_cells(length as uint, max_height, cells.as_slice()).iter() {
+                let z = (rect.y + 1) as f32 + (rect.height as f32 - 1.0) / 2.0;
+                let (pos, size) = if dx != 0 {
+                    (self.get_block_pos((left + line) as uint, top as uint + rect.x,
+                                        1, rect.width, z),
+                     (ts, rect.height as f32 * ts, rect.width as f32 * ts))
+                } else {
+                    (self.get_block_pos(left as uint + rect.x, (top + line) as uint,
+                                        rect.width, 1, z),
+                     (rect.width as f32 * ts, rect.height as f32 * ts, ts))
+                };
+                let tex = unwrap_or!(self.measurements[rect.key as uint], continue);
+                self.cube_maker.add_measured_face(face, pos, size, tex, Default::default());
+            }
+        }
     }
 
-    pub fn add_box(&mut self, x: u16, y: u16, height: u8, tile: Tile) {
-        for z in range(1, height + 1) {
-            let pos = self.get_pos(x, y, z as u16);
-            let tex = tile.get_texture_slice(self.texture_map);
-            self.cube_maker.add_left_side(pos, self.tile_size, &tex);
-            self.cube_maker.add_right_side(pos, self.tile_size, &tex);
-            self.cube_maker.add_far_side(pos, self.tile_size, &tex);
-            self.cube_maker.add_near_side(pos, self.tile_size, &tex);
-            if z == height {
-                self.cube_maker.add_top_side(pos, self.tile_size, &tex);
+    /// Adds the walls of a tile whose surface is below the ground where
+    /// the neighbours are higher.  The surface itself is added with the
+    /// other tops.
+    pub fn add_recess_walls(&mut self, x: u16, y: u16, tile: Tile) {
+        let ts = self.tile_size;
+        let depth = tile.recess_depth() * ts;
+        let (px, py, pz) = self.get_pos(x, y, 0);
+        let ground = py + ts / 2.0;
+        let floor = ground - depth;
+        let tex = unwrap_or!(
+            self.measurements[self.appearance.side_cell(tile, 1) as uint], return);
+
+        let sides = [
+            (-1, 0, Face::Right, (px - ts / 2.0, pz), (0.0, ts)),
+            (1, 0, Face::Left, (px + ts / 2.0, pz), (0.0, ts)),
+            (0, -1, Face::Far, (px, pz + ts / 2.0), (ts, 0.0)),
+            (0, 1, Face::Near, (px, pz - ts / 2.0), (ts, 0.0)),
+        ];
+        for &(dx, dy, face, (wx, wz), (sx, sz)) in sides.iter() {
+            let neighbour = self.map.get_tile_checked(x as i32 + dx, y as i32 + dy);
+            let top = ground - neighbour.recess_depth() * ts;
+            if top <= floor {
+                continue;
             }
+            self.cube_maker.add_measured_face(face, (wx, (floor + top) / 2.0, wz),
+                                              (sx, top - floor, sz), tex, Default::default());
         }
     }
 
     pub fn build_mesh(&mut self) {
-        for y in range(0, self.map.height()) {
-            for x in range(0, self.map.width()) {
+        self.add_tops();
+        self.add_sides(Face::Left, -1, 0);
+        self.add_sides(Face::Right, 1, 0);
+        self.add_sides(Face::Near, 0, -1);
+        self.add_sides(Face::Far, 0, 1);
+        for y in range(self.top, self.top + self.height) {
+            for x in range(self.left, self.left + self.width) {
                 let tile = self.map.get_tile(x, y);
-                if tile.is_ground() {
-                    self.add_ground_tile(x, y);
-                } else if tile.height() > 0 {
-                    self.add_box(x, y, tile.height(), tile);
+                if tile.recess_depth() > 0.0 {
+                    self.add_recess_walls(x, y, tile);
                 }
             }
         }
     }
 
-    pub fn finish(self) -> MapMesh<'a> {
-        let (vertex_data, index_data) = self.cube_maker.finish();
-        let mesh = self.device.create_mesh(vertex_data.as_slice());
-        let slice = self.device
-            .create_buffer_static::<u16>(index_data.as_slice())
-            .to_slice(gfx::PrimitiveType::TriangleList);
-        MapMesh {
-            map: self.map,
-            mesh: mesh,
-            slice: slice,
+    /// The vertices and indexes of the mesh.  Returns `None` if there
+    /// is nothing to draw.
+    pub fn finish(self) -> Res<Option<(Vec<Vertex>, Indexes)>> {
+        if self.cube_maker.triangle_count() == 0 {
+            return Ok(None);
+        }
+        let rv = try!(self.cube_maker.finish());
+        Ok(Some(rv))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::TempDir;
+
+    use appearance::TileAppearance;
+    use errors::GameError;
+    use properties::{Properties, PropertyValue};
+    use super::{Map, MapData, MapFormat, MapMesh, MapProblem, Tile, Tileset, Layer,
+                TileLayer, ObjectLayer, MapObject, ObjectKind, TERRAIN_LAYER};
+
+    fn open(path: &str) -> Map {
+        Map::open(&Path::new(path)).unwrap()
+    }
+
+    fn make_object(id: u32, name: &str, type_name: &str, x: f32, y: f32) -> MapObject {
+        MapObject {
+            id: id,
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            kind: ObjectKind::from_object(type_name, name),
+            x: x,
+            y: y,
+            width: 0.5,
+            height: 1.0,
+            visible: true,
+        }
+    }
+
+    /// map001 with objects and properties added, so that saving it has
+    /// more to lose than the tiles
+    fn decorated_map001() -> Map {
+        let map = open("resources/maps/map001.json");
+        let mut properties = Properties::new();
+        properties.insert("name".to_string(), PropertyValue::String("First".to_string()));
+        properties.insert("par_time".to_string(), PropertyValue::Float(90.5));
+        properties.insert("waves".to_string(), PropertyValue::String("basic*2; fast".to_string()));
+        properties.insert("lives_bonus".to_string(), PropertyValue::Int(2));
+        properties.insert("secret".to_string(), PropertyValue::Bool(true));
+        let mut layers = map.layers().to_vec();
+        layers.push(Layer::Objects(ObjectLayer {
+            name: "objects".to_string(),
+            visible: true,
+            objects: vec![
+                make_object(1, "mine", "spawn", 3.0, 4.0),
+                make_object(2, "gate", "trigger", 10.5, 2.0),
+                make_object(3, "", "waypoint", 7.0, 7.5),
+            ],
+        }));
+        let (tile_width, tile_height) = map.tile_pixels();
+        Map::from_map_data(MapData {
+            width: map.width(),
+            height: map.height(),
+            tile_width: tile_width,
+            tile_height: tile_height,
+            tilesets: map.tilesets().to_vec(),
+            layers: layers,
+            properties: properties,
+        }).unwrap()
+    }
+
+    fn assert_same_map(a: &Map, b: &Map) {
+        assert_eq!(a.width(), b.width());
+        assert_eq!(a.height(), b.height());
+        for y in range(0, a.height()) {
+            for x in range(0, a.width()) {
+                assert_eq!(a.get_tile(x, y), b.get_tile(x, y));
+                assert_eq!(a.get_orientation(x, y), b.get_orientation(x, y));
+            }
+        }
+        assert_eq!(a.layers(), b.layers());
+        assert_eq!(a.objects(), b.objects());
+        assert_eq!(a.tilesets(), b.tilesets());
+        assert_eq!(a.properties(), b.properties());
+        assert_eq!(a.tile_pixels(), b.tile_pixels());
+    }
+
+    fn round_trip(map: &Map, name: &str, format: MapFormat) -> Map {
+        let dir = TempDir::new("tanks").unwrap();
+        let path = dir.path().join(name);
+        map.save(&path, format).unwrap();
+        Map::open(&path).unwrap()
+    }
+
+    #[test]
+    fn json_round_trip_keeps_everything() {
+        let map = decorated_map001();
+        assert_same_map(&map, &round_trip(&map, "map001.json", MapFormat::Json));
+    }
+
+    #[test]
+    fn tmx_round_trip_keeps_everything() {
+        let map = decorated_map001();
+        assert_same_map(&map, &round_trip(&map, "map001.tmx", MapFormat::Tmx));
+    }
+
+    #[test]
+    fn plain_map001_round_trips() {
+        let map = open("resources/maps/map001.json");
+        assert_same_map(&map, &round_trip(&map, "map001.json", MapFormat::Json));
+        assert_same_map(&map, &round_trip(&map, "map001.tmx", MapFormat::Tmx));
+    }
+
+    #[test]
+    fn tmx_and_json_load_the_same_map() {
+        let tmx = open("maps/map001.tmx");
+        let json = open("resources/maps/map001.json");
+        assert_eq!(tmx.width(), json.width());
+        assert_eq!(tmx.height(), json.height());
+        for y in range(0, tmx.height()) {
+            for x in range(0, tmx.width()) {
+                assert_eq!(tmx.get_tile(x, y), json.get_tile(x, y));
+                assert_eq!(tmx.get_orientation(x, y), json.get_orientation(x, y));
+            }
+        }
+        assert_eq!(tmx.spawn_points(), json.spawn_points());
+    }
+
+    /// the triangles of the mesh of a map, built chunk by chunk like
+    /// `MapMesh` builds it but without uploading anything
+    fn count_triangles(map: &Map) -> uint {
+        let appearance = TileAppearance::open(
+            &Path::new("resources/config/tiles.json")).unwrap();
+        // the measurements only move texture coordinates around
+        let measurements = appearance.measure_cells(|_| Some((1.0, 1.0, 0.0, 0.0)));
+        MapMesh::empty(map).chunks().iter().fold(0, |sum, chunk| {
+            match chunk.build(&appearance, measurements.as_slice(), map).unwrap() {
+                Some((_, indexes)) => sum + indexes.len() / 3,
+                None => sum,
+            }
+        })
+    }
+
+    #[test]
+    fn flat_ground_is_one_quad() {
+        let map = Map::from_glyphs(&["....", "....", "...."]).unwrap();
+        assert_eq!(count_triangles(&map), 2);
+    }
+
+    #[test]
+    fn map001_mesh_merges_faces() {
+        assert_eq!(count_triangles(&open("resources/maps/map001.json")), 380);
+    }
+
+    /// the indexes of the dirty chunks of a mesh
+    fn dirty_chunks(mesh: &MapMesh) -> Vec<uint> {
+        mesh.chunks().iter().enumerate()
+            .filter(|&(_, chunk)| chunk.is_dirty())
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    #[test]
+    fn tile_changes_dirty_the_chunks_around_them() {
+        // 3x2 chunks, the last column and row are partial
+        let rows = Vec::from_elem(10, "....................");
+        let mut map = Map::from_glyphs(rows.as_slice()).unwrap();
+        let mut mesh = MapMesh::empty(&map);
+        assert_eq!(dirty_chunks(&mesh), vec![0, 1, 2, 3, 4, 5]);
+        for chunk in mesh.chunks.iter_mut() {
+            chunk.dirty = false;
         }
+
+        mesh.mark_changes(&map);
+        assert!(dirty_chunks(&mesh).is_empty());
+
+        // a tile on the border between chunks 0 and 1
+        map.set_tile(7, 2, Tile::Wall1);
+        mesh.mark_changes(&map);
+        assert_eq!(dirty_chunks(&mesh), vec![0, 1]);
+        for chunk in mesh.chunks.iter_mut() {
+            chunk.dirty = false;
+        }
+
+        // setting the same tile again is no change
+        map.set_tile(7, 2, Tile::Wall1);
+        map.set_tile(19, 9, Tile::Wall1);
+        map.set_tile(19, 9, Tile::Ground);
+        mesh.mark_changes(&map);
+        assert_eq!(dirty_chunks(&mesh), vec![5]);
+    }
+
+    #[test]
+    fn tile_changes_use_bounded_memory() {
+        let mut map = Map::from_glyphs(&["....", "...."]).unwrap();
+        let chunks = map.chunk_revisions.len();
+        for idx in range(0u, 1000) {
+            map.set_tile(1, 1, if idx % 2 == 0 { Tile::Wall1 } else { Tile::Ground });
+        }
+        assert_eq!(map.revision(), 1000);
+        assert_eq!(map.chunk_revision(0), 1000);
+        assert_eq!(map.chunk_revisions.len(), chunks);
+    }
+
+    fn problems(rows: &[&str]) -> Vec<MapProblem> {
+        Map::from_glyphs(rows).unwrap().validate()
+    }
+
+    #[test]
+    fn valid_maps_have_no_problems() {
+        assert!(problems(&["11111", "1P.B1", "11111"]).is_empty());
+        assert!(problems(&["  111 ", "111P11", "1T..B1", "111111"]).is_empty());
+    }
+
+    #[test]
+    fn duplicate_player_spawns_are_reported() {
+        let problems = problems(&["111111", "1P..P1", "111111"]);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].pos, Some((4, 1)));
+        assert_eq!(problems[0].message.as_slice(), "duplicate spawn for player 1");
+    }
+
+    #[test]
+    fn missing_player_spawns_are_reported() {
+        let problems = problems(&["11111", "1.Q.1", "11111"]);
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].pos, None);
+        assert_eq!(problems[0].message.as_slice(), "no spawn for player 1");
+        assert_eq!(problems[1].pos, None);
+        assert_eq!(problems[1].message.as_slice(),
+                   "spawn for player 2 but none for player 1");
+    }
+
+    #[test]
+    fn enclosed_spawns_are_reported() {
+        let problems = problems(&["111111", "1P.111", "1111B1", "111111"]);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].pos, Some((4, 2)));
+        assert_eq!(problems[0].message.as_slice(), "basic spawn is enclosed by walls");
+    }
+
+    #[test]
+    fn unreachable_enemies_are_reported() {
+        let problems = problems(&["1111111", "1P.1.B1", "1111111"]);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].pos, Some((5, 1)));
+        assert_eq!(problems[0].message.as_slice(),
+                   "basic enemy cannot be reached by any player");
+    }
+
+    #[test]
+    fn open_borders_are_reported() {
+        // crates can be shot away, they do not close the border
+        let problems = problems(&["11111", "1P.B1", "11.=1"]);
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].pos, Some((2, 2)));
+        assert_eq!(problems[1].pos, Some((3, 2)));
+        assert!(problems.iter().all(|p| p.message.as_slice()
+            .starts_with("map border is open")));
+    }
+
+    #[test]
+    fn unknown_gids_fail_to_load() {
+        let ground = Tile::Ground.get_gid();
+        let err = Map::from_map_data(MapData {
+            width: 3,
+            height: 2,
+            tile_width: 32,
+            tile_height: 32,
+            tilesets: vec![Tileset::meta()],
+            layers: vec![Layer::Tiles(TileLayer {
+                name: TERRAIN_LAYER.to_string(),
+                visible: true,
+                data: vec![ground, ground, ground, ground, 200, ground],
+            })],
+            properties: Properties::new(),
+        }).err().unwrap();
+        assert!(match err {
+            GameError::InvalidMap(msg, pos) => {
+                msg == "Unknown tile gid" && pos == Some((1, 1))
+            }
+            _ => false,
+        });
     }
 }
+
@@ -0,0 +1,397 @@
+use std::cmp;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+
+use errors::{Res, GameError};
+use map::{Map, Tile, Spawn, EnemyType};
+use world::MAX_PLAYERS;
+
+/// how close to a player spawn enemies may be placed, in tiles
+static MIN_ENEMY_DISTANCE : i32 = 5;
+/// how often a random free tile is searched for before giving up
+static PLACEMENT_TRIES : uint = 200;
+
+
+/// How a generated map mirrors itself so that players start on equal
+/// terms in versus mode.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum Symmetry {
+    /// no symmetry at all
+    None,
+    /// the right half mirrors the left half
+    Horizontal,
+    /// the bottom half mirrors the top half
+    Vertical,
+    /// the map looks the same when turned by 180 degrees
+    Rotational,
+}
+
+impl Symmetry {
+
+    /// Every tile that has to look like the tile at `(x, y)`, starting
+    /// with that tile.  Tiles on the axis are their own mirror and only
+    /// show up once.
+    fn mirrors(&self, x: u16, y: u16, width: u16, height: u16) -> Vec<(u16, u16)> {
+        let (mx, my) = (width - 1 - x, height - 1 - y);
+        let mirror = match *self {
+            Symmetry::None => { return vec![(x, y)]; }
+            Symmetry::Horizontal => (mx, y),
+            Symmetry::Vertical => (x, my),
+            Symmetry::Rotational => (mx, my),
+        };
+        if mirror == (x, y) { vec![(x, y)] } else { vec![(x, y), mirror] }
+    }
+
+    /// the part of the map things are placed in before mirroring as
+    /// exclusive upper bounds
+    fn region(&self, width: u16, height: u16) -> (u16, u16) {
+        match *self {
+            Symmetry::None => (width, height),
+            Symmetry::Horizontal | Symmetry::Rotational => ((width + 1) / 2, height),
+            Symmetry::Vertical => (width, (height + 1) / 2),
+        }
+    }
+}
+
+/// What kind of map `generate` makes.
+#[deriving(Clone, Show)]
+pub struct MapGenParams {
+    pub width: u16,
+    pub height: u16,
+    /// the share of the playing field covered by walls and boxes
+    pub wall_density: f32,
+    /// the share of the playing field covered by water, swamps and holes
+    pub fluid_ratio: f32,
+    /// how many enemies of each type are placed
+    pub enemies: Vec<(EnemyType, u32)>,
+    /// how many player spawns are placed
+    pub players: u8,
+    pub symmetry: Symmetry,
+    /// how many layouts are tried until one passes validation
+    pub max_attempts: uint,
+}
+
+impl MapGenParams {
+
+    pub fn new(width: u16, height: u16) -> MapGenParams {
+        MapGenParams {
+            width: width,
+            height: height,
+            wall_density: 0.15,
+            fluid_ratio: 0.05,
+            enemies: vec![(EnemyType::Basic, 3), (EnemyType::Stationary, 2)],
+            players: 1,
+            symmetry: Symmetry::None,
+            max_attempts: 50,
+        }
+    }
+
+    fn check(&self) -> Res<()> {
+        if self.width < 8 || self.height < 8 {
+            return Err(GameError::InvalidConfig("Generated maps need to be at least 8x8"));
+        }
+        if self.players == 0 || self.players as uint > MAX_PLAYERS {
+            return Err(GameError::InvalidConfig("Invalid number of players"));
+        }
+        if self.wall_density < 0.0 || self.fluid_ratio < 0.0 ||
+           self.wall_density + self.fluid_ratio > 0.8 {
+            return Err(GameError::InvalidConfig("Too many walls and fluids"));
+        }
+        Ok(())
+    }
+}
+
+/// A map that is being generated.
+struct Layout<'a> {
+    params: &'a MapGenParams,
+    rng: XorShiftRng,
+    tiles: Vec<Tile>,
+    players: Vec<(u16, u16)>,
+}
+
+impl<'a> Layout<'a> {
+
+    fn new(params: &'a MapGenParams, seed: u64, attempt: uint) -> Layout<'a> {
+        let (w, h) = (params.width, params.height);
+        let mut tiles = Vec::from_elem(w as uint * h as uint, Tile::Ground);
+        for y in range(0, h) {
+            for x in range(0, w) {
+                if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+                    tiles[y as uint * w as uint + x as uint] = Tile::Wall2;
+                }
+            }
+        }
+        Layout {
+            params: params,
+            rng: SeedableRng::from_seed([seed as u32 ^ 0x6c078965,
+                                         (seed >> 32) as u32 ^ 0x2c1b3c6d,
+                                         attempt as u32 + 1, 0x297a2d39]),
+            tiles: tiles,
+            players: vec![],
+        }
+    }
+
+    fn get(&self, x: u16, y: u16) -> Tile {
+        self.tiles[y as uint * self.params.width as uint + x as uint]
+    }
+
+    /// Sets a tile and all of its mirrors.  Spawns that were placed
+    /// already are kept.
+    fn set(&mut self, x: u16, y: u16, tile: Tile) {
+        let (w, h) = (self.params.width, self.params.height);
+        for &(mx, my) in self.params.symmetry.mirrors(x, y, w, h).iter() {
+            let idx = my as uint * w as uint + mx as uint;
+            if !self.tiles[idx].is_spawn() {
+                self.tiles[idx] = tile;
+            }
+        }
+    }
+
+    /// a random tile of the playing field within the unmirrored region
+    fn random_pos(&mut self) -> (u16, u16) {
+        let (w, h) = self.params.symmetry.region(self.params.width, self.params.height);
+        let x = self.rng.gen_range(1, cmp::min(w, self.params.width - 1));
+        let y = self.rng.gen_range(1, cmp::min(h, self.params.height - 1));
+        (x, y)
+    }
+
+    fn is_near_player(&self, x: u16, y: u16, distance: i32) -> bool {
+        self.players.iter().any(|&(px, py)| {
+            (px as i32 - x as i32).abs() <= distance &&
+            (py as i32 - y as i32).abs() <= distance
+        })
+    }
+
+    /// Places straight runs of `tile` in random directions until roughly
+    /// `share` of the playing field is covered.
+    fn scatter(&mut self, share: f32, pick: |&mut XorShiftRng| -> Tile) {
+        let area = (self.params.width as f32 - 2.0) * (self.params.height as f32 - 2.0);
+        let mut budget = (area * share) as int;
+        let mut tries = 0u;
+        while budget > 0 && tries < PLACEMENT_TRIES * 10 {
+            tries += 1;
+            let tile = pick(&mut self.rng);
+            let (mut x, mut y) = self.random_pos();
+            let horizontal = self.rng.gen::<bool>();
+            let length = self.rng.gen_range(1u, 5);
+            for _ in range(0, length) {
+                if x == 0 || y == 0 || x >= self.params.width - 1 ||
+                   y >= self.params.height - 1 || self.get(x, y) != Tile::Ground {
+                    break;
+                }
+                self.set(x, y, tile);
+                budget -= self.params.symmetry.mirrors(
+                    x, y, self.params.width, self.params.height).len() as int;
+                if horizontal { x += 1; } else { y += 1; }
+            }
+        }
+    }
+
+    /// Puts a player spawn down and clears the tiles around it.  The
+    /// clearing is mirrored to keep the map symmetric, the spawn is not,
+    /// mirrored maps place the other player explicitly.
+    fn place_player(&mut self, x: u16, y: u16, tile: Tile) {
+        let (w, h) = (self.params.width as i32, self.params.height as i32);
+        for dy in range(-1i32, 2) {
+            for dx in range(-1i32, 2) {
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if nx > 0 && ny > 0 && nx < w - 1 && ny < h - 1 {
+                    self.set(nx as u16, ny as u16, Tile::Ground);
+                }
+            }
+        }
+        self.tiles[(y as i32 * w + x as i32) as uint] = tile;
+        self.players.push((x, y));
+    }
+
+    /// Finds a ground tile for a spawn and places it.  Players get the
+    /// area around them cleared so they can always move.  Returns where
+    /// the spawn went.
+    fn place(&mut self, spawn: Spawn) -> Option<(u16, u16)> {
+        let tile = unwrap_or!(Tile::from_spawn(spawn), return None);
+        for _ in range(0, PLACEMENT_TRIES) {
+            let (x, y) = self.random_pos();
+            if self.get(x, y) != Tile::Ground {
+                continue;
+            }
+            match spawn {
+                Spawn::Player(_) => {
+                    if self.is_near_player(x, y, MIN_ENEMY_DISTANCE) {
+                        continue;
+                    }
+                    self.place_player(x, y, tile);
+                }
+                _ => {
+                    if self.is_near_player(x, y, MIN_ENEMY_DISTANCE) {
+                        continue;
+                    }
+                    self.set(x, y, tile);
+                }
+            }
+            return Some((x, y));
+        }
+        None
+    }
+
+    /// Places the player spawns.  On symmetric maps every other player
+    /// starts on the mirror image of the previous one.
+    fn place_players(&mut self) -> bool {
+        let (w, h) = (self.params.width, self.params.height);
+        let mut idx = 0u8;
+        while idx < self.params.players {
+            if self.place(Spawn::Player(idx)).is_none() {
+                return false;
+            }
+            idx += 1;
+            if self.params.symmetry == Symmetry::None || idx >= self.params.players {
+                continue;
+            }
+            let (x, y) = self.players[self.players.len() - 1];
+            let mirrors = self.params.symmetry.mirrors(x, y, w, h);
+            let (mx, my) = mirrors[mirrors.len() - 1];
+            if (mx, my) == (x, y) {
+                continue;
+            }
+            self.place_player(mx, my, Tile::from_spawn(Spawn::Player(idx)).unwrap());
+            idx += 1;
+        }
+        true
+    }
+
+    /// Places the enemies.  Mirroring places an enemy and its mirror
+    /// image together, so symmetric maps may get one enemy more per type.
+    /// Enemies on the axis have no mirror image and count once.
+    fn place_enemies(&mut self) -> bool {
+        let (w, h) = (self.params.width, self.params.height);
+        for &(ty, count) in self.params.enemies.clone().iter() {
+            let mut placed = 0;
+            while placed < count {
+                let (x, y) = unwrap_or!(self.place(Spawn::Enemy(ty)), return false);
+                placed += self.params.symmetry.mirrors(x, y, w, h).len() as u32;
+            }
+        }
+        true
+    }
+
+    fn finish(mut self) -> Option<Map> {
+        let fluids = [(Tile::Water, 5u), (Tile::Swamp, 3), (Tile::Hole, 2)];
+        let walls = [Tile::Wall1, Tile::Wall2, Tile::Wall3, Tile::Box1, Tile::Box2];
+
+        let (wall_density, fluid_ratio) = (self.params.wall_density, self.params.fluid_ratio);
+        self.scatter(wall_density, |rng| walls[rng.gen_range(0, walls.len())]);
+        self.scatter(fluid_ratio, |rng| {
+            let mut pick = rng.gen_range(0u, 10);
+            let mut rv = Tile::Water;
+            for &(tile, weight) in fluids.iter() {
+                if pick < weight {
+                    rv = tile;
+                    break;
+                }
+                pick -= weight;
+            }
+            rv
+        });
+        if !self.place_players() || !self.place_enemies() {
+            return None;
+        }
+        Map::new(self.params.width, self.params.height, self.tiles).ok()
+    }
+}
+
+/// Generates a map from a seed.  Layouts are generated until one passes
+/// `Map::validate`, so the result always has sane spawns and reachable
+/// enemies.  The same seed and parameters always give the same map.
+pub fn generate(seed: u64, params: &MapGenParams) -> Res<Map> {
+    try!(params.check());
+    for attempt in range(0, params.max_attempts) {
+        let map = unwrap_or!(Layout::new(params, seed, attempt).finish(), continue);
+        if map.validate().is_empty() {
+            return Ok(map);
+        }
+    }
+    Err(GameError::InvalidMap("Could not generate a valid map", None))
+}
+
+#[cfg(test)]
+mod tests {
+    use map::{Map, Tile, EnemyType};
+    use super::{generate, MapGenParams, Symmetry};
+
+    fn tiles(map: &Map) -> Vec<Tile> {
+        let mut rv = vec![];
+        for y in range(0, map.height()) {
+            for x in range(0, map.width()) {
+                rv.push(map.get_tile(x, y));
+            }
+        }
+        rv
+    }
+
+    fn count(map: &Map, tile: Tile) -> uint {
+        tiles(map).iter().filter(|&&x| x == tile).count()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_map() {
+        let params = MapGenParams::new(24, 16);
+        let a = generate(1234, &params).unwrap();
+        let b = generate(1234, &params).unwrap();
+        assert_eq!(tiles(&a), tiles(&b));
+    }
+
+    #[test]
+    fn generated_maps_are_valid() {
+        for &symmetry in [Symmetry::None, Symmetry::Horizontal,
+                          Symmetry::Vertical, Symmetry::Rotational].iter() {
+            let mut params = MapGenParams::new(21, 15);
+            params.symmetry = symmetry;
+            params.players = 2;
+            for seed in range(0, 5u64) {
+                let map = generate(seed, &params).unwrap();
+                assert!(map.validate().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn symmetric_maps_mirror_everything_but_player_ids() {
+        let mut params = MapGenParams::new(21, 15);
+        params.symmetry = Symmetry::Rotational;
+        params.players = 2;
+        for seed in range(0, 5u64) {
+            let map = generate(seed, &params).unwrap();
+            let (w, h) = (map.width(), map.height());
+            for y in range(0, h) {
+                for x in range(0, w) {
+                    let (a, b) = (map.get_tile(x, y), map.get_tile(w - 1 - x, h - 1 - y));
+                    let players = a == Tile::Player1Spawn || a == Tile::Player2Spawn;
+                    if players {
+                        assert!(b == Tile::Player1Spawn || b == Tile::Player2Spawn);
+                    } else {
+                        assert_eq!(a, b);
+                    }
+                }
+            }
+            assert_eq!(count(&map, Tile::Player1Spawn), 1);
+            assert_eq!(count(&map, Tile::Player2Spawn), 1);
+        }
+    }
+
+    #[test]
+    fn enemies_are_counted_once_each() {
+        let mut params = MapGenParams::new(21, 15);
+        params.enemies = vec![(EnemyType::Basic, 3), (EnemyType::Fast, 1)];
+        let map = generate(7, &params).unwrap();
+        assert_eq!(count(&map, Tile::BasicEnemy), 3);
+        assert_eq!(count(&map, Tile::FastEnemy), 1);
+
+        params.symmetry = Symmetry::Horizontal;
+        params.players = 2;
+        for seed in range(0, 5u64) {
+            let map = generate(seed, &params).unwrap();
+            let basic = count(&map, Tile::BasicEnemy);
+            assert!(basic == 3 || basic == 4);
+            let fast = count(&map, Tile::FastEnemy);
+            assert!(fast == 1 || fast == 2);
+        }
+    }
+}
@@ -1,12 +1,24 @@
+use std::default::Default;
+use std::{u16, u32};
+
+use gfx;
+use gfx::{Device, DeviceHelper, ToSlice};
+
+use errors::{Res, GameError};
+
+use map::Orientation;
 use texture::Texture;
 
 
-static CUBE_INDEXES : [uint, ..6] = [0, 1, 2, 0, 2, 3];
+/// the two triangles of a quad made of four vertices
+static QUAD_INDEXES : [u32, ..6] = [0, 1, 2, 0, 2, 3];
 
 struct CubeSide {
     pos: [[f32, ..3], ..4],
     normal: [f32, ..3],
     tex_coord: [[f32, ..2], ..4],
+    /// the axes of the box size the texture's u and v run along
+    tex_axes: (uint, uint),
 }
 
 static CUBE_SIDE_NEAR : CubeSide = CubeSide {
@@ -22,7 +34,8 @@ static CUBE_SIDE_NEAR : CubeSide = CubeSide {
         [1.0, 1.0],
         [1.0, 0.0],
         [0.0, 0.0],
-    ]
+    ],
+    tex_axes: (0, 1),
 };
 
 static CUBE_SIDE_FAR : CubeSide = CubeSide {
@@ -38,7 +51,8 @@ static CUBE_SIDE_FAR : CubeSide = CubeSide {
         [1.0, 0.0],
         [0.0, 0.0],
         [0.0, 1.0],
-    ]
+    ],
+    tex_axes: (0, 1),
 };
 
 static CUBE_SIDE_TOP : CubeSide = CubeSide {
@@ -54,7 +68,8 @@ static CUBE_SIDE_TOP : CubeSide = CubeSide {
         [1.0, 0.0],
         [0.0, 0.0],
         [0.0, 1.0],
-    ]
+    ],
+    tex_axes: (0, 2),
 };
 
 static CUBE_SIDE_BOTTOM : CubeSide = CubeSide {
@@ -70,7 +85,8 @@ static CUBE_SIDE_BOTTOM : CubeSide = CubeSide {
         [1.0, 1.0],
         [1.0, 0.0],
         [0.0, 0.0],
-    ]
+    ],
+    tex_axes: (0, 2),
 };
 
 static CUBE_SIDE_LEFT : CubeSide = CubeSide {
@@ -86,7 +102,8 @@ static CUBE_SIDE_LEFT : CubeSide = CubeSide {
         [1.0, 1.0],
         [1.0, 0.0],
         [0.0, 0.0],
-    ]
+    ],
+    tex_axes: (2, 1),
 };
 
 static CUBE_SIDE_RIGHT : CubeSide = CubeSide {
@@ -102,30 +119,85 @@ static CUBE_SIDE_RIGHT : CubeSide = CubeSide {
         [1.0, 0.0],
         [0.0, 0.0],
         [0.0, 1.0],
-    ]
+    ],
+    tex_axes: (2, 1),
 };
 
 
+/// The six sides of a cube.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum Face {
+    Near,
+    Far,
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+impl Face {
+
+    fn get_side(&self) -> &'static CubeSide {
+        match *self {
+            Face::Near => &CUBE_SIDE_NEAR,
+            Face::Far => &CUBE_SIDE_FAR,
+            Face::Left => &CUBE_SIDE_LEFT,
+            Face::Right => &CUBE_SIDE_RIGHT,
+            Face::Top => &CUBE_SIDE_TOP,
+            Face::Bottom => &CUBE_SIDE_BOTTOM,
+        }
+    }
+}
+
+/// A vertex of a textured box.  Texture coordinates count tiles of the
+/// texture, the shader wraps them into the atlas rectangle given by
+/// `tex_rect` (offset and size) so merged faces repeat their texture.
 #[vertex_format]
 pub struct Vertex {
     #[name = "a_Pos"]
     pub pos: [f32, ..3],
     #[name = "a_TexCoord"]
     pub tex_coord: [f32, ..2],
+    #[name = "a_TexRect"]
+    pub tex_rect: [f32, ..4],
     #[name = "a_Normal"]
     pub normal: [f32, ..3],
 }
 
+/// The index data of a mesh.  Small meshes use 16 bit indices, larger
+/// ones switch to 32 bit.
+pub enum Indexes {
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
+
+impl Indexes {
+
+    pub fn len(&self) -> uint {
+        match *self {
+            Indexes::U16(ref x) => x.len(),
+            Indexes::U32(ref x) => x.len(),
+        }
+    }
+}
+
 pub struct CubeMaker {
     vertices: Vec<Vertex>,
-    indexes: Vec<u16>,
+    indexes: Vec<u32>,
+    tile_size: f32,
+    overflow: bool,
 }
 
 impl CubeMaker {
-    pub fn new() -> CubeMaker {
+
+    /// Creates a cube maker.  A face repeats its texture once for every
+    /// `tile_size` it is large.
+    pub fn new(tile_size: f32) -> CubeMaker {
         CubeMaker {
             vertices: vec![],
             indexes: vec![],
+            tile_size: tile_size,
+            overflow: false,
         }
     }
 
@@ -168,25 +240,234 @@ impl CubeMaker {
         self.add_side(&CUBE_SIDE_BOTTOM, pos, size, tex)
     }
 
-    pub fn finish(self) -> (Vec<Vertex>, Vec<u16>) {
-        (self.vertices, self.indexes)
+    /// Adds one side of a box that is `size` large along each axis.  A
+    /// size of zero along the axis of the face's normal gives a plain
+    /// quad at `pos`.
+    pub fn add_face(&mut self, face: Face, pos: (f32, f32, f32),
+                    size: (f32, f32, f32), tex: &Texture) {
+        self.add_box_side(face.get_side(), pos, size, tex.get_measurements(),
+                          Default::default())
+    }
+
+    /// Like `add_face` but flips the texture the way a tile with the
+    /// given orientation is flipped in the map editor.
+    pub fn add_face_oriented(&mut self, face: Face, pos: (f32, f32, f32),
+                             size: (f32, f32, f32), tex: &Texture,
+                             orientation: Orientation) {
+        self.add_box_side(face.get_side(), pos, size, tex.get_measurements(), orientation)
+    }
+
+    /// Like `add_face_oriented` but takes the measurements of the
+    /// texture (see `Texture::get_measurements`) instead of the texture
+    /// itself, so meshes can be made without a GPU.
+    pub fn add_measured_face(&mut self, face: Face, pos: (f32, f32, f32),
+                             size: (f32, f32, f32), measurements: (f32, f32, f32, f32),
+                             orientation: Orientation) {
+        self.add_box_side(face.get_side(), pos, size, measurements, orientation)
+    }
+
+    /// the number of triangles added so far
+    pub fn triangle_count(&self) -> uint {
+        self.indexes.len() / 3
+    }
+
+    /// Returns the vertices and the indexes of the triangles.  The index
+    /// type is the smallest one that can address every vertex.  Fails if
+    /// there are more vertices than 32 bit indices can address.
+    pub fn finish(self) -> Res<(Vec<Vertex>, Indexes)> {
+        if self.overflow {
+            return Err(GameError::MeshError("Too many vertices for 32 bit indices"));
+        }
+        let indexes = if self.vertices.len() <= u16::MAX as uint + 1 {
+            Indexes::U16(self.indexes.iter().map(|&x| x as u16).collect())
+        } else {
+            Indexes::U32(self.indexes)
+        };
+        Ok((self.vertices, indexes))
     }
 
     fn add_side(&mut self, cs: &CubeSide, pos: (f32, f32, f32),
                 size: f32, tex: &Texture) {
-        let halfsize = size / 2.0;
+        self.add_box_side(cs, pos, (size, size, size), tex.get_measurements(),
+                          Default::default())
+    }
+
+    fn add_box_side(&mut self, cs: &CubeSide, pos: (f32, f32, f32),
+                    size: (f32, f32, f32), measurements: (f32, f32, f32, f32),
+                    orientation: Orientation) {
+        let (size_x, size_y, size_z) = size;
+        let (half_x, half_y, half_z) = (size_x / 2.0, size_y / 2.0, size_z / 2.0);
         let (x, y, z) = pos;
-        let (fac_x, fac_y, off_x, off_y) = tex.get_measurements();
+        let (fac_x, fac_y, off_x, off_y) = measurements;
 
-        for &i in CUBE_INDEXES.iter() {
+        let sizes = [size_x, size_y, size_z];
+        let (axis_u, axis_v) = cs.tex_axes;
+        let (repeat_u, repeat_v) = (sizes[axis_u] / self.tile_size,
+                                    sizes[axis_v] / self.tile_size);
+        // a diagonal flip swaps the axes the texture runs along
+        let (repeat_u, repeat_v) = if orientation.flip_diagonally {
+            (repeat_v, repeat_u)
+        } else {
+            (repeat_u, repeat_v)
+        };
+
+        // the vertices of a quad are shared by its two triangles
+        let base = self.vertices.len();
+        if base + 4 > u32::MAX as uint {
+            self.overflow = true;
+            return;
+        }
+        for &i in QUAD_INDEXES.iter() {
+            self.indexes.push(base as u32 + i);
+        }
+
+        for i in range(0, 4) {
             let [cx, cy, cz] = cs.pos[i];
             let [tx, ty] = cs.tex_coord[i];
-            self.indexes.push(self.vertices.len() as u16);
+            let (tx, ty) = orientation.apply(tx, ty);
             self.vertices.push(Vertex {
-                pos: [x + cx * halfsize, y + cy * halfsize, z + cz * halfsize],
-                tex_coord: [tx * fac_x + off_x, ty * fac_y + off_y],
+                pos: [x + cx * half_x, y + cy * half_y, z + cz * half_z],
+                tex_coord: [tx * repeat_u, ty * repeat_v],
+                tex_rect: [off_x, off_y, fac_x, fac_y],
                 normal: cs.normal,
             });
         }
     }
 }
+
+/// Uploads the vertices and indexes of a mesh.
+pub fn upload_mesh(device: &mut gfx::GlDevice, vertices: &[Vertex],
+                   indexes: Indexes) -> (gfx::Mesh, gfx::Slice) {
+    let mesh = device.create_mesh(vertices);
+    let slice = match indexes {
+        Indexes::U16(data) => device
+            .create_buffer_static::<u16>(data.as_slice())
+            .to_slice(gfx::PrimitiveType::TriangleList),
+        Indexes::U32(data) => device
+            .create_buffer_static::<u32>(data.as_slice())
+            .to_slice(gfx::PrimitiveType::TriangleList),
+    };
+    (mesh, slice)
+}
+
+/// A rectangle of equal cells found by `merge_cells`.
+#[deriving(Copy, Clone, Show)]
+pub struct MergedRect<T> {
+    pub x: uint,
+    pub y: uint,
+    pub width: uint,
+    pub height: uint,
+    pub key: T,
+}
+
+fn cell_fits<T: PartialEq>(cells: &[Option<T>], done: &[bool], idx: uint, key: T) -> bool {
+    !done[idx] && cells[idx] == Some(key)
+}
+
+/// Greedily merges a grid of cells into as few rectangles as possible.
+/// Cells are row-major, `None` cells are left out and only cells with
+/// equal keys are merged.
+pub fn merge_cells<T: PartialEq + Copy>(width: uint, height: uint,
+                                        cells: &[Option<T>]) -> Vec<MergedRect<T>> {
+    let mut done = Vec::from_elem(cells.len(), false);
+    let mut rv = vec![];
+    for y in range(0, height) {
+        for x in range(0, width) {
+            let idx = y * width + x;
+            if done[idx] {
+                continue;
+            }
+            let key = unwrap_or!(cells[idx], continue);
+
+            let mut w = 1;
+            while x + w < width && cell_fits(cells, done.as_slice(), idx + w, key) {
+                w += 1;
+            }
+            let mut h = 1;
+            while y + h < height && range(x, x + w).all(|cx| {
+                cell_fits(cells, done.as_slice(), (y + h) * width + cx, key)
+            }) {
+                h += 1;
+            }
+
+            for cy in range(y, y + h) {
+                for cx in range(x, x + w) {
+                    done[cy * width + cx] = true;
+                }
+            }
+            rv.push(MergedRect { x: x, y: y, width: w, height: h, key: key });
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_cells;
+
+    /// the rects of `merge_cells` as `(x, y, width, height, key)`
+    fn merge(width: uint, height: uint, cells: &[Option<char>])
+             -> Vec<(uint, uint, uint, uint, char)> {
+        merge_cells(width, height, cells).iter()
+            .map(|r| (r.x, r.y, r.width, r.height, r.key))
+            .collect()
+    }
+
+    /// cells from rows of characters, `.` is `None`
+    fn cells(rows: &[&str]) -> Vec<Option<char>> {
+        let mut rv = vec![];
+        for row in rows.iter() {
+            for c in row.chars() {
+                rv.push(if c == '.' { None } else { Some(c) });
+            }
+        }
+        rv
+    }
+
+    #[test]
+    fn uniform_grid_is_one_rect() {
+        let grid = cells(&["aaa", "aaa"]);
+        assert_eq!(merge(3, 2, grid.as_slice()), vec![(0, 0, 3, 2, 'a')]);
+    }
+
+    #[test]
+    fn l_shape_is_two_rects() {
+        let grid = cells(&["a..", "a..", "aaa"]);
+        assert_eq!(merge(3, 3, grid.as_slice()),
+                   vec![(0, 0, 1, 3, 'a'), (1, 2, 2, 1, 'a')]);
+    }
+
+    #[test]
+    fn only_equal_keys_merge() {
+        let grid = cells(&["aab", "aab", "bbb"]);
+        assert_eq!(merge(3, 3, grid.as_slice()),
+                   vec![(0, 0, 2, 2, 'a'), (2, 0, 1, 3, 'b'), (0, 2, 2, 1, 'b')]);
+    }
+
+    #[test]
+    fn holes_are_left_out() {
+        let grid = cells(&["a.a", "...", "a.a"]);
+        assert_eq!(merge(3, 3, grid.as_slice()),
+                   vec![(0, 0, 1, 1, 'a'), (2, 0, 1, 1, 'a'),
+                        (0, 2, 1, 1, 'a'), (2, 2, 1, 1, 'a')]);
+        let empty : Vec<Option<char>> = Vec::from_elem(4, None);
+        assert!(merge(2, 2, empty.as_slice()).is_empty());
+    }
+
+    #[test]
+    fn rects_cover_every_cell_once() {
+        let grid = cells(&["ab.ab", "abbab", "..bbb", "aaa.b"]);
+        let mut covered = Vec::from_elem(grid.len(), 0u);
+        for &(x, y, w, h, key) in merge(5, 4, grid.as_slice()).iter() {
+            for cy in range(y, y + h) {
+                for cx in range(x, x + w) {
+                    assert_eq!(grid[cy * 5 + cx], Some(key));
+                    covered[cy * 5 + cx] += 1;
+                }
+            }
+        }
+        for (cell, &count) in grid.iter().zip(covered.iter()) {
+            assert_eq!(count, if cell.is_some() { 1 } else { 0 });
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use geom::Vec2;
+
+
+/// The tuning values of mines.  These are part of the enemy config next
+/// to the bullets.
+#[deriving(Decodable, Copy, Clone, Show)]
+pub struct MineConfig {
+    /// seconds until a freshly laid mine reacts to tanks
+    pub arm_delay: f32,
+    /// how close a tank has to get to an armed mine to set it off
+    pub trigger_radius: f32,
+    /// everything within this distance of an exploding mine is destroyed
+    pub blast_radius: f32,
+    /// how many mines a player may have on the field at the same time
+    pub max_per_player: uint,
+}
+
+#[deriving(Clone, Show)]
+pub struct Mine {
+    pub id: uint,
+    /// the tank that laid the mine, `None` for mines placed by the map
+    pub owner: Option<uint>,
+    pub pos: Vec2,
+    /// how close a tank has to get to set the mine off
+    pub trigger_radius: f32,
+    /// seconds left until the mine is armed
+    pub arm_timer: f32,
+    /// set once the mine was triggered, it explodes in the next step
+    pub triggered: bool,
+    pub alive: bool,
+}
+
+impl Mine {
+
+    pub fn new(id: uint, owner: Option<uint>, pos: Vec2,
+               config: &MineConfig) -> Mine {
+        Mine {
+            id: id,
+            owner: owner,
+            pos: pos,
+            trigger_radius: config.trigger_radius,
+            arm_timer: config.arm_delay,
+            triggered: false,
+            alive: true,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_armed(&self) -> bool {
+        self.arm_timer <= 0.0
+    }
+
+    /// true if a tank at `pos` with `radius` is close enough to set off
+    /// the mine.  Mines ignore tanks until they are armed.
+    pub fn is_triggered_by(&self, pos: Vec2, radius: f32) -> bool {
+        self.is_armed() && self.pos.distance(pos) < self.trigger_radius + radius
+    }
+}
+
+/// An explosion that happened during the last step.
+#[deriving(Copy, Clone, Show)]
+pub struct Explosion {
+    pub pos: Vec2,
+    pub radius: f32,
+}
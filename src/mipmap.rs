@@ -0,0 +1,216 @@
+use std::cmp;
+
+use image::{DynamicImage, GenericImage, ImageBuf, Pixel, Rgba};
+
+use atlas::AtlasRect;
+use texture::RgbaImage;
+
+
+/// how many levels a full mip chain of an image has, down to 1x1
+pub fn mip_count(width: u32, height: u32) -> u8 {
+    let mut size = cmp::max(width, height);
+    let mut rv = 1;
+    while size > 1 {
+        size /= 2;
+        rv += 1;
+    }
+    rv
+}
+
+/// the size of a level of the mip chain of an image
+fn level_size(size: u32) -> u32 {
+    cmp::max(size / 2, 1)
+}
+
+/// Averages the pixels of a block of the source image.  Coordinates
+/// outside of the bounds `(left, top, right, bottom)` are clamped into
+/// them, so pixels are never taken from outside of the bounds.
+fn average(src: &RgbaImage, x: u32, y: u32, bounds: (u32, u32, u32, u32)) -> Rgba<u8> {
+    let (left, top, right, bottom) = bounds;
+    let mut sum = [0u32, ..4];
+    for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+        let sx = cmp::min(cmp::max(x * 2 + dx, left), right - 1);
+        let sy = cmp::min(cmp::max(y * 2 + dy, top), bottom - 1);
+        let (r, g, b, a) = src.get_pixel(sx, sy).channels4();
+        sum[0] += r as u32;
+        sum[1] += g as u32;
+        sum[2] += b as u32;
+        sum[3] += a as u32;
+    }
+    Pixel::from_channels(((sum[0] + 2) / 4) as u8, ((sum[1] + 2) / 4) as u8,
+                         ((sum[2] + 2) / 4) as u8, ((sum[3] + 2) / 4) as u8)
+}
+
+/// Halves an image by averaging blocks of 2x2 pixels.  Odd edges repeat
+/// their last row or column.
+pub fn downsample(src: &RgbaImage) -> RgbaImage {
+    let (width, height) = src.dimensions();
+    let (dst_width, dst_height) = (level_size(width), level_size(height));
+    let mut rv : RgbaImage = ImageBuf::new(dst_width, dst_height);
+    for y in range(0, dst_height) {
+        for x in range(0, dst_width) {
+            rv.put_pixel(x, y, average(src, x, y, (0, 0, width, height)));
+        }
+    }
+    rv
+}
+
+/// Generates the mip chain of an image.  The first level is the image
+/// itself, at most `levels` levels are made.
+pub fn generate_mips(image: &DynamicImage, levels: u8) -> Vec<RgbaImage> {
+    let mut rv = vec![image.to_rgba()];
+    while rv.len() < levels as uint {
+        let (width, height) = rv[rv.len() - 1].dimensions();
+        if width == 1 && height == 1 {
+            break;
+        }
+        let next = downsample(&rv[rv.len() - 1]);
+        rv.push(next);
+    }
+    rv
+}
+
+/// a rect of an atlas at a mip level as `(left, top, right, bottom)`
+fn scale_rect(rect: &AtlasRect, level: uint) -> (u32, u32, u32, u32) {
+    let scale = 1 << level;
+    let (left, top) = (rect.x / scale, rect.y / scale);
+    let right = cmp::max((rect.x + rect.width + scale - 1) / scale, left + 1);
+    let bottom = cmp::max((rect.y + rect.height + scale - 1) / scale, top + 1);
+    (left, top, right, bottom)
+}
+
+/// Generates the mip chain of a texture atlas.  Unlike `generate_mips`
+/// the pixels of every packed image are only averaged with pixels of
+/// the same image, and the shrinking padding around them is extruded
+/// again on every level.  This keeps slices from bleeding into their
+/// neighbours as long as the padding lasts, so `levels` should not go
+/// beyond what the padding of the atlas supports.
+pub fn generate_atlas_mips(image: &DynamicImage, rects: &[AtlasRect], padding: u32,
+                           levels: u8) -> Vec<RgbaImage> {
+    let mut rv = vec![image.to_rgba()];
+    while rv.len() < levels as uint {
+        let level = rv.len();
+        let (width, height) = rv[level - 1].dimensions();
+        if width == 1 && height == 1 {
+            break;
+        }
+        let mut next = downsample(&rv[level - 1]);
+        let (next_width, next_height) = next.dimensions();
+        let level_padding = padding >> level;
+
+        for rect in rects.iter() {
+            let src_bounds = scale_rect(rect, level - 1);
+            let (left, top, right, bottom) = scale_rect(rect, level);
+            for y in range(top, cmp::min(bottom, next_height)) {
+                for x in range(left, cmp::min(right, next_width)) {
+                    next.put_pixel(x, y, average(&rv[level - 1], x, y, src_bounds));
+                }
+            }
+
+            let pad_left = if left > level_padding { left - level_padding } else { 0 };
+            let pad_top = if top > level_padding { top - level_padding } else { 0 };
+            let pad_right = cmp::min(right + level_padding, next_width);
+            let pad_bottom = cmp::min(bottom + level_padding, next_height);
+            for y in range(pad_top, pad_bottom) {
+                for x in range(pad_left, pad_right) {
+                    if x >= left && x < right && y >= top && y < bottom {
+                        continue;
+                    }
+                    let sx = cmp::min(cmp::max(x, left), right - 1);
+                    let sy = cmp::min(cmp::max(y, top), bottom - 1);
+                    let pixel = next.get_pixel(sx, sy);
+                    next.put_pixel(x, y, pixel);
+                }
+            }
+        }
+        rv.push(next);
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImage, ImageBuf, Pixel};
+
+    use atlas::PackedAtlas;
+    use testutils::{solid, pixel};
+    use texture::RgbaImage;
+    use super::{mip_count, downsample, generate_mips, scale_rect};
+
+    #[test]
+    fn mip_count_goes_down_to_one_pixel() {
+        assert_eq!(mip_count(1, 1), 1);
+        assert_eq!(mip_count(256, 256), 9);
+        assert_eq!(mip_count(256, 64), 9);
+        assert_eq!(mip_count(1, 7), 3);
+        assert_eq!(mip_count(5, 3), 3);
+        assert_eq!(mip_count(1000, 1), 10);
+    }
+
+    #[test]
+    fn downsample_averages_blocks() {
+        let mut checkers : RgbaImage = ImageBuf::new(2, 2);
+        for &(x, y) in [(0, 0), (1, 1)].iter() {
+            checkers.put_pixel(x, y, Pixel::from_channels(255, 255, 255, 255));
+        }
+        for &(x, y) in [(1, 0), (0, 1)].iter() {
+            checkers.put_pixel(x, y, Pixel::from_channels(0, 0, 0, 255));
+        }
+        let half = downsample(&checkers);
+        assert_eq!(half.dimensions(), (1, 1));
+        assert_eq!(pixel(&half, 0, 0), (128, 128, 128, 255));
+
+        // blocks start at even pixels, an odd last column is left out
+        let mut stripes = solid(3, 2, (200, 0, 0));
+        stripes.put_pixel(2, 0, Pixel::from_channels(0, 0, 200, 255));
+        stripes.put_pixel(2, 1, Pixel::from_channels(0, 0, 200, 255));
+        let half = downsample(&stripes);
+        assert_eq!(half.dimensions(), (1, 1));
+        assert_eq!(pixel(&half, 0, 0), (200, 0, 0, 255));
+    }
+
+    #[test]
+    fn mip_chain_halves_down_to_one_pixel() {
+        let image = DynamicImage::ImageRgba8(solid(8, 3, (10, 20, 30)));
+        let sizes : Vec<(u32, u32)> = generate_mips(&image, 10).iter()
+            .map(|x| x.dimensions()).collect();
+        assert_eq!(sizes, vec![(8, 3), (4, 1), (2, 1), (1, 1)]);
+        assert_eq!(generate_mips(&image, 2).len(), 2);
+        assert_eq!(generate_mips(&image, 1).len(), 1);
+        for level in generate_mips(&image, 10).iter() {
+            assert_eq!(pixel(level, 0, 0), (10, 20, 30, 255));
+        }
+    }
+
+    #[test]
+    fn atlas_mips_do_not_bleed() {
+        // odd sizes so the rects do not line up with the pixels of the
+        // smaller levels
+        let packed = PackedAtlas::pack(vec![
+            ("red".to_string(), solid(13, 9, (255, 0, 0))),
+            ("green".to_string(), solid(7, 11, (0, 255, 0))),
+        ], 8).unwrap();
+        let mips = packed.generate_mips();
+        let sizes : Vec<(u32, u32)> = mips.iter().map(|x| x.dimensions()).collect();
+        assert_eq!(sizes, vec![(64, 64), (32, 32), (16, 16), (8, 8)]);
+
+        for (level, image) in mips.iter().enumerate() {
+            let padding = 8 >> level;
+            for &(ref name, ref rect) in packed.rects().iter() {
+                let color = if name.as_slice() == "red" {
+                    (255, 0, 0, 255)
+                } else {
+                    (0, 255, 0, 255)
+                };
+                let (left, top, right, bottom) = scale_rect(rect, level);
+                assert!(left >= padding && top >= padding);
+                for y in range(top - padding, bottom + padding) {
+                    for x in range(left - padding, right + padding) {
+                        assert!(pixel(image, x, y) == color,
+                                "{} bleeds at level {}, {}, {}", name, level, x, y);
+                    }
+                }
+            }
+        }
+    }
+}
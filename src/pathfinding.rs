@@ -0,0 +1,409 @@
+use std::collections::BinaryHeap;
+use std::num::Float;
+use std::u32;
+
+use geom::Vec2;
+use map::Map;
+use world::MAX_PLAYERS;
+
+/// cost of a step to a horizontal or vertical neighbour
+static STRAIGHT_COST : u32 = 10;
+/// cost of a diagonal step, roughly sqrt(2) times the straight cost
+static DIAGONAL_COST : u32 = 14;
+
+static NEIGHBOURS : [(i32, i32), ..8] = [
+    (1, 0), (-1, 0), (0, 1), (0, -1),
+    (1, 1), (1, -1), (-1, 1), (-1, -1),
+];
+
+
+#[deriving(PartialEq, Eq)]
+struct OpenNode {
+    cost: u32,
+    idx: uint,
+}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &OpenNode) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenNode {
+    // reversed so that the binary heap pops the cheapest node first
+    fn cmp(&self, other: &OpenNode) -> Ordering {
+        other.cost.cmp(&self.cost)
+    }
+}
+
+/// Which tiles tanks can stand on.  Tanks are narrower than a tile, so
+/// a tank centered on a tile it can enter never touches its neighbours.
+/// Corners are taken care of by only allowing diagonal steps past two
+/// passable tiles.
+#[deriving(Clone)]
+pub struct NavGrid {
+    width: u16,
+    height: u16,
+    passable: Vec<bool>,
+}
+
+impl NavGrid {
+
+    pub fn new(map: &Map) -> NavGrid {
+        let mut passable = Vec::with_capacity(
+            map.width() as uint * map.height() as uint);
+        for y in range(0, map.height()) {
+            for x in range(0, map.width()) {
+                passable.push(!map.get_tile(x, y).blocks_tank());
+            }
+        }
+        NavGrid {
+            width: map.width(),
+            height: map.height(),
+            passable: passable,
+        }
+    }
+
+    #[inline(always)]
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    #[inline(always)]
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn is_passable(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            false
+        } else {
+            self.passable[(y as uint) * (self.width as uint) + x as uint]
+        }
+    }
+
+    fn to_idx(&self, pos: (u16, u16)) -> uint {
+        let (x, y) = pos;
+        (y as uint) * (self.width as uint) + x as uint
+    }
+
+    fn from_idx(&self, idx: uint) -> (u16, u16) {
+        ((idx % self.width as uint) as u16, (idx / self.width as uint) as u16)
+    }
+
+    /// Calls `f` with every neighbour that can be entered from `idx` and
+    /// the cost of getting there.  Diagonal steps are only allowed if
+    /// both tiles next to the corner are passable so tanks do not clip
+    /// wall corners.
+    fn each_neighbour(&self, idx: uint, f: |uint, u32|) {
+        let (x, y) = self.from_idx(idx);
+        let (x, y) = (x as i32, y as i32);
+        for &(dx, dy) in NEIGHBOURS.iter() {
+            if !self.is_passable(x + dx, y + dy) {
+                continue;
+            }
+            let cost = if dx != 0 && dy != 0 {
+                if !self.is_passable(x + dx, y) || !self.is_passable(x, y + dy) {
+                    continue;
+                }
+                DIAGONAL_COST
+            } else {
+                STRAIGHT_COST
+            };
+            f(self.to_idx(((x + dx) as u16, (y + dy) as u16)), cost);
+        }
+    }
+
+    fn estimate(&self, a: uint, b: uint) -> u32 {
+        let (ax, ay) = self.from_idx(a);
+        let (bx, by) = self.from_idx(b);
+        let dx = (ax as i32 - bx as i32).abs() as u32;
+        let dy = (ay as i32 - by as i32).abs() as u32;
+        let (short, long) = if dx < dy { (dx, dy) } else { (dy, dx) };
+        short * DIAGONAL_COST + (long - short) * STRAIGHT_COST
+    }
+
+    /// Finds the cheapest path between two tiles with A*.  The returned
+    /// path includes both the start and the goal tile.
+    pub fn find_path(&self, from: (u16, u16), to: (u16, u16)) -> Option<Vec<(u16, u16)>> {
+        let (fx, fy) = from;
+        let (tx, ty) = to;
+        if !self.is_passable(fx as i32, fy as i32) || !self.is_passable(tx as i32, ty as i32) {
+            return None;
+        }
+
+        let start = self.to_idx(from);
+        let goal = self.to_idx(to);
+        let mut costs = Vec::from_elem(self.passable.len(), u32::MAX);
+        let mut came_from = Vec::from_elem(self.passable.len(), start);
+        let mut open = BinaryHeap::new();
+        costs[start] = 0;
+        open.push(OpenNode { cost: self.estimate(start, goal), idx: start });
+
+        loop {
+            let node = unwrap_or!(open.pop(), break);
+            if node.idx == goal {
+                let mut path = vec![self.from_idx(goal)];
+                let mut idx = goal;
+                while idx != start {
+                    idx = came_from[idx];
+                    path.push(self.from_idx(idx));
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            let base = costs[node.idx];
+            if node.cost > base + self.estimate(node.idx, goal) {
+                continue;
+            }
+            self.each_neighbour(node.idx, |next, cost| {
+                let new_cost = base + cost;
+                if new_cost < costs[next] {
+                    costs[next] = new_cost;
+                    came_from[next] = node.idx;
+                    open.push(OpenNode {
+                        cost: new_cost + self.estimate(next, goal),
+                        idx: next,
+                    });
+                }
+            });
+        }
+
+        None
+    }
+
+    /// Builds the distance of every tile to `goal` with Dijkstra.
+    pub fn distance_field(&self, goal: (u16, u16)) -> DistanceField {
+        let mut costs = Vec::from_elem(self.passable.len(), u32::MAX);
+        let (gx, gy) = goal;
+        if self.is_passable(gx as i32, gy as i32) {
+            let start = self.to_idx(goal);
+            let mut open = BinaryHeap::new();
+            costs[start] = 0;
+            open.push(OpenNode { cost: 0, idx: start });
+            loop {
+                let node = unwrap_or!(open.pop(), break);
+                if node.cost > costs[node.idx] {
+                    continue;
+                }
+                self.each_neighbour(node.idx, |next, cost| {
+                    let new_cost = node.cost + cost;
+                    if new_cost < costs[next] {
+                        costs[next] = new_cost;
+                        open.push(OpenNode { cost: new_cost, idx: next });
+                    }
+                });
+            }
+        }
+        DistanceField {
+            width: self.width,
+            height: self.height,
+            goal: goal,
+            costs: costs,
+        }
+    }
+}
+
+/// The travel cost from every tile of the map to a goal tile.
+#[deriving(Clone)]
+pub struct DistanceField {
+    width: u16,
+    height: u16,
+    goal: (u16, u16),
+    costs: Vec<u32>,
+}
+
+impl DistanceField {
+
+    #[inline(always)]
+    pub fn goal(&self) -> (u16, u16) {
+        self.goal
+    }
+
+    /// the cost to reach the goal from a tile or `None` if the goal is
+    /// unreachable from there
+    pub fn get(&self, x: i32, y: i32) -> Option<u32> {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return None;
+        }
+        match self.costs[(y as uint) * (self.width as uint) + x as uint] {
+            u32::MAX => None,
+            cost => Some(cost),
+        }
+    }
+
+    /// the neighbour of a tile that is closest to the goal
+    pub fn next_step(&self, x: u16, y: u16) -> Option<(u16, u16)> {
+        let mut best = unwrap_or!(self.get(x as i32, y as i32), return None);
+        let mut rv = None;
+        for &(dx, dy) in NEIGHBOURS.iter() {
+            let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+            let cost = unwrap_or!(self.get(nx, ny), continue);
+            if cost < best {
+                best = cost;
+                rv = Some((nx as u16, ny as u16));
+            }
+        }
+        rv
+    }
+}
+
+/// Navigation data for tanks.  The grid and the distance fields towards
+/// the players are cached and only rebuilt when the map changes or a
+/// player moves onto another tile.
+pub struct Pathfinder {
+    revision: u32,
+    grid: NavGrid,
+    fields: Vec<Option<DistanceField>>,
+    /// how many distance fields were computed so far
+    fields_built: uint,
+}
+
+impl Pathfinder {
+
+    pub fn new(map: &Map) -> Pathfinder {
+        Pathfinder {
+            revision: map.revision(),
+            grid: NavGrid::new(map),
+            fields: Vec::from_fn(MAX_PLAYERS, |_| None),
+            fields_built: 0,
+        }
+    }
+
+    /// Rebuilds the cached data if the map changed since the last call.
+    pub fn update(&mut self, map: &Map) {
+        if map.revision() == self.revision {
+            return;
+        }
+        self.revision = map.revision();
+        self.grid = NavGrid::new(map);
+        for field in self.fields.iter_mut() {
+            *field = None;
+        }
+    }
+
+    #[inline(always)]
+    pub fn grid(&self) -> &NavGrid {
+        &self.grid
+    }
+
+    pub fn find_path(&self, from: (u16, u16), to: (u16, u16)) -> Option<Vec<(u16, u16)>> {
+        self.grid.find_path(from, to)
+    }
+
+    /// Moves the goal of a player's distance field.  The field is only
+    /// recomputed if the tile actually changed.
+    pub fn track_player(&mut self, player: u8, pos: (u16, u16)) {
+        let slot = &mut self.fields[player as uint];
+        let stale = match *slot {
+            Some(ref field) => field.goal() != pos,
+            None => true,
+        };
+        if stale {
+            *slot = Some(self.grid.distance_field(pos));
+            self.fields_built += 1;
+        }
+    }
+
+    /// forgets the distance field of a player, e.g. after they died
+    pub fn forget_player(&mut self, player: u8) {
+        self.fields[player as uint] = None;
+    }
+
+    /// the distance field towards a player if the player is tracked
+    pub fn get_player_field(&self, player: u8) -> Option<&DistanceField> {
+        self.fields[player as uint].as_ref()
+    }
+}
+
+/// the tile a position is on
+pub fn tile_of(pos: Vec2) -> (u16, u16) {
+    (pos.x.floor().max(0.0) as u16, pos.y.floor().max(0.0) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use map::{Map, Tile};
+    use super::{NavGrid, Pathfinder};
+
+    fn map() -> Map {
+        Map::from_glyphs(&[
+            "111111",
+            "1..1.1",
+            "1..1.1",
+            "1....1",
+            "111111",
+        ]).unwrap()
+    }
+
+    #[test]
+    fn paths_go_around_walls() {
+        let grid = NavGrid::new(&map());
+        let path = grid.find_path((1, 1), (4, 1)).unwrap();
+        assert_eq!(path[0], (1, 1));
+        assert_eq!(path[path.len() - 1], (4, 1));
+        assert!(path.iter().all(|&(x, y)| grid.is_passable(x as i32, y as i32)));
+        // no diagonal step may cut the corner of the wall at 3, 2
+        assert!(!path.windows(2).any(|w| w[0] == (2, 2) && w[1] == (3, 3)));
+        assert!(grid.find_path((1, 1), (3, 1)).is_none());
+    }
+
+    #[test]
+    fn distance_fields_count_steps() {
+        let grid = NavGrid::new(&map());
+        let field = grid.distance_field((4, 1));
+        assert_eq!(field.get(4, 1), Some(0));
+        assert_eq!(field.get(4, 3), Some(20));
+        assert_eq!(field.get(3, 1), None);
+        assert_eq!(field.get(-1, 0), None);
+    }
+
+    #[test]
+    fn grid_is_rebuilt_when_the_map_changes() {
+        let mut map = map();
+        let mut pathfinder = Pathfinder::new(&map);
+        assert!(pathfinder.find_path((1, 1), (4, 1)).is_some());
+        map.set_tile(4, 2, Tile::Water);
+        pathfinder.update(&map);
+        assert!(pathfinder.find_path((1, 1), (4, 1)).is_none());
+    }
+
+    #[test]
+    fn next_step_heads_for_the_goal() {
+        let grid = NavGrid::new(&map());
+        let field = grid.distance_field((4, 1));
+        assert_eq!(field.next_step(4, 3), Some((4, 2)));
+        assert_eq!(field.next_step(4, 1), None);
+        assert_eq!(field.next_step(3, 1), None);
+    }
+
+    #[test]
+    fn player_fields_are_recomputed_when_needed() {
+        let mut map = map();
+        map.set_tile(4, 3, Tile::Crate);
+        let mut pathfinder = Pathfinder::new(&map);
+        pathfinder.track_player(0, (4, 1));
+        assert_eq!(pathfinder.fields_built, 1);
+        assert_eq!(pathfinder.get_player_field(0).unwrap().get(1, 1), None);
+
+        // nothing changed, the field stays
+        pathfinder.update(&map);
+        pathfinder.track_player(0, (4, 1));
+        assert_eq!(pathfinder.fields_built, 1);
+
+        // the crate is gone, the way around is open now
+        map.set_tile(4, 3, Tile::Ground);
+        pathfinder.update(&map);
+        assert!(pathfinder.get_player_field(0).is_none());
+        pathfinder.track_player(0, (4, 1));
+        assert_eq!(pathfinder.fields_built, 2);
+        assert_eq!(pathfinder.get_player_field(0).unwrap().get(1, 1), Some(64));
+
+        // the player moved
+        pathfinder.track_player(0, (4, 2));
+        assert_eq!(pathfinder.fields_built, 3);
+        pathfinder.forget_player(0);
+        assert!(pathfinder.get_player_field(0).is_none());
+    }
+}
@@ -0,0 +1,532 @@
+use std::num::{Float, FromStrRadix};
+use serialize::json;
+
+use errors::{Res, GameError};
+use map::{Spawn, EnemyType};
+
+pub type Color = [f32, ..4];
+
+
+/// A Tiled custom property.
+#[deriving(Clone, PartialEq, Show)]
+pub enum PropertyValue {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Color(Color),
+}
+
+impl PropertyValue {
+
+    /// Parses a property value as written by Tiled for a property type.
+    /// Unknown types (like files) are kept as strings.
+    pub fn parse(type_name: &str, value: &str) -> Option<PropertyValue> {
+        match type_name {
+            "int" => from_str(value.trim()).map(|x| PropertyValue::Int(x)),
+            "float" => from_str(value.trim()).map(|x| PropertyValue::Float(x)),
+            "bool" => from_str(value.trim()).map(|x| PropertyValue::Bool(x)),
+            "color" => parse_color(value.trim()).map(|x| PropertyValue::Color(x)),
+            _ => Some(PropertyValue::String(value.to_string())),
+        }
+    }
+
+    /// the name of the type as written by Tiled
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            PropertyValue::String(_) => "string",
+            PropertyValue::Int(_) => "int",
+            PropertyValue::Float(_) => "float",
+            PropertyValue::Bool(_) => "bool",
+            PropertyValue::Color(_) => "color",
+        }
+    }
+
+    /// the value as written by Tiled into a TMX file
+    pub fn to_value_string(&self) -> String {
+        match *self {
+            PropertyValue::String(ref x) => x.clone(),
+            PropertyValue::Int(x) => x.to_string(),
+            PropertyValue::Float(x) => x.to_string(),
+            PropertyValue::Bool(x) => x.to_string(),
+            PropertyValue::Color(x) => format_color(x),
+        }
+    }
+
+    /// the value as written by Tiled into a JSON file
+    pub fn to_json(&self) -> json::Json {
+        match *self {
+            PropertyValue::String(ref x) => json::Json::String(x.clone()),
+            PropertyValue::Int(x) => json::Json::I64(x),
+            PropertyValue::Float(x) => json::Json::F64(x),
+            PropertyValue::Bool(x) => json::Json::Boolean(x),
+            PropertyValue::Color(x) => json::Json::String(format_color(x)),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            PropertyValue::String(ref x) => Some(x.as_slice()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            PropertyValue::Int(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// the value as float, ints are converted
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            PropertyValue::Float(x) => Some(x),
+            PropertyValue::Int(x) => Some(x as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            PropertyValue::Bool(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// the value as color, strings in color notation are accepted too
+    pub fn as_color(&self) -> Option<Color> {
+        match *self {
+            PropertyValue::Color(x) => Some(x),
+            PropertyValue::String(ref x) => parse_color(x.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a color the way Tiled writes them: `#AARRGGBB` or `#RRGGBB`.
+pub fn parse_color(value: &str) -> Option<Color> {
+    let hex = if value.starts_with("#") { value.slice_from(1) } else { value };
+    let value : u32 = unwrap_or!(FromStrRadix::from_str_radix(hex, 16), return None);
+    let channel = |shift: uint| ((value >> shift) & 0xff) as f32 / 255.0;
+    match hex.len() {
+        6 => Some([channel(16), channel(8), channel(0), 1.0]),
+        8 => Some([channel(16), channel(8), channel(0), channel(24)]),
+        _ => None,
+    }
+}
+
+/// Formats a color the way Tiled writes them: `#AARRGGBB`.
+pub fn format_color(color: Color) -> String {
+    let channel = |idx: uint| (color[idx].max(0.0).min(1.0) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}{:02x}", channel(3), channel(0), channel(1), channel(2))
+}
+
+/// The custom properties of a map or tileset in the order they were
+/// defined.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Properties {
+    values: Vec<(String, PropertyValue)>,
+}
+
+impl Properties {
+
+    pub fn new() -> Properties {
+        Properties { values: vec![] }
+    }
+
+    /// Sets a property, replacing a previous value of the same name.
+    pub fn insert(&mut self, name: String, value: PropertyValue) {
+        match self.values.iter().position(|&(ref key, _)| *key == name) {
+            Some(idx) => { self.values[idx] = (name, value); }
+            None => { self.values.push((name, value)); }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PropertyValue> {
+        self.values.iter()
+            .find(|&&(ref key, _)| key.as_slice() == name)
+            .map(|&(_, ref value)| value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> ::std::slice::Items<(String, PropertyValue)> {
+        self.values.iter()
+    }
+
+    /// Converts the properties into the list of typed properties of
+    /// current Tiled versions.
+    pub fn to_json(&self) -> json::Json {
+        json::Json::Array(self.values.iter().map(|&(ref name, ref value)| {
+            let mut rv = json::JsonObject::new();
+            rv.insert("name".to_string(), json::Json::String(name.clone()));
+            rv.insert("type".to_string(), json::Json::String(value.type_name().to_string()));
+            rv.insert("value".to_string(), value.to_json());
+            json::Json::Object(rv)
+        }).collect())
+    }
+
+    /// Reads properties from the `properties` key of a Tiled JSON object.
+    /// Both the list of typed properties of current Tiled versions and the
+    /// older object with `propertytypes` next to it are understood.
+    pub fn from_json(obj: &json::Json) -> Res<Properties> {
+        let mut rv = Properties::new();
+        match obj.find("properties") {
+            None => {}
+            Some(&json::Json::Array(ref items)) => {
+                for item in items.iter() {
+                    let name = unwrap_or!(item.find("name").and_then(|x| x.as_string()),
+                        return Err(GameError::InvalidMap("Property without name", None)));
+                    let type_name = item.find("type").and_then(|x| x.as_string())
+                        .unwrap_or("string");
+                    let value = unwrap_or!(item.find("value"),
+                        return Err(GameError::InvalidMap("Property without value", None)));
+                    rv.insert(name.to_string(), try!(json_value(type_name, value)));
+                }
+            }
+            Some(&json::Json::Object(ref items)) => {
+                let types = obj.find("propertytypes");
+                for (name, value) in items.iter() {
+                    let type_name = types
+                        .and_then(|x| x.find(name.as_slice()))
+                        .and_then(|x| x.as_string())
+                        .unwrap_or("");
+                    rv.insert(name.clone(), try!(json_value(type_name, value)));
+                }
+            }
+            Some(_) => {
+                return Err(GameError::InvalidMap("Malformed properties", None));
+            }
+        }
+        Ok(rv)
+    }
+}
+
+fn json_value(type_name: &str, value: &json::Json) -> Res<PropertyValue> {
+    let rv = match *value {
+        json::Json::String(ref x) => match type_name {
+            "" => Some(PropertyValue::String(x.clone())),
+            _ => PropertyValue::parse(type_name, x.as_slice()),
+        },
+        json::Json::Boolean(x) => Some(PropertyValue::Bool(x)),
+        _ => match (type_name, value.as_i64(), value.as_f64()) {
+            ("float", _, Some(x)) => Some(PropertyValue::Float(x)),
+            (_, Some(x), _) => Some(PropertyValue::Int(x)),
+            (_, None, Some(x)) => Some(PropertyValue::Float(x)),
+            _ => None,
+        },
+    };
+    match rv {
+        Some(rv) => Ok(rv),
+        None => Err(GameError::InvalidMap("Malformed property value", None)),
+    }
+}
+
+/// Where the camera looks at, overriding the default of looking at the
+/// center of the map.  Positions are in tiles.
+#[deriving(Clone, PartialEq, Show)]
+pub struct CameraOverride {
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub height: Option<f32>,
+}
+
+/// A group of enemies that arrives together.
+#[deriving(Clone, PartialEq, Show)]
+pub struct EnemyWave {
+    pub enemies: Vec<(EnemyType, u32)>,
+}
+
+/// The typed metadata of a level.  Everything is read from the custom
+/// properties of the map, the untyped properties are kept as well.
+#[deriving(Clone, PartialEq, Show)]
+pub struct MapProperties {
+    /// `name`: the name of the level as shown to players
+    pub name: Option<String>,
+    /// `par_time`: seconds a good player needs for the level
+    pub par_time: Option<f32>,
+    /// `music`: the music cue played during the level
+    pub music: Option<String>,
+    /// `camera_x`, `camera_y`, `camera_height`
+    pub camera: CameraOverride,
+    /// `waves`: enemies arriving during the level, like
+    /// `basic*3, fast; rocket*2`.  Waves are separated by semicolons.
+    pub waves: Vec<EnemyWave>,
+    /// `wave_interval`: seconds between two waves
+    pub wave_interval: f32,
+    /// `ambient_color`: the ambient light of the level
+    pub ambient_color: Option<Color>,
+    /// all properties as they are in the map file
+    pub all: Properties,
+}
+
+fn wrong_type() -> GameError {
+    GameError::InvalidMap("Map property has the wrong type", None)
+}
+
+fn get_string(props: &Properties, name: &str) -> Res<Option<String>> {
+    match props.get(name) {
+        Some(value) => match value.as_str() {
+            Some(x) => Ok(Some(x.to_string())),
+            None => Err(wrong_type()),
+        },
+        None => Ok(None),
+    }
+}
+
+fn get_float(props: &Properties, name: &str) -> Res<Option<f32>> {
+    match props.get(name) {
+        Some(value) => match value.as_float() {
+            Some(x) => Ok(Some(x as f32)),
+            None => Err(wrong_type()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parses the wave notation, e.g. `basic*3, fast; rocket*2`.
+fn parse_waves(value: &str) -> Res<Vec<EnemyWave>> {
+    let mut rv = vec![];
+    for wave in value.split(';') {
+        let mut enemies = vec![];
+        for item in wave.split(',') {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+            let mut parts = item.splitn(1, '*');
+            let name = parts.next().unwrap_or("").trim();
+            let count = match parts.next() {
+                Some(count) => unwrap_or!(from_str(count.trim()),
+                    return Err(GameError::InvalidMap("Invalid enemy count in waves", None))),
+                None => 1,
+            };
+            match Spawn::from_name(name) {
+                Some(Spawn::Enemy(ty)) => enemies.push((ty, count)),
+                _ => { return Err(GameError::InvalidMap("Unknown enemy in waves", None)); }
+            }
+        }
+        if !enemies.is_empty() {
+            rv.push(EnemyWave { enemies: enemies });
+        }
+    }
+    Ok(rv)
+}
+
+impl MapProperties {
+
+    pub fn new() -> MapProperties {
+        MapProperties::from_properties(Properties::new()).unwrap()
+    }
+
+    /// Reads the typed metadata from custom properties.  Properties the
+    /// game does not know are ignored, known ones of the wrong type fail.
+    pub fn from_properties(props: Properties) -> Res<MapProperties> {
+        let waves = match try!(get_string(&props, "waves")) {
+            Some(value) => try!(parse_waves(value.as_slice())),
+            None => vec![],
+        };
+        let ambient_color = match props.get("ambient_color") {
+            Some(value) => Some(unwrap_or!(value.as_color(), return Err(wrong_type()))),
+            None => None,
+        };
+        Ok(MapProperties {
+            name: try!(get_string(&props, "name")),
+            par_time: try!(get_float(&props, "par_time")),
+            music: try!(get_string(&props, "music")),
+            camera: CameraOverride {
+                x: try!(get_float(&props, "camera_x")),
+                y: try!(get_float(&props, "camera_y")),
+                height: try!(get_float(&props, "camera_height")),
+            },
+            waves: waves,
+            wave_interval: try!(get_float(&props, "wave_interval")).unwrap_or(0.0),
+            ambient_color: ambient_color,
+            all: props,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialize::json;
+
+    use errors::{Res, GameError};
+    use map::EnemyType;
+    use super::{Properties, PropertyValue, MapProperties, EnemyWave,
+                parse_color, format_color, parse_waves};
+
+    fn from_json(text: &str) -> Res<Properties> {
+        Properties::from_json(&json::from_str(text).unwrap())
+    }
+
+    fn map_properties(text: &str) -> Res<MapProperties> {
+        MapProperties::from_properties(try!(from_json(text)))
+    }
+
+    fn message<T>(rv: Res<T>) -> &'static str {
+        match rv.err().unwrap() {
+            GameError::InvalidMap(msg, _) => msg,
+            _ => "",
+        }
+    }
+
+    #[test]
+    fn colors() {
+        let half = 128.0 / 255.0;
+        assert_eq!(parse_color("#80ff0000"), Some([1.0, 0.0, 0.0, half]));
+        assert_eq!(parse_color("#ff8000"), Some([1.0, half, 0.0, 1.0]));
+        assert_eq!(parse_color("00ff00"), Some([0.0, 1.0, 0.0, 1.0]));
+        assert_eq!(parse_color("#fff"), None);
+        assert_eq!(parse_color("#gg0000"), None);
+        assert_eq!(format_color([1.0, 0.5, 0.0, 1.0]).as_slice(), "#ffff8000");
+        assert_eq!(format_color([2.0, -1.0, 0.0, 0.0]).as_slice(), "#00ff0000");
+        assert_eq!(format_color(parse_color("#80123456").unwrap()).as_slice(), "#80123456");
+    }
+
+    #[test]
+    fn waves() {
+        assert_eq!(parse_waves("basic*3, fast; rocket*2").unwrap(), vec![
+            EnemyWave { enemies: vec![(EnemyType::Basic, 3), (EnemyType::Fast, 1)] },
+            EnemyWave { enemies: vec![(EnemyType::Rocket, 2)] },
+        ]);
+        assert_eq!(parse_waves(" ; heat_seeker * 2 ;").unwrap(), vec![
+            EnemyWave { enemies: vec![(EnemyType::HeatSeeker, 2)] },
+        ]);
+        assert!(parse_waves("").unwrap().is_empty());
+        assert_eq!(message(parse_waves("basic*many")), "Invalid enemy count in waves");
+        assert_eq!(message(parse_waves("basic, tank")), "Unknown enemy in waves");
+        assert_eq!(message(parse_waves("mine")), "Unknown enemy in waves");
+    }
+
+    #[test]
+    fn typed_properties() {
+        let props = from_json(r#"{"properties": [
+            {"name": "title", "type": "string", "value": "Intro"},
+            {"name": "lives", "type": "int", "value": 3},
+            {"name": "speed", "type": "float", "value": 1},
+            {"name": "secret", "type": "bool", "value": true},
+            {"name": "tint", "type": "color", "value": "#ff00ff00"},
+            {"name": "untyped", "value": "x"}
+        ]}"#).unwrap();
+        assert_eq!(props.get("title"), Some(&PropertyValue::String("Intro".to_string())));
+        assert_eq!(props.get("lives"), Some(&PropertyValue::Int(3)));
+        assert_eq!(props.get("speed"), Some(&PropertyValue::Float(1.0)));
+        assert_eq!(props.get("secret"), Some(&PropertyValue::Bool(true)));
+        assert_eq!(props.get("tint"), Some(&PropertyValue::Color([0.0, 1.0, 0.0, 1.0])));
+        assert_eq!(props.get("untyped"), Some(&PropertyValue::String("x".to_string())));
+        assert_eq!(props.iter().map(|&(ref name, _)| name.as_slice()).collect::<Vec<&str>>(),
+                   vec!["title", "lives", "speed", "secret", "tint", "untyped"]);
+        let mut obj = json::JsonObject::new();
+        obj.insert("properties".to_string(), props.to_json());
+        assert_eq!(Properties::from_json(&json::Json::Object(obj)).unwrap(), props);
+    }
+
+    #[test]
+    fn legacy_properties() {
+        let props = from_json(r#"{
+            "properties": {
+                "title": "Intro",
+                "lives": "3",
+                "speed": 2,
+                "secret": "true",
+                "tint": "#ff00ff00",
+                "plain": 4,
+                "fraction": 0.5
+            },
+            "propertytypes": {
+                "title": "string",
+                "lives": "int",
+                "speed": "float",
+                "secret": "bool",
+                "tint": "color"
+            }
+        }"#).unwrap();
+        assert_eq!(props.get("title"), Some(&PropertyValue::String("Intro".to_string())));
+        assert_eq!(props.get("lives"), Some(&PropertyValue::Int(3)));
+        assert_eq!(props.get("speed"), Some(&PropertyValue::Float(2.0)));
+        assert_eq!(props.get("secret"), Some(&PropertyValue::Bool(true)));
+        assert_eq!(props.get("tint"), Some(&PropertyValue::Color([0.0, 1.0, 0.0, 1.0])));
+        // without a type numbers keep what they look like
+        assert_eq!(props.get("plain"), Some(&PropertyValue::Int(4)));
+        assert_eq!(props.get("fraction"), Some(&PropertyValue::Float(0.5)));
+        assert!(from_json("{}").unwrap().is_empty());
+        assert_eq!(message(from_json(r#"{"properties": 3}"#)), "Malformed properties");
+    }
+
+    #[test]
+    fn malformed_values() {
+        for &(type_name, value) in [
+            ("int", "\"three\""),
+            ("int", "[]"),
+            ("float", "\"fast\""),
+            ("bool", "\"maybe\""),
+            ("color", "\"#12\""),
+            ("color", "\"red\""),
+        ].iter() {
+            let text = format!(r#"{{"properties": [{{"name": "x", "type": "{}", "value": {}}}]}}"#,
+                               type_name, value);
+            assert_eq!(message(from_json(text.as_slice())), "Malformed property value");
+        }
+        assert_eq!(message(from_json(r#"{"properties": [{"value": 1}]}"#)),
+                   "Property without name");
+        assert_eq!(message(from_json(r#"{"properties": [{"name": "x"}]}"#)),
+                   "Property without value");
+    }
+
+    #[test]
+    fn map_properties_are_typed() {
+        let props = map_properties(r#"{"properties": [
+            {"name": "name", "type": "string", "value": "First"},
+            {"name": "par_time", "type": "int", "value": 90},
+            {"name": "music", "type": "string", "value": "march"},
+            {"name": "camera_x", "type": "float", "value": 4.5},
+            {"name": "camera_height", "type": "float", "value": 12},
+            {"name": "waves", "type": "string", "value": "basic*2; fast"},
+            {"name": "wave_interval", "type": "float", "value": 20},
+            {"name": "ambient_color", "type": "string", "value": "#ff000000"},
+            {"name": "custom", "type": "bool", "value": false}
+        ]}"#).unwrap();
+        assert_eq!(props.name, Some("First".to_string()));
+        assert_eq!(props.par_time, Some(90.0));
+        assert_eq!(props.music, Some("march".to_string()));
+        assert_eq!(props.camera.x, Some(4.5));
+        assert_eq!(props.camera.y, None);
+        assert_eq!(props.camera.height, Some(12.0));
+        assert_eq!(props.waves, vec![
+            EnemyWave { enemies: vec![(EnemyType::Basic, 2)] },
+            EnemyWave { enemies: vec![(EnemyType::Fast, 1)] },
+        ]);
+        assert_eq!(props.wave_interval, 20.0);
+        assert_eq!(props.ambient_color, Some([0.0, 0.0, 0.0, 1.0]));
+        assert_eq!(props.all.get("custom"), Some(&PropertyValue::Bool(false)));
+
+        let empty = MapProperties::new();
+        assert_eq!(empty.name, None);
+        assert_eq!(empty.par_time, None);
+        assert!(empty.waves.is_empty());
+        assert_eq!(empty.wave_interval, 0.0);
+        assert_eq!(empty.ambient_color, None);
+    }
+
+    #[test]
+    fn map_properties_of_the_wrong_type() {
+        for &(name, type_name, value) in [
+            ("name", "int", "1"),
+            ("music", "bool", "true"),
+            ("waves", "int", "2"),
+            ("par_time", "string", "\"soon\""),
+            ("camera_x", "bool", "false"),
+            ("ambient_color", "float", "0.5"),
+            ("ambient_color", "string", "\"dark\""),
+        ].iter() {
+            let text = format!(r#"{{"properties": [{{"name": "{}", "type": "{}", "value": {}}}]}}"#,
+                               name, type_name, value);
+            assert_eq!(message(map_properties(text.as_slice())),
+                       "Map property has the wrong type");
+        }
+    }
+}
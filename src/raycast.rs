@@ -0,0 +1,297 @@
+use std::num::Float;
+use std::f32::consts::PI;
+
+use collision;
+use geom::{Vec2, normalize_angle, closest_point_on_segment};
+use map::{Map, Tile};
+
+/// how far a traced ray is moved away from a wall after a bounce
+static BOUNCE_OFFSET : f32 = 0.001;
+
+
+/// The path a shot takes, as polyline from the muzzle to where it ends.
+/// Every point between the first and the last one is a ricochet, the
+/// last one is where the shot stops, at a wall or anywhere else.
+#[deriving(Clone, Show)]
+pub struct Trajectory {
+    pub angle: f32,
+    pub points: Vec<Vec2>,
+    bounces: uint,
+}
+
+impl Trajectory {
+
+    /// how often the shot bounces off walls, the wall it stops at does
+    /// not count
+    pub fn bounces(&self) -> uint {
+        self.bounces
+    }
+
+    /// the total length of the polyline
+    pub fn length(&self) -> f32 {
+        let mut rv = 0.0;
+        for idx in range(1, self.points.len()) {
+            rv += self.points[idx - 1].distance(self.points[idx]);
+        }
+        rv
+    }
+
+    /// true if the path passes within `radius` of `pos`
+    pub fn passes(&self, pos: Vec2, radius: f32) -> bool {
+        self.passes_after(0, pos, radius)
+    }
+
+    /// Like `passes` but ignores the first `segments` segments.  This is
+    /// useful to check if a shot comes back to the shooter.
+    pub fn passes_after(&self, segments: uint, pos: Vec2, radius: f32) -> bool {
+        for idx in range(segments + 1, self.points.len()) {
+            let closest = closest_point_on_segment(
+                self.points[idx - 1], self.points[idx], pos);
+            if closest.distance(pos) < radius {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Cuts the path off where it first passes within `radius` of `pos`,
+    /// which gives the part of the path up to hitting a target.
+    pub fn truncate_at(&self, pos: Vec2, radius: f32) -> Trajectory {
+        let mut points = vec![self.points[0]];
+        let mut bounces = self.bounces;
+        for idx in range(1, self.points.len()) {
+            let closest = closest_point_on_segment(
+                self.points[idx - 1], self.points[idx], pos);
+            if closest.distance(pos) < radius {
+                points.push(closest);
+                // only the ricochets before the cut are left
+                bounces = idx - 1;
+                break;
+            }
+            points.push(self.points[idx]);
+        }
+        Trajectory { angle: self.angle, points: points, bounces: bounces }
+    }
+}
+
+/// What `find_bank_shot` searches for.
+#[deriving(Copy, Clone, Show)]
+pub struct BankShotQuery {
+    /// the maximum number of ricochets the shot may take
+    pub max_bounces: u8,
+    /// how many evenly spaced angles are tried
+    pub samples: uint,
+    /// how far the shot may travel in total
+    pub max_distance: f32,
+    /// how close the shot has to pass the target to count as hit
+    pub target_radius: f32,
+    /// Shots with the same number of ricochets are picked by how close
+    /// they are to this angle.  Without it the shortest shot wins.
+    pub preferred_angle: Option<f32>,
+}
+
+impl BankShotQuery {
+
+    pub fn new(max_bounces: u8) -> BankShotQuery {
+        BankShotQuery {
+            max_bounces: max_bounces,
+            samples: 180,
+            max_distance: 30.0,
+            target_radius: 0.35,
+            preferred_angle: None,
+        }
+    }
+}
+
+/// Checks that nothing blocking bullets is between two points.  The
+/// grid is walked with a DDA so no tile the line touches is skipped.
+pub fn line_of_sight(map: &Map, from: Vec2, to: Vec2) -> bool {
+    collision::sweep_ray(map, from, to, Tile::blocks_bullet).is_none()
+}
+
+/// Traces a shot from `from` into the direction of `angle` for up to
+/// `max_distance` tiles.  The shot bounces off tiles that block bullets
+/// at most `max_bounces` times and ends at the wall after that.
+pub fn trace(map: &Map, from: Vec2, angle: f32, max_bounces: u8,
+             max_distance: f32) -> Trajectory {
+    let mut points = vec![from];
+    let mut bounces = 0;
+    let mut pos = from;
+    let mut delta = Vec2::from_angle(angle).scale(max_distance);
+    for bounce in range(0, max_bounces as uint + 1) {
+        match collision::sweep_ray(map, pos, pos.add(delta), Tile::blocks_bullet) {
+            Some(hit) => {
+                points.push(hit.pos);
+                if bounce == max_bounces as uint {
+                    break;
+                }
+                bounces += 1;
+                pos = hit.pos.add(hit.normal.scale(BOUNCE_OFFSET));
+                delta = delta.scale(1.0 - hit.fraction).reflect(hit.normal);
+            }
+            None => {
+                points.push(pos.add(delta));
+                break;
+            }
+        }
+    }
+    Trajectory { angle: angle, points: points, bounces: bounces }
+}
+
+/// what decides between two shots with the same number of ricochets,
+/// lower is better
+fn tie_break(query: &BankShotQuery, shot: &Trajectory) -> f32 {
+    match query.preferred_angle {
+        Some(angle) => normalize_angle(shot.angle - angle).abs(),
+        None => shot.length(),
+    }
+}
+
+/// Searches for a shot from `from` that reaches `to`, either directly
+/// or off up to `query.max_bounces` walls.  Only shots that `accept`
+/// agrees with are considered, it sees the shot up to the target and
+/// can reject it for passing something on the way.  Shots with fewer
+/// bounces win, the direct line above all.  The returned trajectory
+/// ends at the target.
+pub fn find_bank_shot(map: &Map, from: Vec2, to: Vec2, query: &BankShotQuery,
+                      accept: |&Trajectory| -> bool) -> Option<Trajectory> {
+    let direct = to.sub(from).angle();
+    if from.distance(to) <= query.max_distance && line_of_sight(map, from, to) {
+        let shot = Trajectory { angle: direct, points: vec![from, to], bounces: 0 };
+        if accept(&shot) {
+            return Some(shot);
+        }
+    }
+
+    let mut best : Option<Trajectory> = None;
+    for idx in range(0, query.samples) {
+        let angle = normalize_angle(
+            direct + (idx as f32 / query.samples as f32) * PI * 2.0);
+        let shot = trace(map, from, angle, query.max_bounces, query.max_distance);
+        if !shot.passes(to, query.target_radius) {
+            continue;
+        }
+        let shot = shot.truncate_at(to, query.target_radius);
+        if !accept(&shot) {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some(ref other) => {
+                shot.bounces() < other.bounces() ||
+                    (shot.bounces() == other.bounces() &&
+                     tie_break(query, &shot) < tie_break(query, other))
+            }
+        };
+        if better {
+            best = Some(shot);
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use geom::Vec2;
+    use map::Map;
+    use super::{trace, find_bank_shot, BankShotQuery};
+
+    fn corridor() -> Map {
+        Map::from_glyphs(&[
+            "11111111",
+            "1......1",
+            "11111111",
+        ]).unwrap()
+    }
+
+    /// a room with a pillar between the two spawns
+    fn room() -> Map {
+        Map::from_glyphs(&[
+            "111111111",
+            "1.......1",
+            "1.......1",
+            "1...1...1",
+            "1.......1",
+            "1.......1",
+            "111111111",
+        ]).unwrap()
+    }
+
+    #[test]
+    fn stopping_at_a_wall_is_no_bounce() {
+        let map = corridor();
+        let from = Vec2::new(1.5, 1.5);
+        let shot = trace(&map, from, 0.0, 0, 100.0);
+        assert_eq!(shot.points.len(), 2);
+        assert_eq!(shot.bounces(), 0);
+        let shot = trace(&map, from, 0.0, 1, 100.0);
+        assert_eq!(shot.points.len(), 3);
+        assert_eq!(shot.bounces(), 1);
+        let shot = trace(&map, from, 0.0, 3, 100.0);
+        assert_eq!(shot.points.len(), 5);
+        assert_eq!(shot.bounces(), 3);
+        // running out of distance before the next wall
+        let shot = trace(&map, from, 0.0, 3, 8.0);
+        assert_eq!(shot.points.len(), 3);
+        assert_eq!(shot.bounces(), 1);
+    }
+
+    #[test]
+    fn direct_shots_respect_the_distance() {
+        let map = corridor();
+        let (from, to) = (Vec2::new(1.5, 1.5), Vec2::new(6.5, 1.5));
+        let mut query = BankShotQuery::new(0);
+        query.max_distance = 4.0;
+        assert!(find_bank_shot(&map, from, to, &query, |_| true).is_none());
+        query.max_distance = 6.0;
+        let shot = find_bank_shot(&map, from, to, &query, |_| true).unwrap();
+        assert_eq!(shot.bounces(), 0);
+        assert_eq!(shot.angle, 0.0);
+    }
+
+    #[test]
+    fn rejected_shots_are_skipped() {
+        let map = room();
+        let (from, to) = (Vec2::new(2.5, 3.5), Vec2::new(6.5, 3.5));
+        let mut query = BankShotQuery::new(1);
+        query.preferred_angle = Some(-PI / 4.0);
+        let shot = find_bank_shot(&map, from, to, &query, |shot| {
+            shot.points[1].y > 4.0
+        }).unwrap();
+        assert!(shot.angle > 0.0);
+        assert!(find_bank_shot(&map, from, to, &query, |_| false).is_none());
+    }
+
+    #[test]
+    fn shots_back_through_the_shooter_can_be_skipped() {
+        let map = corridor();
+        let (from, to) = (Vec2::new(4.5, 1.5), Vec2::new(2.5, 1.5));
+        let query = BankShotQuery::new(1);
+        let shot = find_bank_shot(&map, from, to, &query, |shot| {
+            shot.bounces() > 0 && !shot.passes_after(1, from, 0.35)
+        }).unwrap();
+        assert_eq!(shot.bounces(), 1);
+        assert!(!shot.passes_after(1, from, 0.35));
+    }
+
+    #[test]
+    fn bank_shots_prefer_the_given_heading() {
+        let map = room();
+        let (from, to) = (Vec2::new(2.5, 3.5), Vec2::new(6.5, 3.5));
+        let mut query = BankShotQuery::new(1);
+
+        query.preferred_angle = Some(-PI / 4.0);
+        let up = find_bank_shot(&map, from, to, &query, |_| true).unwrap();
+        assert_eq!(up.bounces(), 1);
+        assert!(up.angle < 0.0);
+        assert!(up.points[1].y < 3.0);
+
+        query.preferred_angle = Some(PI / 4.0);
+        let down = find_bank_shot(&map, from, to, &query, |_| true).unwrap();
+        assert_eq!(down.bounces(), 1);
+        assert!(down.angle > 0.0);
+        assert!(down.points[1].y > 4.0);
+    }
+}
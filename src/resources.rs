@@ -1,9 +1,16 @@
 use std::os;
 
+use gfx;
 use image;
 use image::DynamicImage;
 
 use map::Map;
+use ai::AiConfig;
+use appearance::TileAppearance;
+use atlas::{TextureAtlas, DEFAULT_PADDING};
+use shaders::ShaderProgram;
+use texture::TextureOptions;
+use campaign::Campaign;
 use errors::Res;
 
 
@@ -48,7 +55,32 @@ impl ResourceLoader {
         Ok(try!(image::open(&self.get_filename("images", name))))
     }
 
+    /// Packs the images in a folder of the images into an atlas.
+    pub fn load_atlas(&self, device: &mut gfx::GlDevice, name: &str,
+                      options: TextureOptions) -> Res<TextureAtlas> {
+        TextureAtlas::from_directory(device, &self.get_filename("images", name),
+                                     DEFAULT_PADDING, options)
+    }
+
+    /// Loads and links a vertex and a fragment shader.
+    pub fn load_program(&self, device: &mut gfx::GlDevice, vertex: &str,
+                        fragment: &str) -> Res<ShaderProgram> {
+        ShaderProgram::load(device, &self.root.join("shaders"), vertex, fragment)
+    }
+
     pub fn load_map(&self, name: &str) -> Res<Map> {
         Map::open(&self.get_filename("maps", name))
     }
+
+    pub fn load_campaign(&self, name: &str) -> Res<Campaign> {
+        Campaign::open(&self.get_filename("maps", name))
+    }
+
+    pub fn load_ai_config(&self, name: &str) -> Res<AiConfig> {
+        AiConfig::open(&self.get_filename("config", name))
+    }
+
+    pub fn load_tile_appearance(&self, name: &str) -> Res<TileAppearance> {
+        TileAppearance::open(&self.get_filename("config", name))
+    }
 }
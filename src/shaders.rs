@@ -0,0 +1,307 @@
+use std::io;
+use std::io::fs;
+use std::mem;
+
+use gfx;
+use gfx::Device;
+use render;
+
+use errors::{Res, GameError};
+
+
+/// Where a line of a preprocessed shader came from.
+#[deriving(Clone, Show)]
+struct SourceLine {
+    /// index into the files of the source
+    file: uint,
+    /// the line in that file, starting at 1
+    line: uint,
+}
+
+/// A shader with all of its includes resolved.  It remembers which file
+/// and line every line of the result came from, so that errors of the
+/// GLSL compiler can be reported against the files as they are on disk.
+pub struct ShaderSource {
+    text: String,
+    files: Vec<Path>,
+    lines: Vec<SourceLine>,
+}
+
+impl ShaderSource {
+
+    /// Loads a shader from a folder of shaders.  A line like
+    /// `#include "lighting.glsl"` is replaced with the contents of that
+    /// file from the same folder.  Every file is included only once, so
+    /// shared chunks can include what they need.
+    pub fn load(root: &Path, name: &str) -> Res<ShaderSource> {
+        let mut rv = ShaderSource {
+            text: String::new(),
+            files: vec![],
+            lines: vec![],
+        };
+        try!(rv.add_file(root, name));
+        Ok(rv)
+    }
+
+    fn add_file(&mut self, root: &Path, name: &str) -> Res<()> {
+        let path = root.join(name);
+        if self.files.contains(&path) {
+            return Ok(());
+        }
+        let mut reader = try!(io::File::open(&path));
+        let contents = try!(reader.read_to_string());
+        let file = self.files.len();
+        self.files.push(path);
+
+        for (idx, line) in contents.as_slice().lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.starts_with("#include") {
+                let include = trimmed.slice_from(8).trim();
+                if include.len() < 2 || !include.starts_with("\"") || !include.ends_with("\"") {
+                    return Err(GameError::InvalidConfig("Malformed shader include"));
+                }
+                try!(self.add_file(root, include.slice(1, include.len() - 1)));
+                continue;
+            }
+            self.text.push_str(line);
+            self.text.push('\n');
+            self.lines.push(SourceLine { file: file, line: idx + 1 });
+        }
+        Ok(())
+    }
+
+    /// the preprocessed source
+    pub fn text(&self) -> &str {
+        self.text.as_slice()
+    }
+
+    /// the shader file and every file it includes
+    pub fn files(&self) -> &[Path] {
+        self.files.as_slice()
+    }
+
+    /// the file and line a line of the preprocessed source came from
+    pub fn map_line(&self, line: uint) -> Option<(&Path, uint)> {
+        if line == 0 || line > self.lines.len() {
+            return None;
+        }
+        let source = &self.lines[line - 1];
+        Some((&self.files[source.file], source.line))
+    }
+
+    /// Rewrites the line references in a log of the GLSL compiler to
+    /// point to the files the lines came from.  Drivers write them as
+    /// `0:12` or `0(12)`, both are understood.
+    pub fn map_log(&self, log: &str) -> String {
+        let mut rv = String::new();
+        let bytes = log.as_bytes();
+        let (mut idx, mut copied) = (0, 0);
+        while idx < bytes.len() {
+            let starts_ref = bytes[idx] == b'0' &&
+                (idx == 0 || !(bytes[idx - 1] as char).is_digit(10)) &&
+                idx + 2 < bytes.len() &&
+                (bytes[idx + 1] == b':' || bytes[idx + 1] == b'(') &&
+                (bytes[idx + 2] as char).is_digit(10);
+            if starts_ref {
+                let mut end = idx + 2;
+                while end < bytes.len() && (bytes[end] as char).is_digit(10) {
+                    end += 1;
+                }
+                let line = from_str(log.slice(idx + 2, end)).unwrap_or(0);
+                if let Some((path, line)) = self.map_line(line) {
+                    rv.push_str(log.slice(copied, idx));
+                    rv.push_str(format!("{}:{}", path.display(), line).as_slice());
+                    // skip the closing parenthesis of `0(12)`
+                    if bytes[idx + 1] == b'(' && end < bytes.len() && bytes[end] == b')' {
+                        end += 1;
+                    }
+                    idx = end;
+                    copied = end;
+                    continue;
+                }
+            }
+            idx += 1;
+        }
+        rv.push_str(log.slice_from(copied));
+        rv
+    }
+}
+
+/// when a file was last modified, `None` if it cannot be read
+fn modified(path: &Path) -> Option<u64> {
+    fs::stat(path).ok().map(|x| x.modified)
+}
+
+/// A vertex and fragment shader linked into a program.  The program
+/// knows the files it was made of and can be reloaded when they change.
+pub struct ShaderProgram {
+    root: Path,
+    vertex: String,
+    fragment: String,
+    handle: gfx::ProgramHandle,
+    files: Vec<(Path, Option<u64>)>,
+}
+
+/// Compiles and links the shaders.  Compiler errors are reported with
+/// the line references mapped to the shader files.
+fn link(device: &mut gfx::GlDevice, vertex: &ShaderSource,
+        fragment: &ShaderSource) -> Res<gfx::ProgramHandle> {
+    let vs = match device.create_shader(gfx::shade::Stage::Vertex,
+                                        vertex.text().as_bytes()) {
+        Ok(vs) => vs,
+        Err(err) => {
+            let log = vertex.map_log(err.to_string().as_slice());
+            return Err(GameError::ProgramError(render::ProgramError::Vertex(err), Some(log)));
+        }
+    };
+    let fs = match device.create_shader(gfx::shade::Stage::Fragment,
+                                        fragment.text().as_bytes()) {
+        Ok(fs) => fs,
+        Err(err) => {
+            let log = fragment.map_log(err.to_string().as_slice());
+            return Err(GameError::ProgramError(render::ProgramError::Fragment(err), Some(log)));
+        }
+    };
+    match device.create_program(&[vs, fs]) {
+        Ok(program) => Ok(program),
+        Err(err) => Err(GameError::ProgramError(render::ProgramError::Link(err), None)),
+    }
+}
+
+impl ShaderProgram {
+
+    /// Loads a program from two files in a folder of shaders.
+    pub fn load(device: &mut gfx::GlDevice, root: &Path, vertex: &str,
+                fragment: &str) -> Res<ShaderProgram> {
+        let vertex_source = try!(ShaderSource::load(root, vertex));
+        let fragment_source = try!(ShaderSource::load(root, fragment));
+        let handle = try!(link(device, &vertex_source, &fragment_source));
+        let files = vertex_source.files().iter()
+            .chain(fragment_source.files().iter())
+            .map(|path| (path.clone(), modified(path)))
+            .collect();
+        Ok(ShaderProgram {
+            root: root.clone(),
+            vertex: vertex.to_string(),
+            fragment: fragment.to_string(),
+            handle: handle,
+            files: files,
+        })
+    }
+
+    pub fn handle(&self) -> &gfx::ProgramHandle {
+        &self.handle
+    }
+
+    /// true if any of the files the program was made of changed since
+    /// it was loaded
+    pub fn is_stale(&self) -> bool {
+        self.files.iter().any(|&(ref path, mtime)| modified(path) != mtime)
+    }
+
+    /// Loads the program again if its files changed.  Returns true if it
+    /// was reloaded, the old program is deleted then.  If the new version
+    /// fails to compile the error is returned and the old program is
+    /// kept, it is not retried until the files change again.
+    pub fn reload_if_stale(&mut self, device: &mut gfx::GlDevice) -> Res<bool> {
+        if !self.is_stale() {
+            return Ok(false);
+        }
+        for &mut (ref path, ref mut mtime) in self.files.iter_mut() {
+            *mtime = modified(path);
+        }
+        let program = try!(ShaderProgram::load(
+            device, &self.root, self.vertex.as_slice(), self.fragment.as_slice()));
+        let old = mem::replace(self, program);
+        device.delete_program(old.handle);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::TempDir;
+
+    use errors::GameError;
+    use super::ShaderSource;
+
+    fn write(root: &Path, name: &str, contents: &str) {
+        io::File::create(&root.join(name)).unwrap().write_str(contents).unwrap();
+    }
+
+    /// a shader that includes lighting which includes common
+    fn nested() -> TempDir {
+        let dir = TempDir::new("tanks").unwrap();
+        write(dir.path(), "main.glsl",
+              "#version 150\n#include \"lighting.glsl\"\nvoid main() {}\n");
+        write(dir.path(), "lighting.glsl",
+              "#include \"common.glsl\"\nfloat light() { return 1.0; }\n");
+        write(dir.path(), "common.glsl", "const float PI = 3.14;\n");
+        dir
+    }
+
+    #[test]
+    fn nested_includes_are_resolved() {
+        let dir = nested();
+        let root = dir.path();
+        let source = ShaderSource::load(root, "main.glsl").unwrap();
+        assert_eq!(source.text(), "#version 150\nconst float PI = 3.14;\n\
+                                   float light() { return 1.0; }\nvoid main() {}\n");
+        assert_eq!(source.files(), [root.join("main.glsl"), root.join("lighting.glsl"),
+                                    root.join("common.glsl")].as_slice());
+        assert_eq!(source.map_line(0), None);
+        assert_eq!(source.map_line(1), Some((&root.join("main.glsl"), 1)));
+        assert_eq!(source.map_line(2), Some((&root.join("common.glsl"), 1)));
+        assert_eq!(source.map_line(3), Some((&root.join("lighting.glsl"), 2)));
+        assert_eq!(source.map_line(4), Some((&root.join("main.glsl"), 3)));
+        assert_eq!(source.map_line(5), None);
+    }
+
+    #[test]
+    fn files_are_included_once() {
+        let dir = nested();
+        let root = dir.path();
+        write(root, "twice.glsl", "#include \"common.glsl\"\n\
+                                   #include \"lighting.glsl\"\n\
+                                   #include \"common.glsl\"\nvoid main() {}\n");
+        let source = ShaderSource::load(root, "twice.glsl").unwrap();
+        assert_eq!(source.text(), "const float PI = 3.14;\n\
+                                   float light() { return 1.0; }\nvoid main() {}\n");
+        assert_eq!(source.files().len(), 3);
+    }
+
+    #[test]
+    fn malformed_includes_fail() {
+        let dir = TempDir::new("tanks").unwrap();
+        let root = dir.path();
+        write(root, "common.glsl", "const float PI = 3.14;\n");
+        for (idx, line) in ["#include common.glsl", "#include \"common.glsl",
+                            "#include \"", "#include"].iter().enumerate() {
+            let name = format!("broken{}.glsl", idx);
+            write(root, name.as_slice(), *line);
+            let err = ShaderSource::load(root, name.as_slice()).err().unwrap();
+            assert!(match err {
+                GameError::InvalidConfig(msg) => msg == "Malformed shader include",
+                _ => false,
+            });
+        }
+        assert!(ShaderSource::load(root, "missing.glsl").is_err());
+    }
+
+    #[test]
+    fn log_lines_point_to_the_files() {
+        let dir = nested();
+        let root = dir.path();
+        let source = ShaderSource::load(root, "main.glsl").unwrap();
+        let main = root.join("main.glsl");
+        let lighting = root.join("lighting.glsl");
+        let log = source.map_log("ERROR: 0:4: 'x' : undeclared identifier\n\
+                                  0(3) : error C1008: undefined variable\n\
+                                  ERROR: 0:9: out of range, 10:2 is not a reference\n");
+        assert_eq!(log, format!("ERROR: {}:3: 'x' : undeclared identifier\n\
+                                 {}:2 : error C1008: undefined variable\n\
+                                 ERROR: 0:9: out of range, 10:2 is not a reference\n",
+                                main.display(), lighting.display()));
+    }
+}
@@ -0,0 +1,106 @@
+use std::num::Float;
+
+use collision;
+use geom::{Vec2, normalize_angle};
+use map::{Map, Tile, EnemyType};
+
+/// the radius of a tank's collision circle in tiles
+pub static TANK_RADIUS : f32 = 0.35;
+
+
+/// The tuning values shared by all tanks, players and enemies alike.
+/// These are part of the enemy config.
+#[deriving(Decodable, Copy, Clone, Show)]
+pub struct TankConfig {
+    /// how much of its speed a tank keeps in a swamp
+    pub swamp_factor: f32,
+}
+
+/// Who controls a tank.
+#[deriving(PartialEq, Copy, Clone, Show)]
+pub enum TankKind {
+    Player(u8),
+    Enemy(EnemyType),
+}
+
+#[deriving(Clone, Show)]
+pub struct Tank {
+    pub id: uint,
+    pub kind: TankKind,
+    pub pos: Vec2,
+    /// the direction the hull is facing in radians
+    pub heading: f32,
+    /// the direction the turret is facing in radians
+    pub turret_heading: f32,
+    /// top speed in tiles per second
+    pub speed: f32,
+    /// how fast the hull turns in radians per second
+    pub turn_rate: f32,
+    /// how fast the turret turns in radians per second
+    pub turret_turn_rate: f32,
+    /// the radius of the tank's collision circle in tiles
+    pub radius: f32,
+    /// the fraction of the speed that is left when driving in a swamp
+    pub swamp_factor: f32,
+    pub fire_cooldown: f32,
+    pub alive: bool,
+}
+
+impl Tank {
+
+    pub fn new(id: uint, kind: TankKind, pos: Vec2, config: &TankConfig) -> Tank {
+        Tank {
+            id: id,
+            kind: kind,
+            pos: pos,
+            heading: 0.0,
+            turret_heading: 0.0,
+            speed: 2.0,
+            turn_rate: 2.5,
+            turret_turn_rate: 3.0,
+            radius: TANK_RADIUS,
+            swamp_factor: config.swamp_factor,
+            fire_cooldown: 0.0,
+            alive: true,
+        }
+    }
+
+    pub fn is_player(&self) -> bool {
+        match self.kind {
+            TankKind::Player(_) => true,
+            TankKind::Enemy(_) => false,
+        }
+    }
+
+    /// the direction the hull is facing as unit vector
+    pub fn forward(&self) -> Vec2 {
+        Vec2::from_angle(self.heading)
+    }
+
+    /// Turns the hull by `turn` (-1.0 to 1.0) and drives by `forward`
+    /// (-1.0 to 1.0) for `dt` seconds.  Walls, out of bounds tiles,
+    /// holes and water stop the tank, it slides along them instead of
+    /// sticking.  Swamps slow it down.
+    pub fn drive(&mut self, map: &Map, forward: f32, turn: f32, dt: f32) {
+        let forward = forward.max(-1.0).min(1.0);
+        let turn = turn.max(-1.0).min(1.0);
+        self.heading = normalize_angle(self.heading + turn * self.turn_rate * dt);
+        let ground = map.get_tile_checked(self.pos.x.floor() as i32,
+                                          self.pos.y.floor() as i32);
+        let speed = if ground.slows_tank() {
+            self.speed * self.swamp_factor
+        } else {
+            self.speed
+        };
+        let delta = self.forward().scale(forward * speed * dt);
+        self.pos = collision::move_circle(map, self.pos, delta, self.radius,
+                                          Tile::blocks_tank);
+    }
+
+    /// Turns the turret by `turn` (-1.0 to 1.0) for `dt` seconds.
+    pub fn turn_turret(&mut self, turn: f32, dt: f32) {
+        let turn = turn.max(-1.0).min(1.0);
+        self.turret_heading = normalize_angle(
+            self.turret_heading + turn * self.turret_turn_rate * dt);
+    }
+}
@@ -0,0 +1,22 @@
+//! Helpers shared by the unit tests of several modules.
+
+use image::{GenericImage, ImageBuf, Pixel};
+
+use texture::RgbaImage;
+
+/// an opaque image of a single color
+pub fn solid(width: u32, height: u32, color: (u8, u8, u8)) -> RgbaImage {
+    let (r, g, b) = color;
+    let mut rv : RgbaImage = ImageBuf::new(width, height);
+    for y in range(0, height) {
+        for x in range(0, width) {
+            rv.put_pixel(x, y, Pixel::from_channels(r, g, b, 255));
+        }
+    }
+    rv
+}
+
+/// the channels of a pixel, comparable with `assert_eq!`
+pub fn pixel(image: &RgbaImage, x: u32, y: u32) -> (u8, u8, u8, u8) {
+    image.get_pixel(x, y).channels4()
+}
@@ -1,9 +1,84 @@
 use gfx;
 use gfx::Device;
 
-use image::{DynamicImage, GenericImage};
+use image::{DynamicImage, GenericImage, ImageBuf, Rgba};
 
 use errors::Res;
+use mipmap::{generate_mips, mip_count};
+
+pub type RgbaImage = ImageBuf<Rgba<u8>>;
+
+
+/// How a texture is filtered when it is drawn smaller or larger than
+/// it is.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum Filtering {
+    /// the closest pixel, no mipmaps
+    Nearest,
+    /// blends the closest pixels, no mipmaps
+    Bilinear,
+    /// blends the closest pixels of the two closest mip levels
+    Trilinear,
+    /// like trilinear but takes more samples along surfaces that are
+    /// seen at a steep angle.  The value is the maximum anisotropy.
+    Anisotropic(u8),
+}
+
+impl Filtering {
+
+    /// Looks up a filtering by the name used in config files.
+    /// Anisotropic filtering uses the given maximum anisotropy.
+    pub fn from_name(name: &str, anisotropy: u8) -> Option<Filtering> {
+        match name {
+            "nearest" => Some(Filtering::Nearest),
+            "bilinear" => Some(Filtering::Bilinear),
+            "trilinear" => Some(Filtering::Trilinear),
+            "anisotropic" => Some(Filtering::Anisotropic(anisotropy)),
+            _ => None,
+        }
+    }
+
+    /// true if the filtering reads from mip levels
+    pub fn needs_mips(&self) -> bool {
+        match *self {
+            Filtering::Nearest | Filtering::Bilinear => false,
+            _ => true,
+        }
+    }
+
+    fn to_filter_method(&self) -> gfx::tex::FilterMethod {
+        match *self {
+            Filtering::Nearest => gfx::tex::FilterMethod::Scale,
+            Filtering::Bilinear => gfx::tex::FilterMethod::Bilinear,
+            Filtering::Trilinear => gfx::tex::FilterMethod::Trilinear,
+            Filtering::Anisotropic(x) => gfx::tex::FilterMethod::Anisotropic(x),
+        }
+    }
+}
+
+/// How a texture is sampled.  Every texture gets a sampler made from
+/// its options.
+#[deriving(PartialEq, Copy, Clone, Show)]
+pub struct TextureOptions {
+    pub filtering: Filtering,
+    pub wrap: gfx::tex::WrapMode,
+}
+
+impl TextureOptions {
+
+    /// bilinear filtering and clamping at the edges
+    pub fn new() -> TextureOptions {
+        TextureOptions {
+            filtering: Filtering::Bilinear,
+            wrap: gfx::tex::WrapMode::Clamp,
+        }
+    }
+
+    pub fn create_sampler(&self, device: &mut gfx::GlDevice) -> gfx::SamplerHandle {
+        device.create_sampler(gfx::tex::SamplerInfo::new(
+            self.filtering.to_filter_method(), self.wrap))
+    }
+}
 
 
 pub trait Texture {
@@ -12,6 +87,7 @@ pub trait Texture {
     fn offset_x(&self) -> u16;
     fn offset_y(&self) -> u16;
     fn handle(&self) -> gfx::TextureHandle;
+    fn sampler(&self) -> gfx::SamplerHandle;
     fn info(&self) -> &gfx::tex::TextureInfo;
     fn basic_texture(&self) -> &BasicTexture;
 
@@ -34,14 +110,11 @@ pub trait Texture {
             offset_y: self.offset_y() + y,
         }
     }
-
-    fn safe_slice(&self, x: u16, y: u16, width: u16, height: u16) -> TextureSlice {
-        self.slice(x + 1, y + 1, width - 2, height - 2)
-    }
 }
 
 pub struct BasicTexture {
     handle: gfx::TextureHandle,
+    sampler: gfx::SamplerHandle,
     info: gfx::tex::TextureInfo,
 }
 
@@ -55,37 +128,55 @@ pub struct TextureSlice<'a> {
 
 impl BasicTexture {
 
-    pub fn new(info: gfx::tex::TextureInfo, handle: gfx::TextureHandle) -> BasicTexture {
+    pub fn new(info: gfx::tex::TextureInfo, handle: gfx::TextureHandle,
+               sampler: gfx::SamplerHandle) -> BasicTexture {
         BasicTexture {
             handle: handle,
+            sampler: sampler,
             info: info,
         }
     }
 
     pub fn from_image(device: &mut gfx::GlDevice, image: &DynamicImage) -> Res<BasicTexture> {
+        BasicTexture::from_image_with_options(device, image, TextureOptions::new())
+    }
+
+    /// Uploads an image.  If the filtering needs them the full mip chain
+    /// is generated and uploaded as well.
+    pub fn from_image_with_options(device: &mut gfx::GlDevice, image: &DynamicImage,
+                                   options: TextureOptions) -> Res<BasicTexture> {
         let (width, height) = image.dimensions();
+        let levels = if options.filtering.needs_mips() { mip_count(width, height) } else { 1 };
+        BasicTexture::from_mips(device, generate_mips(image, levels).as_slice(), options)
+    }
+
+    /// Uploads a mip chain.  The first image is the texture itself, every
+    /// following one needs to be half as large as the one before.
+    pub fn from_mips(device: &mut gfx::GlDevice, mips: &[RgbaImage],
+                     options: TextureOptions) -> Res<BasicTexture> {
+        let (width, height) = mips[0].dimensions();
         let texture_info = gfx::tex::TextureInfo {
             width: width as u16,
             height: height as u16,
             depth: 1,
-            levels: 1,
+            levels: mips.len() as u8,
             kind: gfx::tex::TextureKind::Texture2D,
             format: gfx::tex::RGBA8,
         };
-        let image_info = texture_info.to_image_info();
 
         let texture = try!(device.create_texture(texture_info));
 
-        match image.as_rgba8() {
-            Some(buf) => {
-                try!(device.update_texture(&texture, &image_info, buf.rawbuf()));
-            },
-            None => {
-                try!(device.update_texture(&texture, &image_info, image.to_rgba().rawbuf()));
-            }
+        for (level, mip) in mips.iter().enumerate() {
+            let (width, height) = mip.dimensions();
+            let mut image_info = texture_info.to_image_info();
+            image_info.width = width as u16;
+            image_info.height = height as u16;
+            image_info.mipmap = level as u8;
+            try!(device.update_texture(&texture, &image_info, mip.rawbuf()));
         }
 
-        Ok(BasicTexture::new(texture_info, texture))
+        let sampler = options.create_sampler(device);
+        Ok(BasicTexture::new(texture_info, texture, sampler))
     }
 }
 
@@ -95,6 +186,7 @@ impl Texture for BasicTexture {
     fn offset_x(&self) -> u16 { 0 }
     fn offset_y(&self) -> u16 { 0 }
     fn handle(&self) -> gfx::TextureHandle { self.handle }
+    fn sampler(&self) -> gfx::SamplerHandle { self.sampler }
     fn info(&self) -> &gfx::tex::TextureInfo { &self.info }
     fn basic_texture(&self) -> &BasicTexture { self }
 }
@@ -105,6 +197,7 @@ impl<'a> Texture for TextureSlice<'a> {
     fn offset_x(&self) -> u16 { self.offset_x }
     fn offset_y(&self) -> u16 { self.offset_y }
     fn handle(&self) -> gfx::TextureHandle { self.parent.handle() }
+    fn sampler(&self) -> gfx::SamplerHandle { self.parent.sampler() }
     fn info(&self) -> &gfx::tex::TextureInfo { self.parent.info() }
     fn basic_texture(&self) -> &BasicTexture { self.parent }
 }
@@ -0,0 +1,251 @@
+use std::io;
+use serialize::{json, Decodable};
+
+use errors::{Res, GameError};
+use map::{Map, MapData, Tileset, Layer, TileLayer, ObjectLayer, MapObject, ObjectKind};
+use map::DEFAULT_TILE_PIXELS;
+use properties::Properties;
+
+
+fn get_json_str<'a>(obj: &'a json::Json, key: &str) -> &'a str {
+    obj.find(key).and_then(|x| x.as_string()).unwrap_or("")
+}
+
+fn get_json_f64(obj: &json::Json, key: &str) -> Option<f64> {
+    obj.find(key).and_then(|x| x.as_f64())
+}
+
+fn get_json_u32(obj: &json::Json, key: &str, default: u32) -> u32 {
+    get_json_f64(obj, key).map(|x| x as u32).unwrap_or(default)
+}
+
+fn get_json_bool(obj: &json::Json, key: &str) -> bool {
+    obj.find(key).and_then(|x| x.as_boolean()).unwrap_or(true)
+}
+
+/// Reads the objects of a Tiled object layer.  Tiled stores positions in
+/// pixels, these are converted into tiles.
+fn read_objects(layer: &json::Json, tile_width: u32,
+                tile_height: u32) -> Res<Vec<MapObject>> {
+    let objects = unwrap_or!(layer.find("objects").and_then(|x| x.as_array()),
+        return Err(GameError::InvalidMap("Object layer without objects", None)));
+    let mut rv = vec![];
+    for obj in objects.iter() {
+        let name = get_json_str(obj, "name");
+        let type_name = get_json_str(obj, "type");
+        rv.push(MapObject {
+            id: get_json_f64(obj, "id").unwrap_or(0.0) as u32,
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            kind: ObjectKind::from_object(type_name, name),
+            x: (get_json_f64(obj, "x").unwrap_or(0.0) / tile_width as f64) as f32,
+            y: (get_json_f64(obj, "y").unwrap_or(0.0) / tile_height as f64) as f32,
+            width: (get_json_f64(obj, "width").unwrap_or(0.0) / tile_width as f64) as f32,
+            height: (get_json_f64(obj, "height").unwrap_or(0.0) / tile_height as f64) as f32,
+            visible: get_json_bool(obj, "visible"),
+        });
+    }
+    Ok(rv)
+}
+
+/// Reads the tilesets of a Tiled JSON map.  External tilesets only
+/// carry their first gid and file name.
+fn read_tilesets(json: &json::Json) -> Res<Vec<Tileset>> {
+    let tilesets = unwrap_or!(json.find("tilesets").and_then(|x| x.as_array()),
+        return Ok(vec![]));
+    let mut rv = vec![];
+    for tileset in tilesets.iter() {
+        let first_gid = unwrap_or!(get_json_f64(tileset, "firstgid"),
+            return Err(GameError::InvalidMap("Tileset without firstgid", None)));
+        let source = tileset.find("source").and_then(|x| x.as_string());
+        let name = match get_json_str(tileset, "name") {
+            "" => source.unwrap_or(""),
+            name => name,
+        };
+        rv.push(Tileset {
+            name: name.to_string(),
+            first_gid: first_gid as u32,
+            source: source.map(|x| x.to_string()),
+            tile_count: get_json_f64(tileset, "tilecount").map(|x| x as u32),
+            tile_width: get_json_u32(tileset, "tilewidth", DEFAULT_TILE_PIXELS),
+            tile_height: get_json_u32(tileset, "tileheight", DEFAULT_TILE_PIXELS),
+            image: tileset.find("image").and_then(|x| x.as_string())
+                .map(|x| x.to_string()),
+            image_width: get_json_u32(tileset, "imagewidth", 0),
+            image_height: get_json_u32(tileset, "imageheight", 0),
+            margin: get_json_u32(tileset, "margin", 0),
+            spacing: get_json_u32(tileset, "spacing", 0),
+            properties: try!(Properties::from_json(tileset)),
+        });
+    }
+    Ok(rv)
+}
+
+/// Reads a map from the Tiled JSON format.
+pub fn read_map_data(json: &json::Json) -> Res<MapData> {
+    let width = unwrap_or!(get_json_f64(json, "width"),
+        return Err(GameError::InvalidMap("Map without width", None)));
+    let height = unwrap_or!(get_json_f64(json, "height"),
+        return Err(GameError::InvalidMap("Map without height", None)));
+    let tile_width = get_json_u32(json, "tilewidth", DEFAULT_TILE_PIXELS);
+    let tile_height = get_json_u32(json, "tileheight", DEFAULT_TILE_PIXELS);
+    let layers = unwrap_or!(json.find("layers").and_then(|x| x.as_array()),
+        return Err(GameError::InvalidMap("Map without layers", None)));
+
+    let mut rv = vec![];
+    for layer in layers.iter() {
+        let name = get_json_str(layer, "name").to_string();
+        let visible = get_json_bool(layer, "visible");
+        match get_json_str(layer, "type") {
+            "tilelayer" => {
+                let data = unwrap_or!(layer.find("data"),
+                    return Err(GameError::InvalidMap("Tile layer without data", None)));
+                let mut decoder = json::Decoder::new(data.clone());
+                rv.push(Layer::Tiles(TileLayer {
+                    name: name,
+                    visible: visible,
+                    data: try!(Decodable::decode(&mut decoder)),
+                }));
+            }
+            "objectgroup" => {
+                rv.push(Layer::Objects(ObjectLayer {
+                    name: name,
+                    visible: visible,
+                    objects: try!(read_objects(layer, tile_width, tile_height)),
+                }));
+            }
+            // image layers and whatever else Tiled comes up with are of
+            // no interest to the game.
+            _ => {}
+        }
+    }
+
+    Ok(MapData {
+        width: width as u16,
+        height: height as u16,
+        tile_width: tile_width,
+        tile_height: tile_height,
+        tilesets: try!(read_tilesets(json)),
+        layers: rv,
+        properties: try!(Properties::from_json(json)),
+    })
+}
+
+/// Opens a map exported by Tiled as JSON.
+pub fn open(path: &Path) -> Res<Map> {
+    let mut file = try!(io::File::open(path));
+    let json = try!(json::from_reader(&mut file));
+    Map::from_map_data(try!(read_map_data(&json)))
+}
+
+fn make_object(items: Vec<(&str, json::Json)>) -> json::Json {
+    let mut rv = json::JsonObject::new();
+    for (key, value) in items.into_iter() {
+        rv.insert(key.to_string(), value);
+    }
+    json::Json::Object(rv)
+}
+
+fn make_u32(value: u32) -> json::Json {
+    json::Json::U64(value as u64)
+}
+
+fn make_str(value: &str) -> json::Json {
+    json::Json::String(value.to_string())
+}
+
+fn write_tileset(tileset: &Tileset) -> json::Json {
+    if let Some(ref source) = tileset.source {
+        return make_object(vec![
+            ("firstgid", make_u32(tileset.first_gid)),
+            ("source", make_str(source.as_slice())),
+        ]);
+    }
+    let mut items = vec![
+        ("firstgid", make_u32(tileset.first_gid)),
+        ("name", make_str(tileset.name.as_slice())),
+        ("tilewidth", make_u32(tileset.tile_width)),
+        ("tileheight", make_u32(tileset.tile_height)),
+        ("imagewidth", make_u32(tileset.image_width)),
+        ("imageheight", make_u32(tileset.image_height)),
+        ("margin", make_u32(tileset.margin)),
+        ("spacing", make_u32(tileset.spacing)),
+        ("properties", tileset.properties.to_json()),
+    ];
+    if let Some(ref image) = tileset.image {
+        items.push(("image", make_str(image.as_slice())));
+    }
+    if let Some(count) = tileset.tile_count {
+        items.push(("tilecount", make_u32(count)));
+    }
+    make_object(items)
+}
+
+fn write_objects(objects: &[MapObject], tile_width: u32, tile_height: u32) -> json::Json {
+    json::Json::Array(objects.iter().map(|obj| make_object(vec![
+        ("id", make_u32(obj.id)),
+        ("name", make_str(obj.name.as_slice())),
+        ("type", make_str(obj.type_name.as_slice())),
+        ("x", json::Json::F64(obj.x as f64 * tile_width as f64)),
+        ("y", json::Json::F64(obj.y as f64 * tile_height as f64)),
+        ("width", json::Json::F64(obj.width as f64 * tile_width as f64)),
+        ("height", json::Json::F64(obj.height as f64 * tile_height as f64)),
+        ("rotation", make_u32(0)),
+        ("visible", json::Json::Boolean(obj.visible)),
+    ])).collect())
+}
+
+fn write_layer(map: &Map, layer: &Layer) -> json::Json {
+    let (tile_width, tile_height) = map.tile_pixels();
+    match *layer {
+        Layer::Tiles(ref layer) => make_object(vec![
+            ("type", make_str("tilelayer")),
+            ("name", make_str(layer.name.as_slice())),
+            ("width", make_u32(map.width() as u32)),
+            ("height", make_u32(map.height() as u32)),
+            ("x", make_u32(0)),
+            ("y", make_u32(0)),
+            ("opacity", make_u32(1)),
+            ("visible", json::Json::Boolean(layer.visible)),
+            ("data", json::Json::Array(
+                layer.data.iter().map(|&gid| make_u32(gid)).collect())),
+        ]),
+        Layer::Objects(ref layer) => make_object(vec![
+            ("type", make_str("objectgroup")),
+            ("name", make_str(layer.name.as_slice())),
+            ("draworder", make_str("topdown")),
+            ("x", make_u32(0)),
+            ("y", make_u32(0)),
+            ("opacity", make_u32(1)),
+            ("visible", json::Json::Boolean(layer.visible)),
+            ("objects", write_objects(layer.objects.as_slice(),
+                                      tile_width, tile_height)),
+        ]),
+    }
+}
+
+/// Converts a map into Tiled's JSON format.
+pub fn write_map(map: &Map) -> json::Json {
+    let (tile_width, tile_height) = map.tile_pixels();
+    make_object(vec![
+        ("version", make_u32(1)),
+        ("orientation", make_str("orthogonal")),
+        ("renderorder", make_str("right-down")),
+        ("width", make_u32(map.width() as u32)),
+        ("height", make_u32(map.height() as u32)),
+        ("tilewidth", make_u32(tile_width)),
+        ("tileheight", make_u32(tile_height)),
+        ("properties", map.properties().all.to_json()),
+        ("tilesets", json::Json::Array(
+            map.tilesets().iter().map(write_tileset).collect())),
+        ("layers", json::Json::Array(
+            map.layers().iter().map(|layer| write_layer(map, layer)).collect())),
+    ])
+}
+
+/// Saves a map as Tiled JSON.
+pub fn save(map: &Map, path: &Path) -> Res<()> {
+    let mut file = try!(io::File::create(path));
+    try!(file.write_str(write_map(map).to_pretty_str().as_slice()));
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use std::num::Float;
+
+/// the default number of simulation ticks per second
+pub static DEFAULT_TICK_RATE : u32 = 60;
+/// the default number of ticks that may be simulated in a single frame
+pub static DEFAULT_MAX_TICKS_PER_FRAME : uint = 8;
+
+
+/// Accumulates real time and converts it into a whole number of fixed
+/// length simulation ticks.  Whatever is left over is exposed as an
+/// interpolation alpha so the renderer can blend between the last two
+/// simulated states.
+///
+/// If a frame takes so long that more than `max_ticks_per_frame` ticks
+/// would have to be simulated, the excess time is dropped instead of
+/// carried over.  Otherwise a slow machine would fall further behind
+/// with every frame.
+pub struct FixedTimestep {
+    tick_duration: f64,
+    max_ticks_per_frame: uint,
+    accumulator: f64,
+    ticks: u64,
+    dropped_ticks: u64,
+}
+
+impl FixedTimestep {
+
+    pub fn new(tick_rate: u32) -> FixedTimestep {
+        assert!(tick_rate > 0, "tick rate must be positive");
+        FixedTimestep {
+            tick_duration: 1.0 / tick_rate as f64,
+            max_ticks_per_frame: DEFAULT_MAX_TICKS_PER_FRAME,
+            accumulator: 0.0,
+            ticks: 0,
+            dropped_ticks: 0,
+        }
+    }
+
+    pub fn set_max_ticks_per_frame(&mut self, value: uint) {
+        self.max_ticks_per_frame = value;
+    }
+
+    /// the length of a single tick in seconds
+    #[inline(always)]
+    pub fn dt(&self) -> f32 {
+        self.tick_duration as f32
+    }
+
+    /// the total number of ticks handed out so far
+    #[inline(always)]
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// the number of ticks that were skipped to catch up
+    #[inline(always)]
+    pub fn dropped_ticks(&self) -> u64 {
+        self.dropped_ticks
+    }
+
+    /// How far the current frame is between the previous and the next
+    /// tick, from 0.0 to 1.0.
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.tick_duration) as f32
+    }
+
+    /// Feeds `elapsed` seconds of real time into the accumulator and
+    /// returns how many ticks the caller has to simulate now.
+    pub fn advance(&mut self, elapsed: f64) -> uint {
+        self.accumulator += elapsed.max(0.0);
+        let mut count = (self.accumulator / self.tick_duration).floor() as uint;
+        self.accumulator -= count as f64 * self.tick_duration;
+
+        if count > self.max_ticks_per_frame {
+            self.dropped_ticks += (count - self.max_ticks_per_frame) as u64;
+            count = self.max_ticks_per_frame;
+        }
+
+        self.ticks += count as u64;
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixedTimestep, DEFAULT_MAX_TICKS_PER_FRAME};
+
+    #[test]
+    fn fixed_deltas_give_fixed_ticks() {
+        let mut timestep = FixedTimestep::new(4);
+        let deltas = [0.125, 0.125, 0.5, 0.0625, 0.1875, 0.0, 0.75];
+        let ticks : Vec<uint> = deltas.iter().map(|&x| timestep.advance(x)).collect();
+        assert_eq!(ticks, vec![0, 1, 2, 0, 1, 0, 3]);
+        assert_eq!(timestep.ticks(), 7);
+        assert_eq!(timestep.dropped_ticks(), 0);
+        assert_eq!(timestep.alpha(), 0.0);
+    }
+
+    #[test]
+    fn uneven_deltas_are_deterministic() {
+        let deltas = [0.016, 0.017, 0.033, 0.001, 0.05, 0.016, 0.02, 0.1];
+        let run = || {
+            let mut timestep = FixedTimestep::new(60);
+            let ticks : Vec<uint> = deltas.iter().map(|&x| timestep.advance(x)).collect();
+            (ticks, timestep.ticks())
+        };
+        let (first, total) = run();
+        let (second, _) = run();
+        assert_eq!(first, second);
+        // 0.253 seconds at 60 ticks per second
+        assert_eq!(total, 15);
+    }
+
+    #[test]
+    fn alpha_is_the_leftover_fraction() {
+        let mut timestep = FixedTimestep::new(4);
+        assert_eq!(timestep.advance(0.375), 1);
+        assert_eq!(timestep.alpha(), 0.5);
+    }
+
+    #[test]
+    fn long_frames_are_clamped() {
+        let mut timestep = FixedTimestep::new(4);
+        assert_eq!(timestep.advance(10.0), DEFAULT_MAX_TICKS_PER_FRAME);
+        assert_eq!(timestep.dropped_ticks(), 40 - DEFAULT_MAX_TICKS_PER_FRAME as u64);
+        assert_eq!(timestep.alpha(), 0.0);
+        // the dropped time is not carried over into the next frame
+        assert_eq!(timestep.advance(0.25), 1);
+        assert_eq!(timestep.ticks(), DEFAULT_MAX_TICKS_PER_FRAME as u64 + 1);
+
+        timestep.set_max_ticks_per_frame(2);
+        assert_eq!(timestep.advance(1.0), 2);
+        assert_eq!(timestep.dropped_ticks(), 40 - DEFAULT_MAX_TICKS_PER_FRAME as u64 + 2);
+    }
+
+    #[test]
+    fn negative_time_is_ignored() {
+        let mut timestep = FixedTimestep::new(4);
+        assert_eq!(timestep.advance(-1.0), 0);
+        assert_eq!(timestep.advance(0.25), 1);
+    }
+}
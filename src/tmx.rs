@@ -0,0 +1,552 @@
+use std::io;
+use std::str::FromStr;
+use serialize::base64::FromBase64;
+
+use flate;
+use xml::reader::EventReader;
+use xml::reader::events::XmlEvent;
+
+use errors::{Res, GameError};
+use map::{Map, MapData, Tileset, Layer, TileLayer, ObjectLayer, MapObject, ObjectKind};
+use map::DEFAULT_TILE_PIXELS;
+use properties::{Properties, PropertyValue};
+
+static GZIP_FLAG_HCRC : u8 = 0x02;
+static GZIP_FLAG_EXTRA : u8 = 0x04;
+static GZIP_FLAG_NAME : u8 = 0x08;
+static GZIP_FLAG_COMMENT : u8 = 0x10;
+
+
+/// A parsed XML element.  Maps are small, so the whole document is read
+/// into a tree before it is looked at.
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+
+    pub fn get_attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|&&(ref key, _)| key.as_slice() == name)
+            .map(|&(_, ref value)| value.as_slice())
+    }
+
+    /// parses an attribute, fails if it is missing or malformed
+    pub fn parse_attr<T: FromStr>(&self, name: &str) -> Res<T> {
+        let value = unwrap_or!(self.get_attr(name),
+            return Err(GameError::InvalidMap("Missing attribute in map", None)));
+        match from_str(value.trim()) {
+            Some(rv) => Ok(rv),
+            None => Err(GameError::InvalidMap("Malformed attribute in map", None)),
+        }
+    }
+
+    /// parses an optional attribute, `default` is used if it is missing
+    pub fn parse_attr_or<T: FromStr>(&self, name: &str, default: T) -> Res<T> {
+        match self.get_attr(name) {
+            Some(_) => self.parse_attr(name),
+            None => Ok(default),
+        }
+    }
+
+    fn is_visible(&self) -> Res<bool> {
+        Ok(try!(self.parse_attr_or("visible", 1u8)) != 0)
+    }
+
+    pub fn find_child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name.as_slice() == name)
+    }
+
+    pub fn children_named(&self, name: &str) -> Vec<&Element> {
+        self.children.iter().filter(|c| c.name.as_slice() == name).collect()
+    }
+}
+
+/// Reads an XML document into a tree of elements.
+pub fn parse_xml<B: Buffer>(reader: B) -> Res<Element> {
+    let mut parser = EventReader::new(reader);
+    let mut stack : Vec<Element> = vec![];
+
+    for event in parser.events() {
+        match event {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                stack.push(Element {
+                    name: name.local_name,
+                    attributes: attributes.into_iter()
+                        .map(|attr| (attr.name.local_name, attr.value))
+                        .collect(),
+                    children: vec![],
+                    text: String::new(),
+                });
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = unwrap_or!(stack.pop(),
+                    return Err(GameError::InvalidMap("Malformed XML", None)));
+                match stack.last_mut() {
+                    Some(parent) => { parent.children.push(element); }
+                    None => { return Ok(element); }
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(text.as_slice());
+                }
+            }
+            XmlEvent::Error(_) => {
+                return Err(GameError::InvalidMap("Malformed XML", None));
+            }
+            _ => {}
+        }
+    }
+
+    Err(GameError::InvalidMap("Unexpected end of XML", None))
+}
+
+/// Strips the gzip header and trailer and inflates the payload.
+fn gunzip(bytes: &[u8]) -> Res<Vec<u8>> {
+    if bytes.len() < 18 || bytes[0] != 0x1f || bytes[1] != 0x8b || bytes[2] != 8 {
+        return Err(GameError::InvalidMap("Invalid gzip data in map", None));
+    }
+    let flags = bytes[3];
+    let mut pos = 10u;
+
+    if flags & GZIP_FLAG_EXTRA != 0 {
+        if pos + 2 > bytes.len() {
+            return Err(GameError::InvalidMap("Invalid gzip data in map", None));
+        }
+        pos += 2 + (bytes[pos] as uint | (bytes[pos + 1] as uint << 8));
+    }
+    for &flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT].iter() {
+        if flags & flag != 0 {
+            while pos < bytes.len() && bytes[pos] != 0 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+    }
+    if flags & GZIP_FLAG_HCRC != 0 {
+        pos += 2;
+    }
+    if pos + 8 > bytes.len() {
+        return Err(GameError::InvalidMap("Invalid gzip data in map", None));
+    }
+
+    match flate::inflate_bytes(bytes.slice(pos, bytes.len() - 8)) {
+        Some(data) => Ok(data.as_slice().to_vec()),
+        None => Err(GameError::InvalidMap("Invalid gzip data in map", None)),
+    }
+}
+
+/// Decodes base64 layer data with the given compression.
+fn decode_base64(text: &str, compression: Option<&str>) -> Res<Vec<u32>> {
+    let raw = match text.trim().from_base64() {
+        Ok(raw) => raw,
+        Err(_) => { return Err(GameError::InvalidMap("Invalid base64 data in map", None)); }
+    };
+    let bytes = match compression {
+        None => raw,
+        Some("zlib") => match flate::inflate_bytes_zlib(raw.as_slice()) {
+            Some(data) => data.as_slice().to_vec(),
+            None => { return Err(GameError::InvalidMap("Invalid zlib data in map", None)); }
+        },
+        Some("gzip") => try!(gunzip(raw.as_slice())),
+        Some(_) => { return Err(GameError::InvalidMap("Unsupported compression in map", None)); }
+    };
+
+    if bytes.len() % 4 != 0 {
+        return Err(GameError::InvalidMap("Truncated layer data in map", None));
+    }
+    Ok(bytes.as_slice().chunks(4).map(|c| {
+        c[0] as u32 | (c[1] as u32 << 8) | (c[2] as u32 << 16) | (c[3] as u32 << 24)
+    }).collect())
+}
+
+/// Decodes the gids of a `<data>` element in any of the encodings
+/// Tiled supports: plain XML, CSV and base64 with optional compression.
+pub fn decode_layer_data(data: &Element) -> Res<Vec<u32>> {
+    match data.get_attr("encoding") {
+        None => {
+            let mut rv = vec![];
+            for tile in data.children_named("tile").iter() {
+                rv.push(match tile.get_attr("gid") {
+                    Some(_) => try!(tile.parse_attr("gid")),
+                    None => 0,
+                });
+            }
+            Ok(rv)
+        }
+        Some("csv") => {
+            let mut rv = vec![];
+            for item in data.text.as_slice().split(',') {
+                let item = item.trim();
+                if item.is_empty() {
+                    continue;
+                }
+                rv.push(unwrap_or!(from_str(item),
+                    return Err(GameError::InvalidMap("Invalid CSV data in map", None))));
+            }
+            Ok(rv)
+        }
+        Some("base64") => decode_base64(data.text.as_slice(),
+                                        data.get_attr("compression")),
+        Some(_) => Err(GameError::InvalidMap("Unsupported encoding in map", None)),
+    }
+}
+
+fn read_tile_layer(layer: &Element) -> Res<TileLayer> {
+    let data = unwrap_or!(layer.find_child("data"),
+        return Err(GameError::InvalidMap("Layer without data", None)));
+    Ok(TileLayer {
+        name: layer.get_attr("name").unwrap_or("").to_string(),
+        visible: try!(layer.is_visible()),
+        data: try!(decode_layer_data(data)),
+    })
+}
+
+/// Reads the `<properties>` of an element.  Long strings are stored as
+/// text instead of a `value` attribute.
+fn read_properties(element: &Element) -> Res<Properties> {
+    let mut rv = Properties::new();
+    let props = unwrap_or!(element.find_child("properties"), return Ok(rv));
+    for prop in props.children_named("property").iter() {
+        let name = unwrap_or!(prop.get_attr("name"),
+            return Err(GameError::InvalidMap("Property without name", None)));
+        let value = prop.get_attr("value").unwrap_or(prop.text.as_slice());
+        let type_name = prop.get_attr("type").unwrap_or("string");
+        rv.insert(name.to_string(), unwrap_or!(PropertyValue::parse(type_name, value),
+            return Err(GameError::InvalidMap("Malformed property value", None))));
+    }
+    Ok(rv)
+}
+
+/// Reads a `<tileset>`.  External tilesets are only known by their
+/// first gid and file name.
+fn read_tileset(tileset: &Element) -> Res<Tileset> {
+    let source = tileset.get_attr("source");
+    let name = tileset.get_attr("name").or(source).unwrap_or("");
+    let image = tileset.find_child("image");
+    Ok(Tileset {
+        name: name.to_string(),
+        first_gid: try!(tileset.parse_attr("firstgid")),
+        source: source.map(|x| x.to_string()),
+        tile_count: match tileset.get_attr("tilecount") {
+            Some(_) => Some(try!(tileset.parse_attr("tilecount"))),
+            None => None,
+        },
+        tile_width: try!(tileset.parse_attr_or("tilewidth", DEFAULT_TILE_PIXELS)),
+        tile_height: try!(tileset.parse_attr_or("tileheight", DEFAULT_TILE_PIXELS)),
+        image: image.and_then(|x| x.get_attr("source")).map(|x| x.to_string()),
+        image_width: match image {
+            Some(image) => try!(image.parse_attr_or("width", 0)),
+            None => 0,
+        },
+        image_height: match image {
+            Some(image) => try!(image.parse_attr_or("height", 0)),
+            None => 0,
+        },
+        margin: try!(tileset.parse_attr_or("margin", 0)),
+        spacing: try!(tileset.parse_attr_or("spacing", 0)),
+        properties: try!(read_properties(tileset)),
+    })
+}
+
+/// Reads an `<objectgroup>`.  Positions are converted from pixels to
+/// tiles.
+fn read_object_layer(group: &Element, tile_width: u32,
+                     tile_height: u32) -> Res<ObjectLayer> {
+    let (tw, th) = (tile_width as f32, tile_height as f32);
+    let mut objects = vec![];
+    for obj in group.children_named("object").iter() {
+        let name = obj.get_attr("name").unwrap_or("");
+        // newer versions of Tiled call the type a class
+        let type_name = obj.get_attr("type")
+            .or_else(|| obj.get_attr("class")).unwrap_or("");
+        objects.push(MapObject {
+            id: try!(obj.parse_attr_or("id", 0u32)),
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            kind: ObjectKind::from_object(type_name, name),
+            x: try!(obj.parse_attr_or("x", 0.0f32)) / tw,
+            y: try!(obj.parse_attr_or("y", 0.0f32)) / th,
+            width: try!(obj.parse_attr_or("width", 0.0f32)) / tw,
+            height: try!(obj.parse_attr_or("height", 0.0f32)) / th,
+            visible: try!(obj.is_visible()),
+        });
+    }
+    Ok(ObjectLayer {
+        name: group.get_attr("name").unwrap_or("").to_string(),
+        visible: try!(group.is_visible()),
+        objects: objects,
+    })
+}
+
+/// Converts a parsed TMX document into raw map data.  Tile and object
+/// layers are kept in document order, everything else but tilesets is
+/// skipped.
+pub fn read_map_data(root: &Element) -> Res<MapData> {
+    if root.name.as_slice() != "map" {
+        return Err(GameError::InvalidMap("Not a TMX map", None));
+    }
+    let tile_width = try!(root.parse_attr("tilewidth"));
+    let tile_height = try!(root.parse_attr("tileheight"));
+
+    let mut tilesets = vec![];
+    let mut layers = vec![];
+    for child in root.children.iter() {
+        match child.name.as_slice() {
+            "tileset" => {
+                tilesets.push(try!(read_tileset(child)));
+            }
+            "layer" => {
+                layers.push(Layer::Tiles(try!(read_tile_layer(child))));
+            }
+            "objectgroup" => {
+                layers.push(Layer::Objects(try!(read_object_layer(
+                    child, tile_width, tile_height))));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(MapData {
+        width: try!(root.parse_attr("width")),
+        height: try!(root.parse_attr("height")),
+        tile_width: tile_width,
+        tile_height: tile_height,
+        tilesets: tilesets,
+        layers: layers,
+        properties: try!(read_properties(root)),
+    })
+}
+
+/// Opens a Tiled TMX map.
+pub fn open(path: &Path) -> Res<Map> {
+    let file = try!(io::File::open(path));
+    let root = try!(parse_xml(io::BufferedReader::new(file)));
+    Map::from_map_data(try!(read_map_data(&root)))
+}
+
+/// Escapes text for use in an XML attribute.
+fn escape(text: &str) -> String {
+    let mut rv = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => rv.push_str("&amp;"),
+            '<' => rv.push_str("&lt;"),
+            '>' => rv.push_str("&gt;"),
+            '"' => rv.push_str("&quot;"),
+            '\n' => rv.push_str("&#10;"),
+            c => rv.push(c),
+        }
+    }
+    rv
+}
+
+fn visible_attr(visible: bool) -> &'static str {
+    if visible { "" } else { " visible=\"0\"" }
+}
+
+fn write_properties(out: &mut String, props: &Properties, indent: &str) {
+    if props.is_empty() {
+        return;
+    }
+    out.push_str(format!("{}<properties>\n", indent).as_slice());
+    for &(ref name, ref value) in props.iter() {
+        let type_attr = match *value {
+            PropertyValue::String(_) => String::new(),
+            _ => format!(" type=\"{}\"", value.type_name()),
+        };
+        out.push_str(format!("{} <property name=\"{}\"{} value=\"{}\"/>\n",
+            indent, escape(name.as_slice()), type_attr,
+            escape(value.to_value_string().as_slice())).as_slice());
+    }
+    out.push_str(format!("{}</properties>\n", indent).as_slice());
+}
+
+fn write_tileset(out: &mut String, tileset: &Tileset) {
+    if let Some(ref source) = tileset.source {
+        out.push_str(format!(" <tileset firstgid=\"{}\" source=\"{}\"/>\n",
+            tileset.first_gid, escape(source.as_slice())).as_slice());
+        return;
+    }
+    let tile_count = match tileset.tile_count {
+        Some(count) => format!(" tilecount=\"{}\"", count),
+        None => String::new(),
+    };
+    out.push_str(format!(
+        " <tileset firstgid=\"{}\" name=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" \
+         spacing=\"{}\" margin=\"{}\"{}>\n",
+        tileset.first_gid, escape(tileset.name.as_slice()), tileset.tile_width,
+        tileset.tile_height, tileset.spacing, tileset.margin, tile_count).as_slice());
+    write_properties(out, &tileset.properties, "  ");
+    if let Some(ref image) = tileset.image {
+        out.push_str(format!("  <image source=\"{}\" width=\"{}\" height=\"{}\"/>\n",
+            escape(image.as_slice()), tileset.image_width,
+            tileset.image_height).as_slice());
+    }
+    out.push_str(" </tileset>\n");
+}
+
+fn write_layer(out: &mut String, map: &Map, layer: &Layer) {
+    let (tile_width, tile_height) = map.tile_pixels();
+    let (tw, th) = (tile_width as f32, tile_height as f32);
+    match *layer {
+        Layer::Tiles(ref layer) => {
+            out.push_str(format!(" <layer name=\"{}\" width=\"{}\" height=\"{}\"{}>\n",
+                escape(layer.name.as_slice()), map.width(), map.height(),
+                visible_attr(layer.visible)).as_slice());
+            out.push_str("  <data encoding=\"csv\">\n");
+            for (idx, row) in layer.data.as_slice().chunks(map.width() as uint).enumerate() {
+                let row : Vec<String> = row.iter().map(|gid| gid.to_string()).collect();
+                out.push_str(row.connect(",").as_slice());
+                if idx + 1 < map.height() as uint {
+                    out.push_str(",");
+                }
+                out.push_str("\n");
+            }
+            out.push_str("  </data>\n </layer>\n");
+        }
+        Layer::Objects(ref layer) => {
+            out.push_str(format!(" <objectgroup name=\"{}\"{}>\n",
+                escape(layer.name.as_slice()), visible_attr(layer.visible)).as_slice());
+            for obj in layer.objects.iter() {
+                out.push_str(format!(
+                    "  <object id=\"{}\" name=\"{}\" type=\"{}\" x=\"{}\" y=\"{}\" \
+                     width=\"{}\" height=\"{}\"{}/>\n",
+                    obj.id, escape(obj.name.as_slice()), escape(obj.type_name.as_slice()),
+                    obj.x * tw, obj.y * th, obj.width * tw, obj.height * th,
+                    visible_attr(obj.visible)).as_slice());
+            }
+            out.push_str(" </objectgroup>\n");
+        }
+    }
+}
+
+/// Converts a map into a TMX document.  Layer data is stored as CSV.
+pub fn write_map(map: &Map) -> String {
+    let (tile_width, tile_height) = map.tile_pixels();
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(format!(
+        "<map version=\"1.0\" orientation=\"orthogonal\" renderorder=\"right-down\" \
+         width=\"{}\" height=\"{}\" tilewidth=\"{}\" tileheight=\"{}\">\n",
+        map.width(), map.height(), tile_width, tile_height).as_slice());
+    write_properties(&mut out, &map.properties().all, " ");
+    for tileset in map.tilesets().iter() {
+        write_tileset(&mut out, tileset);
+    }
+    for layer in map.layers().iter() {
+        write_layer(&mut out, map, layer);
+    }
+    out.push_str("</map>\n");
+    out
+}
+
+/// Saves a map as Tiled TMX.
+pub fn save(map: &Map, path: &Path) -> Res<()> {
+    let mut file = try!(io::File::create(path));
+    try!(file.write_str(write_map(map).as_slice()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use errors::GameError;
+    use map::Map;
+    use super::{Element, parse_xml, decode_layer_data, read_map_data};
+
+    /// the gids every encoded sample below holds, the last one is flipped
+    static GIDS : [u32, ..4] = [1, 2, 3, 0x80000004];
+
+    fn parse(xml: &str) -> Element {
+        parse_xml(io::BufReader::new(xml.as_bytes())).unwrap()
+    }
+
+    fn decode(encoding: &str, compression: &str, text: &str) -> Vec<u32> {
+        decode_layer_data(&parse(format!(
+            "<data encoding=\"{}\"{}>{}</data>", encoding, compression,
+            text).as_slice())).unwrap()
+    }
+
+    fn decode_err(compression: &str, text: &str) -> &'static str {
+        let data = parse(format!("<data encoding=\"base64\"{}>{}</data>",
+                                 compression, text).as_slice());
+        match decode_layer_data(&data).err().unwrap() {
+            GameError::InvalidMap(msg, _) => msg,
+            _ => "",
+        }
+    }
+
+    #[test]
+    fn decode_xml_and_csv() {
+        let data = parse("<data><tile gid=\"1\"/><tile/><tile gid=\"3\"/></data>");
+        assert_eq!(decode_layer_data(&data).unwrap(), vec![1, 0, 3]);
+        assert_eq!(decode("csv", "", "\n1,2,\n3,2147483652\n"), GIDS.to_vec());
+        let data = parse("<data encoding=\"csv\">1,x</data>");
+        assert!(match decode_layer_data(&data).err().unwrap() {
+            GameError::InvalidMap(msg, _) => msg == "Invalid CSV data in map",
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn decode_base64_data() {
+        assert_eq!(decode("base64", "", "\n   AQAAAAIAAAADAAAABAAAgA==\n  "),
+                   GIDS.to_vec());
+        assert_eq!(decode("base64", " compression=\"zlib\"",
+                          "eNpjZGBgYAJiZiBmYWBoAAAA4ACL"), GIDS.to_vec());
+        assert_eq!(decode("base64", " compression=\"gzip\"",
+                          "H4sIAAAAAAACA2NkYGBgAmJmIGZhYGgAAM9XvUIQAAAA"),
+                   GIDS.to_vec());
+    }
+
+    #[test]
+    fn decode_gzip_with_optional_header_fields() {
+        for text in [
+            // FEXTRA
+            "H4sIBAAAAAACAwQAeHkAemNkYGBgAmJmIGZhYGgAAM9XvUIQAAAA",
+            // FNAME
+            "H4sICAAAAAACA21hcC50bXgAY2RgYGACYmYgZmFgaAAAz1e9QhAAAAA=",
+            // FCOMMENT
+            "H4sIEAAAAAACA3RpbGVzAGNkYGBgAmJmIGZhYGgAAM9XvUIQAAAA",
+            // FHCRC
+            "H4sIAgAAAAACAyUVY2RgYGACYmYgZmFgaAAAz1e9QhAAAAA=",
+            // all of them at once
+            "H4sIHgAAAAACAwQAeHkAem1hcC50bXgAdGlsZXMAYvhjZGBgYAJiZiBmYWBoAADPV71CEAAAAA==",
+        ].iter() {
+            assert_eq!(decode("base64", " compression=\"gzip\"", *text), GIDS.to_vec());
+        }
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode_err(" compression=\"gzip\"",
+                              "HosIAAAAAAACA2NkYGBgAmJmIGZhYGgAAM9XvUIQAAAA"),
+                   "Invalid gzip data in map");
+        assert_eq!(decode_err(" compression=\"gzip\"", "H4sIAAAAAAACA2NkYGBgAmJmIGY="),
+                   "Invalid gzip data in map");
+        assert_eq!(decode_err(" compression=\"zlib\"", "eNpjZGBgYAJi"),
+                   "Invalid zlib data in map");
+        assert_eq!(decode_err("", "AQAAAAIA"), "Truncated layer data in map");
+        assert_eq!(decode_err("", "!!!"), "Invalid base64 data in map");
+        assert_eq!(decode_err(" compression=\"lzma\"", "AQAAAAIA"),
+                   "Unsupported compression in map");
+    }
+
+    #[test]
+    fn wrong_tile_count() {
+        let root = parse("<map width=\"2\" height=\"2\" tilewidth=\"32\" tileheight=\"32\">\
+                          <layer name=\"terrain\"><data encoding=\"csv\">1,1,1</data>\
+                          </layer></map>");
+        let data = read_map_data(&root).unwrap();
+        assert!(match Map::from_map_data(data).err().unwrap() {
+            GameError::InvalidMap(msg, _) => msg == "Invalid dimensions",
+            _ => false,
+        });
+    }
+}
@@ -0,0 +1,926 @@
+use std::default::Default;
+use std::num::Float;
+
+use ai;
+use ai::{AiConfig, Brain, Command};
+use bullet::{Bullet, BulletKind};
+use campaign::Outcome;
+use geom::{Vec2, normalize_angle};
+use map::{Map, Tile, Spawn, ObjectType};
+use mine::{Mine, Explosion};
+use pathfinding::{Pathfinder, tile_of};
+use tank::{Tank, TankKind};
+
+/// the maximum number of local players
+pub const MAX_PLAYERS : uint = 4;
+
+/// seconds between two shots of the same tank
+static FIRE_COOLDOWN : f32 = 0.5;
+
+
+/// A destructible crate.  While it is alive its tile on the world's map
+/// is a `Tile::Crate` which blocks tanks and bullets.
+#[deriving(Clone, Show)]
+pub struct Crate {
+    pub id: uint,
+    pub x: u16,
+    pub y: u16,
+    pub alive: bool,
+}
+
+/// The input of a single player for one simulation step.
+#[deriving(Copy, Clone, Default, Show)]
+pub struct PlayerInput {
+    /// -1.0 to drive backwards, 1.0 to drive forwards
+    pub forward: f32,
+    /// -1.0 to turn left, 1.0 to turn right
+    pub turn: f32,
+    /// -1.0 to turn the turret left, 1.0 to turn it right
+    pub turret: f32,
+    pub fire: bool,
+    pub lay_mine: bool,
+}
+
+/// The inputs of all players for one simulation step.
+#[deriving(Copy, Clone)]
+pub struct Inputs {
+    pub players: [PlayerInput, ..MAX_PLAYERS],
+}
+
+impl Inputs {
+
+    pub fn new() -> Inputs {
+        Inputs {
+            players: [PlayerInput::default(), ..MAX_PLAYERS],
+        }
+    }
+
+    pub fn get_player(&self, idx: u8) -> &PlayerInput {
+        &self.players[idx as uint]
+    }
+}
+
+/// A box standing on the ground that stands in for an entity until
+/// there are models for them.
+#[deriving(Copy, Clone, Show)]
+pub struct EntityBox {
+    /// the center of the box on the ground
+    pub pos: Vec2,
+    /// the direction the box faces in radians
+    pub heading: f32,
+    /// the size of the box along its heading
+    pub length: f32,
+    /// the size of the box across its heading
+    pub width: f32,
+    pub height: f32,
+    /// how far above the ground the bottom of the box is
+    pub lift: f32,
+}
+
+/// An immutable copy of the world state that renderers consume.
+#[deriving(Clone)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub time: f64,
+    pub tanks: Vec<Tank>,
+    pub bullets: Vec<Bullet>,
+    pub mines: Vec<Mine>,
+    pub crates: Vec<Crate>,
+    pub explosions: Vec<Explosion>,
+}
+
+impl Snapshot {
+
+    /// Blends this snapshot with the following one.  Entities that only
+    /// exist in one of the two snapshots are taken from `next` as is.
+    pub fn interpolate(&self, next: &Snapshot, alpha: f32) -> Snapshot {
+        let mut rv = next.clone();
+        rv.time = self.time + (next.time - self.time) * alpha as f64;
+        for tank in rv.tanks.iter_mut() {
+            if let Some(old) = self.tanks.iter().find(|t| t.id == tank.id) {
+                tank.pos = old.pos.lerp(tank.pos, alpha);
+                tank.heading = lerp_angle(old.heading, tank.heading, alpha);
+                tank.turret_heading = lerp_angle(
+                    old.turret_heading, tank.turret_heading, alpha);
+            }
+        }
+        for bullet in rv.bullets.iter_mut() {
+            if let Some(old) = self.bullets.iter().find(|b| b.id == bullet.id) {
+                bullet.pos = old.pos.lerp(bullet.pos, alpha);
+            }
+        }
+        rv
+    }
+
+    /// The boxes everything that moves is drawn as: a hull and a turret
+    /// for every living tank, and one box for every bullet and mine.
+    /// Crates are part of the map and not included.
+    pub fn boxes(&self) -> Vec<EntityBox> {
+        let mut rv = vec![];
+        for tank in self.tanks.iter().filter(|t| t.alive) {
+            rv.push(EntityBox {
+                pos: tank.pos,
+                heading: tank.heading,
+                length: tank.radius * 2.0,
+                width: tank.radius * 1.6,
+                height: 0.3,
+                lift: 0.0,
+            });
+            rv.push(EntityBox {
+                pos: tank.pos,
+                heading: tank.turret_heading,
+                length: tank.radius * 1.4,
+                width: tank.radius * 0.5,
+                height: 0.15,
+                lift: 0.3,
+            });
+        }
+        for bullet in self.bullets.iter().filter(|b| b.alive) {
+            rv.push(EntityBox {
+                pos: bullet.pos,
+                heading: bullet.vel.angle(),
+                length: bullet.radius * 3.0,
+                width: bullet.radius * 2.0,
+                height: bullet.radius * 2.0,
+                lift: 0.2,
+            });
+        }
+        for mine in self.mines.iter().filter(|m| m.alive) {
+            rv.push(EntityBox {
+                pos: mine.pos,
+                heading: 0.0,
+                length: 0.3,
+                width: 0.3,
+                height: 0.05,
+                lift: 0.0,
+            });
+        }
+        rv
+    }
+}
+
+fn lerp_angle(a: f32, b: f32, alpha: f32) -> f32 {
+    a + normalize_angle(b - a) * alpha
+}
+
+/// The headless game simulation.  A world is built from a map and then
+/// advanced with `step`.  It never talks to the engine, so it can be
+/// driven from tests and tools just as well as from the game loop.
+pub struct World {
+    map: Map,
+    tick: u64,
+    time: f64,
+    next_id: uint,
+    tanks: Vec<Tank>,
+    bullets: Vec<Bullet>,
+    mines: Vec<Mine>,
+    crates: Vec<Crate>,
+    explosions: Vec<Explosion>,
+    ai_config: AiConfig,
+    brains: Vec<Brain>,
+    pathfinder: Pathfinder,
+}
+
+/// returns the position of the center of a tile
+pub fn tile_center(x: u16, y: u16) -> Vec2 {
+    Vec2::new(x as f32 + 0.5, y as f32 + 0.5)
+}
+
+impl World {
+
+    /// Creates a world where enemies and bullets use the given tuning
+    /// values.
+    pub fn new(map: &Map, ai_config: AiConfig) -> World {
+        let mut world = World {
+            map: map.clone(),
+            tick: 0,
+            time: 0.0,
+            next_id: 0,
+            tanks: vec![],
+            bullets: vec![],
+            mines: vec![],
+            crates: vec![],
+            explosions: vec![],
+            ai_config: ai_config,
+            brains: vec![],
+            pathfinder: Pathfinder::new(map),
+        };
+
+        for point in map.spawn_points().iter() {
+            world.spawn(point.spawn, Vec2::new(point.x, point.y));
+        }
+
+        world
+    }
+
+    fn alloc_id(&mut self) -> uint {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn spawn(&mut self, spawn: Spawn, pos: Vec2) {
+        let id = self.alloc_id();
+        match spawn {
+            Spawn::Player(idx) => {
+                self.tanks.push(Tank::new(id, TankKind::Player(idx), pos,
+                                          &self.ai_config.tanks));
+            }
+            Spawn::Enemy(ty) => {
+                let mut tank = Tank::new(id, TankKind::Enemy(ty), pos,
+                                         &self.ai_config.tanks);
+                ai::apply_params(&mut tank, self.ai_config.get(ty));
+                self.tanks.push(tank);
+                self.brains.push(Brain::new(id, ty));
+            }
+            Spawn::Object(ObjectType::Mine) => {
+                self.mines.push(Mine::new(id, None, pos, &self.ai_config.mines));
+            }
+            Spawn::Object(ObjectType::Crate) => {
+                // crates always fill a whole tile, wherever they were placed
+                let (x, y) = tile_of(pos);
+                if x >= self.map.width() || y >= self.map.height() {
+                    return;
+                }
+                self.map.set_tile(x, y, Tile::Crate);
+                self.crates.push(Crate { id: id, x: x, y: y, alive: true });
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    #[inline(always)]
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    #[inline(always)]
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn tanks(&self) -> &[Tank] {
+        self.tanks.as_slice()
+    }
+
+    pub fn bullets(&self) -> &[Bullet] {
+        self.bullets.as_slice()
+    }
+
+    pub fn mines(&self) -> &[Mine] {
+        self.mines.as_slice()
+    }
+
+    pub fn crates(&self) -> &[Crate] {
+        self.crates.as_slice()
+    }
+
+    /// the explosions that happened during the last step
+    pub fn explosions(&self) -> &[Explosion] {
+        self.explosions.as_slice()
+    }
+
+    /// Decides if the level is over.  It is won once every tank that
+    /// spawned as an enemy is destroyed and lost once every player is.
+    pub fn outcome(&self) -> Outcome {
+        let players_alive = self.tanks.iter().any(|t| t.alive && t.is_player());
+        let enemies_alive = self.tanks.iter().any(|t| t.alive && !t.is_player());
+        if !players_alive {
+            Outcome::Lost
+        } else if !enemies_alive {
+            Outcome::Won
+        } else {
+            Outcome::Playing
+        }
+    }
+
+    /// finds the tank of the given player
+    pub fn get_player_tank(&self, idx: u8) -> Option<&Tank> {
+        self.tanks.iter().find(|t| t.kind == TankKind::Player(idx))
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tick: self.tick,
+            time: self.time,
+            tanks: self.tanks.clone(),
+            bullets: self.bullets.clone(),
+            mines: self.mines.clone(),
+            crates: self.crates.clone(),
+            explosions: self.explosions.clone(),
+        }
+    }
+
+    /// Advances the simulation by `dt` seconds.
+    pub fn step(&mut self, dt: f32, inputs: &Inputs) {
+        self.explosions.clear();
+        self.update_navigation();
+        self.update_tanks(dt, inputs);
+        self.update_bullets(dt);
+        self.update_mines(dt);
+        self.remove_dead();
+        self.tick += 1;
+        self.time += dt as f64;
+    }
+
+    /// the navigation data for tanks on this world's map
+    pub fn pathfinder(&self) -> &Pathfinder {
+        &self.pathfinder
+    }
+
+    fn update_navigation(&mut self) {
+        self.pathfinder.update(&self.map);
+        for tank in self.tanks.iter() {
+            if let TankKind::Player(idx) = tank.kind {
+                if tank.alive {
+                    self.pathfinder.track_player(idx, tile_of(tank.pos));
+                } else {
+                    self.pathfinder.forget_player(idx);
+                }
+            }
+        }
+    }
+
+    fn update_tanks(&mut self, dt: f32, inputs: &Inputs) {
+        let mut commands = vec![];
+        for brain in self.brains.iter_mut() {
+            let params = self.ai_config.get(brain.enemy_type);
+            let in_air = self.bullets.iter()
+                .filter(|b| b.owner == brain.tank_id).count();
+            commands.push((brain.tank_id, brain.think(
+                &self.map, &self.pathfinder, self.tanks.as_slice(), params,
+                in_air, dt)));
+        }
+
+        let mut shots = vec![];
+        let mut new_mines = vec![];
+
+        for tank in self.tanks.iter_mut() {
+            if !tank.alive {
+                continue;
+            }
+            tank.fire_cooldown = (tank.fire_cooldown - dt).max(0.0);
+
+            let (cmd, cooldown, bullet) = match tank.kind {
+                TankKind::Player(idx) => {
+                    let input = inputs.get_player(idx);
+                    if input.lay_mine {
+                        new_mines.push((tank.id, tank.pos));
+                    }
+                    (Command {
+                        forward: input.forward,
+                        turn: input.turn,
+                        turret: input.turret,
+                        fire: input.fire,
+                    }, FIRE_COOLDOWN, BulletKind::Standard)
+                }
+                TankKind::Enemy(ty) => {
+                    let params = self.ai_config.get(ty);
+                    let cmd = unwrap_or!(commands.iter()
+                        .find(|&&(id, _)| id == tank.id)
+                        .map(|&(_, cmd)| cmd), continue);
+                    let bullet = params.get_bullet_kind()
+                        .unwrap_or(BulletKind::Standard);
+                    (cmd, params.fire_cooldown, bullet)
+                }
+            };
+
+            tank.drive(&self.map, cmd.forward, cmd.turn, dt);
+            tank.turn_turret(cmd.turret, dt);
+            if cmd.fire && tank.fire_cooldown <= 0.0 {
+                tank.fire_cooldown = cooldown;
+                shots.push((tank.id, bullet, tank.pos, tank.turret_heading));
+            }
+        }
+
+        for &(owner, bullet, pos, angle) in shots.iter() {
+            self.fire(owner, bullet, pos, angle);
+        }
+        for &(owner, pos) in new_mines.iter() {
+            self.lay_mine(owner, pos);
+        }
+    }
+
+    /// Lays a mine under a tank unless the tank has too many mines out
+    /// already or there is one right here.
+    fn lay_mine(&mut self, owner: uint, pos: Vec2) {
+        let count = self.mines.iter()
+            .filter(|m| m.alive && m.owner == Some(owner)).count();
+        let config = self.ai_config.mines;
+        let crowded = self.mines.iter()
+            .any(|m| m.alive && m.pos.distance(pos) < config.trigger_radius * 2.0);
+        if count >= config.max_per_player || crowded {
+            return;
+        }
+        let id = self.alloc_id();
+        self.mines.push(Mine::new(id, Some(owner), pos, &config));
+    }
+
+    fn update_mines(&mut self, dt: f32) {
+        for mine in self.mines.iter_mut() {
+            if !mine.alive {
+                continue;
+            }
+            mine.arm_timer = (mine.arm_timer - dt).max(0.0);
+            if self.tanks.iter().any(|t| t.alive && mine.is_triggered_by(t.pos, t.radius)) {
+                mine.triggered = true;
+            }
+        }
+
+        // mines that get shot go off no matter if they are armed
+        for bullet in self.bullets.iter_mut() {
+            if !bullet.alive {
+                continue;
+            }
+            for mine in self.mines.iter_mut() {
+                if !mine.alive {
+                    continue;
+                }
+                if bullet.distance_to(mine.pos) < mine.trigger_radius * 0.5 + bullet.radius {
+                    mine.triggered = true;
+                    bullet.alive = false;
+                    break;
+                }
+            }
+        }
+
+        // explosions can set off further mines, keep going until the
+        // chain reaction is over.
+        let radius = self.ai_config.mines.blast_radius;
+        loop {
+            let pos = unwrap_or!(self.mines.iter()
+                .find(|m| m.alive && m.triggered)
+                .map(|m| m.pos), break);
+            for mine in self.mines.iter_mut() {
+                if mine.alive && mine.pos == pos {
+                    mine.alive = false;
+                }
+            }
+            self.explode(pos, radius);
+        }
+    }
+
+    /// Destroys everything within `radius` of `pos`: tanks, bullets and
+    /// crates die, other mines are triggered.
+    fn explode(&mut self, pos: Vec2, radius: f32) {
+        self.explosions.push(Explosion { pos: pos, radius: radius });
+
+        for tank in self.tanks.iter_mut() {
+            if tank.alive && tank.pos.distance(pos) < radius + tank.radius {
+                tank.alive = false;
+            }
+        }
+        for bullet in self.bullets.iter_mut() {
+            if bullet.alive && bullet.pos.distance(pos) < radius + bullet.radius {
+                bullet.alive = false;
+            }
+        }
+        for mine in self.mines.iter_mut() {
+            if mine.alive && mine.pos.distance(pos) < radius {
+                mine.triggered = true;
+            }
+        }
+        for krate in self.crates.iter_mut() {
+            if krate.alive && tile_center(krate.x, krate.y).distance(pos) < radius {
+                krate.alive = false;
+                // the map copy of the world is what collisions and the
+                // pathfinder look at, so the crate has to go from there.
+                self.map.set_tile(krate.x, krate.y, Tile::Ground);
+            }
+        }
+    }
+
+    fn fire(&mut self, owner: uint, kind: BulletKind, pos: Vec2, angle: f32) {
+        let id = self.alloc_id();
+        let bullet = Bullet::new(id, kind, self.ai_config.bullets.get(kind),
+                                 owner, pos, angle);
+        self.bullets.push(bullet);
+    }
+
+    /// the closest living tank on the other side of `owner`
+    fn find_enemy_of(&self, owner: uint, pos: Vec2) -> Option<Vec2> {
+        let owner_is_player = self.tanks.iter().find(|t| t.id == owner)
+            .map_or(false, |t| t.is_player());
+        let mut best : Option<Vec2> = None;
+        for tank in self.tanks.iter() {
+            if !tank.alive || tank.is_player() == owner_is_player {
+                continue;
+            }
+            match best {
+                Some(other) if pos.distance(other) <= pos.distance(tank.pos) => {}
+                _ => { best = Some(tank.pos); }
+            }
+        }
+        best
+    }
+
+    fn update_bullets(&mut self, dt: f32) {
+        let targets : Vec<Option<Vec2>> = self.bullets.iter().map(|b| {
+            if b.homing > 0.0 { self.find_enemy_of(b.owner, b.pos) } else { None }
+        }).collect();
+        for (bullet, target) in self.bullets.iter_mut().zip(targets.iter()) {
+            if let Some(target) = *target {
+                bullet.steer_towards(target, dt);
+            }
+            bullet.advance(&self.map, dt);
+        }
+        self.collide_bullets();
+        self.collide_bullets_with_tanks();
+    }
+
+    /// Bullets that touch each other destroy each other.  Their paths of
+    /// the last step are compared, so fast bullets cannot pass through
+    /// each other between two steps.
+    fn collide_bullets(&mut self) {
+        let count = self.bullets.len();
+        for i in range(0, count) {
+            for j in range(i + 1, count) {
+                let hit = {
+                    let (a, b) = (&self.bullets[i], &self.bullets[j]);
+                    a.alive && b.alive &&
+                        a.distance_to_bullet(b) < a.radius + b.radius
+                };
+                if hit {
+                    self.bullets[i].alive = false;
+                    self.bullets[j].alive = false;
+                }
+            }
+        }
+    }
+
+    /// bullets kill the first tank their path of the last step touches,
+    /// ricochets included
+    fn collide_bullets_with_tanks(&mut self) {
+        for bullet in self.bullets.iter_mut() {
+            if !bullet.alive {
+                continue;
+            }
+            for tank in self.tanks.iter_mut() {
+                if !tank.alive {
+                    continue;
+                }
+                if tank.id == bullet.owner && !bullet.armed {
+                    if bullet.pos.distance(tank.pos) > tank.radius + bullet.radius {
+                        bullet.armed = true;
+                    }
+                    continue;
+                }
+                if bullet.distance_to(tank.pos) < tank.radius + bullet.radius {
+                    tank.alive = false;
+                    bullet.alive = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn remove_dead(&mut self) {
+        self.bullets.retain(|b| b.alive);
+        self.mines.retain(|m| m.alive);
+        let tanks = &self.tanks;
+        self.brains.retain(|brain| {
+            tanks.iter().any(|t| t.id == brain.tank_id && t.alive)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use ai::AiConfig;
+    use map::{Map, Tile};
+    use super::{World, Inputs};
+
+    static DT : f32 = 1.0 / 60.0;
+
+    fn config() -> AiConfig {
+        AiConfig::open(&Path::new("resources/config/enemies.json")).unwrap()
+    }
+
+    fn corridor(length: uint) -> Map {
+        let width = length + 2;
+        let mut tiles = Vec::from_elem(width * 3, Tile::Wall1);
+        for x in range(1, width - 1) {
+            tiles[width + x] = Tile::Ground;
+        }
+        tiles[width + 1] = Tile::Player1Spawn;
+        Map::new(width as u16, 3, tiles).unwrap()
+    }
+
+    fn drive(world: &mut World, forward: f32, turn: f32, ticks: uint) {
+        let mut inputs = Inputs::new();
+        inputs.players[0].forward = forward;
+        inputs.players[0].turn = turn;
+        for _ in range(0, ticks) {
+            world.step(DT, &inputs);
+        }
+    }
+
+    #[test]
+    fn tank_drives_along_its_heading() {
+        let mut world = World::new(&corridor(6), config());
+        let start = world.get_player_tank(0).unwrap().pos;
+        drive(&mut world, 1.0, 0.0, 30);
+        let tank = world.get_player_tank(0).unwrap();
+        // two tiles per second for half a second
+        assert!((tank.pos.x - start.x - 1.0).abs() < 0.01);
+        assert!((tank.pos.y - start.y).abs() < 0.0001);
+        assert_eq!(world.tick(), 30);
+    }
+
+    #[test]
+    fn tank_turns_and_reverses() {
+        let mut world = World::new(&corridor(6), config());
+        drive(&mut world, 0.0, 1.0, 12);
+        let tank = world.get_player_tank(0).unwrap().clone();
+        assert!((tank.heading - tank.turn_rate * DT * 12.0).abs() < 0.0001);
+
+        let mut world = World::new(&corridor(6), config());
+        drive(&mut world, 1.0, 0.0, 60);
+        let forward = world.get_player_tank(0).unwrap().pos;
+        drive(&mut world, -1.0, 0.0, 30);
+        let back = world.get_player_tank(0).unwrap().pos;
+        assert!((forward.x - back.x - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn swamps_slow_tanks_down() {
+        let map = Map::from_glyphs(&[
+            "111111111",
+            "1P......1",
+            "1Q\"\"\"\"\"\"1",
+            "111111111",
+        ]).unwrap();
+        let config = config();
+        let mut world = World::new(&map, config.clone());
+        let mut inputs = Inputs::new();
+        inputs.players[0].forward = 1.0;
+        inputs.players[1].forward = 1.0;
+        // the spawn tile is not a swamp, get both tanks off them first
+        for _ in range(0, 30u) {
+            world.step(DT, &inputs);
+        }
+        let ground = world.get_player_tank(0).unwrap().pos;
+        let swamp = world.get_player_tank(1).unwrap().pos;
+        for _ in range(0, 30u) {
+            world.step(DT, &inputs);
+        }
+        let on_ground = world.get_player_tank(0).unwrap().pos.distance(ground);
+        let in_swamp = world.get_player_tank(1).unwrap().pos.distance(swamp);
+        assert!((on_ground - 1.0).abs() < 0.01);
+        assert!((in_swamp - on_ground * config.tanks.swamp_factor).abs() < 0.01);
+    }
+
+    #[test]
+    fn tank_stops_at_wall() {
+        let mut world = World::new(&corridor(6), config());
+        drive(&mut world, 1.0, 0.0, 300);
+        let tank = world.get_player_tank(0).unwrap();
+        assert!((tank.pos.x - (7.0 - tank.radius)).abs() < 0.01);
+        assert!((tank.pos.y - 1.5).abs() < 0.0001);
+    }
+
+    #[test]
+    fn bullet_expires_after_its_lifetime() {
+        let mut world = World::new(&corridor(62), config());
+        let mut inputs = Inputs::new();
+        inputs.players[0].fire = true;
+        world.step(0.1, &inputs);
+        assert_eq!(world.bullets().len(), 1);
+        let lifetime = world.bullets()[0].lifetime;
+
+        inputs.players[0].fire = false;
+        let mut time = 0.1;
+        while time + 0.1 < lifetime {
+            world.step(0.1, &inputs);
+            time += 0.1;
+            assert_eq!(world.bullets().len(), 1);
+        }
+        world.step(0.1, &inputs);
+        world.step(0.1, &inputs);
+        assert!(world.bullets().is_empty());
+        assert!(world.get_player_tank(0).unwrap().alive);
+    }
+
+    #[test]
+    fn snapshots_are_drawn_as_boxes() {
+        let mut world = World::new(&corridor(6), config());
+        let mut inputs = Inputs::new();
+        inputs.players[0].forward = 1.0;
+        inputs.players[0].fire = true;
+        world.step(DT, &inputs);
+        let previous = world.snapshot();
+        world.step(DT, &inputs);
+        let current = world.snapshot();
+
+        // a hull and a turret for the tank and one box for the bullet
+        let boxes = previous.interpolate(&current, 0.5).boxes();
+        assert_eq!(boxes.len(), 3);
+        let (old, new) = (previous.tanks[0].pos, current.tanks[0].pos);
+        assert!(boxes[0].pos.distance(old.lerp(new, 0.5)) < 0.0001);
+        assert_eq!(boxes[1].pos, boxes[0].pos);
+        assert!(boxes[1].lift >= boxes[0].height);
+        assert!(boxes[2].pos.distance(previous.bullets[0].pos.lerp(
+            current.bullets[0].pos, 0.5)) < 0.0001);
+    }
+
+    #[test]
+    fn bullet_ricochets_once_and_hits_its_shooter() {
+        let mut world = World::new(&corridor(6), config());
+        let mut inputs = Inputs::new();
+        inputs.players[0].fire = true;
+        world.step(DT, &inputs);
+        inputs.players[0].fire = false;
+        for _ in range(0, 120) {
+            world.step(DT, &inputs);
+        }
+        assert!(world.bullets().is_empty());
+        assert!(!world.get_player_tank(0).unwrap().alive);
+    }
+
+    #[test]
+    fn navigation_follows_living_players() {
+        let mut world = World::new(&corridor(6), config());
+        drive(&mut world, 0.0, 0.0, 1);
+        assert_eq!(world.pathfinder().get_player_field(0).unwrap().goal(), (1, 1));
+        // two tiles further, the field moves along on the next step
+        drive(&mut world, 1.0, 0.0, 60);
+        drive(&mut world, 0.0, 0.0, 1);
+        assert_eq!(world.pathfinder().get_player_field(0).unwrap().goal(), (3, 1));
+        assert!(world.pathfinder().get_player_field(1).is_none());
+
+        // shot by its own ricochet
+        let mut inputs = Inputs::new();
+        inputs.players[0].fire = true;
+        world.step(DT, &inputs);
+        drive(&mut world, 0.0, 0.0, 120);
+        assert!(!world.get_player_tank(0).unwrap().alive);
+        assert!(world.pathfinder().get_player_field(0).is_none());
+    }
+
+    fn fire_and_wait_for_explosion(world: &mut World) {
+        let mut inputs = Inputs::new();
+        inputs.players[0].fire = true;
+        world.step(DT, &inputs);
+        inputs.players[0].fire = false;
+        for _ in range(0, 120u) {
+            world.step(DT, &inputs);
+            if world.explosions().len() > 0 {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn mines_arm_after_a_delay() {
+        let config = config();
+        let mut world = World::new(&corridor(6), config.clone());
+        let mut inputs = Inputs::new();
+        inputs.players[0].lay_mine = true;
+        world.step(DT, &inputs);
+        assert_eq!(world.mines().len(), 1);
+        assert_eq!(world.mines()[0].owner, Some(world.get_player_tank(0).unwrap().id));
+
+        // the tank sits right on top of its mine
+        let ticks = (config.mines.arm_delay / DT) as uint;
+        drive(&mut world, 0.0, 0.0, ticks - 5);
+        assert_eq!(world.mines().len(), 1);
+        assert!(world.get_player_tank(0).unwrap().alive);
+        drive(&mut world, 0.0, 0.0, 10);
+        assert!(world.mines().is_empty());
+        assert!(!world.get_player_tank(0).unwrap().alive);
+    }
+
+    #[test]
+    fn players_have_a_limited_number_of_mines() {
+        let config = config();
+        let mut world = World::new(&corridor(12), config.clone());
+        let mut inputs = Inputs::new();
+        inputs.players[0].forward = 1.0;
+        inputs.players[0].lay_mine = true;
+        // mines are not laid on top of each other
+        world.step(DT, &inputs);
+        world.step(DT, &inputs);
+        assert_eq!(world.mines().len(), 1);
+        for _ in range(0, 60u) {
+            world.step(DT, &inputs);
+        }
+        assert_eq!(world.mines().len(), config.mines.max_per_player);
+    }
+
+    #[test]
+    fn tanks_set_off_armed_mines() {
+        let map = Map::from_glyphs(&[
+            "111111111",
+            "1P...*..1",
+            "111111111",
+        ]).unwrap();
+        let config = config();
+        let mut world = World::new(&map, config.clone());
+        drive(&mut world, 0.0, 0.0, (config.mines.arm_delay / DT) as uint + 5);
+        assert_eq!(world.mines().len(), 1);
+
+        let mut inputs = Inputs::new();
+        inputs.players[0].forward = 1.0;
+        for _ in range(0, 120u) {
+            world.step(DT, &inputs);
+            if world.explosions().len() > 0 {
+                break;
+            }
+        }
+        assert_eq!(world.explosions().len(), 1);
+        assert_eq!(world.explosions()[0].pos.x, 5.5);
+        assert_eq!(world.explosions()[0].radius, config.mines.blast_radius);
+        let tank = world.get_player_tank(0).unwrap();
+        assert!(!tank.alive);
+        assert!(tank.pos.x < 5.5 - config.mines.trigger_radius);
+        assert!(world.mines().is_empty());
+    }
+
+    #[test]
+    fn shot_mines_blow_up_tanks_nearby() {
+        let map = Map::from_glyphs(&[
+            "11111111",
+            "1P...*T1",
+            "11111111",
+        ]).unwrap();
+        let mut world = World::new(&map, config());
+        // the mine is not armed yet, bullets set it off anyway
+        fire_and_wait_for_explosion(&mut world);
+        assert_eq!(world.explosions().len(), 1);
+        assert!(world.mines().is_empty());
+        assert!(world.bullets().is_empty());
+        assert!(world.get_player_tank(0).unwrap().alive);
+        assert!(world.tanks().iter().all(|t| t.is_player() || !t.alive));
+    }
+
+    #[test]
+    fn mines_set_off_each_other() {
+        let map = Map::from_glyphs(&[
+            "1111111111",
+            "1P..**..*1",
+            "1111111111",
+        ]).unwrap();
+        let mut world = World::new(&map, config());
+        fire_and_wait_for_explosion(&mut world);
+        // the two mines next to each other go off together, the one
+        // further down the corridor is out of reach
+        assert_eq!(world.explosions().len(), 2);
+        assert_eq!(world.mines().len(), 1);
+        assert_eq!(world.mines()[0].pos.x, 8.5);
+        assert!(world.get_player_tank(0).unwrap().alive);
+    }
+
+    #[test]
+    fn crates_block_tanks_and_bullets() {
+        let map = Map::from_glyphs(&[
+            "111111111",
+            "1P..=...1",
+            "111111111",
+        ]).unwrap();
+        let mut world = World::new(&map, config());
+        assert_eq!(world.crates().len(), 1);
+        assert_eq!(world.map().get_tile(4, 1), Tile::Crate);
+        drive(&mut world, 1.0, 0.0, 120);
+        let tank = world.get_player_tank(0).unwrap().clone();
+        assert!((tank.pos.x - (4.0 - tank.radius)).abs() < 0.01);
+
+        // back off a bit, the shell bounces off the crate and comes back
+        drive(&mut world, -1.0, 0.0, 30);
+        let mut inputs = Inputs::new();
+        inputs.players[0].fire = true;
+        world.step(DT, &inputs);
+        inputs.players[0].fire = false;
+        drive(&mut world, 0.0, 0.0, 15);
+        assert!(world.bullets()[0].pos.x < 4.0);
+        assert!(world.bullets()[0].vel.x < 0.0);
+        drive(&mut world, 0.0, 0.0, 45);
+        assert!(world.crates()[0].alive);
+        assert!(world.bullets().is_empty());
+        assert!(!world.get_player_tank(0).unwrap().alive);
+    }
+
+    #[test]
+    fn exploding_mines_destroy_crates() {
+        let map = Map::from_glyphs(&[
+            "1111111111",
+            "1P...*=..1",
+            "1111111111",
+        ]).unwrap();
+        let mut world = World::new(&map, config());
+        let revision = world.map().revision();
+        fire_and_wait_for_explosion(&mut world);
+        assert!(!world.crates()[0].alive);
+        assert_eq!(world.map().get_tile(6, 1), Tile::Ground);
+        assert!(world.map().revision() != revision);
+
+        // the way is free now
+        drive(&mut world, 1.0, 0.0, 180);
+        assert!(world.get_player_tank(0).unwrap().pos.x > 7.0);
+    }
+}
+